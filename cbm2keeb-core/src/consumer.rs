@@ -0,0 +1,134 @@
+//! Usages of the Consumer page, and the System Control usages of the Generic Desktop page. Media
+//! and system keys arrive as these, on their own interface or report ID.
+//!
+//! They are translated to keyboard usages with [`CONSUMER_KEYS`] and [`SYSTEM_KEYS`], so they
+//! take part in the key state like any other key. Usages that end up on a key in
+//! [`KEYMAP`](crate::keys::KEYMAP) (or one of its aliases) press that key on the CBM.
+
+use core::ops::RangeInclusive;
+
+use crate::{
+    hid::{PAGE_CONSUMER, PAGE_GENERIC_DESKTOP},
+    keys::*,
+};
+
+pub const CC_POWER: u16 = 0x0030; // Power
+pub const CC_SLEEP: u16 = 0x0032; // Sleep
+pub const CC_MENU: u16 = 0x0040; // Menu
+pub const CC_HELP: u16 = 0x0095; // Help
+pub const CC_PLAY: u16 = 0x00B0; // Play
+pub const CC_PAUSE: u16 = 0x00B1; // Pause
+pub const CC_RECORD: u16 = 0x00B2; // Record
+pub const CC_FAST_FORWARD: u16 = 0x00B3; // Fast Forward
+pub const CC_REWIND: u16 = 0x00B4; // Rewind
+pub const CC_SCAN_NEXT_TRACK: u16 = 0x00B5; // Scan Next Track
+pub const CC_SCAN_PREVIOUS_TRACK: u16 = 0x00B6; // Scan Previous Track
+pub const CC_STOP: u16 = 0x00B7; // Stop
+pub const CC_EJECT: u16 = 0x00B8; // Eject
+pub const CC_PLAY_PAUSE: u16 = 0x00CD; // Play/Pause
+pub const CC_MUTE: u16 = 0x00E2; // Mute
+pub const CC_VOLUME_UP: u16 = 0x00E9; // Volume Increment
+pub const CC_VOLUME_DOWN: u16 = 0x00EA; // Volume Decrement
+pub const CC_AL_CONSUMER_CONTROL_CONFIG: u16 = 0x0183; // AL Consumer Control Configuration
+pub const CC_AL_EMAIL_READER: u16 = 0x018A; // AL Email Reader
+pub const CC_AL_CALCULATOR: u16 = 0x0192; // AL Calculator
+pub const CC_AL_LOCAL_BROWSER: u16 = 0x0194; // AL Local Machine Browser
+pub const CC_AC_SEARCH: u16 = 0x0221; // AC Search
+pub const CC_AC_HOME: u16 = 0x0223; // AC Home
+pub const CC_AC_BACK: u16 = 0x0224; // AC Back
+pub const CC_AC_FORWARD: u16 = 0x0225; // AC Forward
+pub const CC_AC_STOP: u16 = 0x0226; // AC Stop
+pub const CC_AC_REFRESH: u16 = 0x0227; // AC Refresh
+pub const CC_AC_BOOKMARKS: u16 = 0x022A; // AC Bookmarks
+
+pub const SC_POWER_DOWN: u16 = 0x0081; // System Power Down
+pub const SC_SLEEP: u16 = 0x0082; // System Sleep
+pub const SC_WAKE_UP: u16 = 0x0083; // System Wake Up
+
+/// Consumer usages as keyboard usages, `(consumer usage, key)`
+pub static CONSUMER_KEYS: [(u16, u8); 8] = [
+    // STOP
+    (CC_STOP, KEY_STOP),
+    (CC_AC_STOP, KEY_STOP),
+    // CE
+    (CC_AL_CALCULATOR, KEY_KPCLEARENTRY),
+    (CC_MUTE, KEY_MUTE),
+    (CC_VOLUME_UP, KEY_VOLUMEUP),
+    (CC_VOLUME_DOWN, KEY_VOLUMEDOWN),
+    (CC_POWER, KEY_POWER),
+    (CC_HELP, KEY_HELP),
+];
+
+/// System Control usages as keyboard usages, `(system usage, key)`
+pub static SYSTEM_KEYS: [(u16, u8); 1] = [(SC_POWER_DOWN, KEY_POWER)];
+
+/// The keyboard usages for the usages on `page`.
+fn table(page: u16) -> &'static [(u16, u8)] {
+    match page {
+        PAGE_CONSUMER => &CONSUMER_KEYS,
+        PAGE_GENERIC_DESKTOP => &SYSTEM_KEYS,
+        _ => &[],
+    }
+}
+
+/// The keyboard usage for a usage on another page, if it has one.
+pub fn key_for(page: u16, usage: u16) -> Option<u8> {
+    table(page)
+        .iter()
+        .find(|&&(from, _)| from == usage)
+        .map(|&(_, key)| key)
+}
+
+/// Whether any of the `usages` on `page` has a keyboard usage.
+pub fn has_keys(page: u16, usages: RangeInclusive<u16>) -> bool {
+    table(page).iter().any(|(usage, _)| usages.contains(usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::col_bits_from_keys;
+
+    #[test]
+    fn no_usage_is_mapped_twice() {
+        for table in [&CONSUMER_KEYS[..], &SYSTEM_KEYS[..]] {
+            for (i, &(usage, _)) in table.iter().enumerate() {
+                assert!(
+                    table[i + 1..].iter().all(|&(other, _)| other != usage),
+                    "usage {usage:#06x} mapped twice"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stop_and_calculator_reach_the_matrix() {
+        for (usage, cbm_key) in [
+            (CC_STOP, KEY_PAUSE),
+            (CC_AC_STOP, KEY_PAUSE),
+            (CC_AL_CALCULATOR, KEY_KPCLEARENTRY),
+        ] {
+            let key = key_for(PAGE_CONSUMER, usage).unwrap();
+            assert_eq!(col_bits_from_keys([key]), col_bits_from_keys([cbm_key]));
+            assert_ne!(col_bits_from_keys([key]), [0; 4]);
+        }
+    }
+
+    #[test]
+    fn only_consumer_and_system_control_usages_are_mapped() {
+        assert_eq!(
+            key_for(PAGE_GENERIC_DESKTOP, SC_POWER_DOWN),
+            Some(KEY_POWER)
+        );
+        assert_eq!(key_for(PAGE_GENERIC_DESKTOP, 0x30), None);
+        assert_eq!(key_for(0xFF00, CC_STOP), None);
+
+        assert!(has_keys(PAGE_CONSUMER, 0..=0xFFFF));
+        assert!(has_keys(
+            PAGE_GENERIC_DESKTOP,
+            SC_POWER_DOWN..=SC_POWER_DOWN
+        ));
+        assert!(!has_keys(PAGE_GENERIC_DESKTOP, 0x30..=0x38));
+        assert!(!has_keys(0xFF00, 0..=0xFFFF));
+    }
+}
@@ -0,0 +1,258 @@
+//! Which host keys reach which CBM-II key with the default mapping: [`KEYMAP`], [`KEY_ALIASES`],
+//! [`CHORDS`], the base layer of [`LAYERS`] and the keys the pipeline takes for itself. [`Report`]
+//! prints it as a table.
+
+use core::fmt;
+
+use crate::{
+    keys::*,
+    layer::{Action, FN_LAYER, LAYERS},
+    petscii::KeyPos,
+    pipeline::{MODE_KEY, SHIFT_LOCK_KEY},
+};
+
+/// The CBM-II keys, indexed like [`KEYMAP`]. Empty where the matrix has no key.
+#[rustfmt::skip]
+pub static LABELS: [[&str; 16]; 6] = [
+    ["F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "CRSR↓", "CRSR↑", "HOME", "RVS", "GRAPH", "STOP"],
+    ["ESC", "1", "2", "3", "4", "5", "7", "8", "9", "0", "=", "CRSR←", "?", "CE", "*", "/"],
+    ["TAB", "Q", "W", "E", "R", "6", "U", "I", "O", "-", "@", "CRSR→", "KP7", "KP8", "KP9", "KP-"],
+    ["", "A", "S", "D", "T", "Y", "J", "K", "L", "P", "]", "DEL", "KP4", "KP5", "KP6", "KP+"],
+    ["SHIFT", "Z", "X", "F", "G", "H", "M", ",", ";", "[", "RETURN", "C=", "KP1", "KP2", "KP3", "ENTER"],
+    ["CTRL", "←", "C", "V", "B", "N", "SPACE", ".", "/", "'", "π", "↑", "KP0", "KP.", "00", ""],
+];
+
+/// The keys of full size keyboards, ANSI's 104 and the two ISO adds, `(usage, name)`.
+#[rustfmt::skip]
+pub static STANDARD_KEYS: [(u8, &str); 106] = [
+    (KEY_ESC, "Esc"), (KEY_F1, "F1"), (KEY_F2, "F2"), (KEY_F3, "F3"), (KEY_F4, "F4"),
+    (KEY_F5, "F5"), (KEY_F6, "F6"), (KEY_F7, "F7"), (KEY_F8, "F8"), (KEY_F9, "F9"),
+    (KEY_F10, "F10"), (KEY_F11, "F11"), (KEY_F12, "F12"),
+    (KEY_SYSRQ, "Print Screen"), (KEY_SCROLLLOCK, "Scroll Lock"), (KEY_PAUSE, "Pause"),
+    (KEY_GRAVE, "`"), (KEY_1, "1"), (KEY_2, "2"), (KEY_3, "3"), (KEY_4, "4"), (KEY_5, "5"),
+    (KEY_6, "6"), (KEY_7, "7"), (KEY_8, "8"), (KEY_9, "9"), (KEY_0, "0"), (KEY_MINUS, "-"),
+    (KEY_EQUAL, "="), (KEY_BACKSPACE, "Backspace"),
+    (KEY_TAB, "Tab"), (KEY_Q, "Q"), (KEY_W, "W"), (KEY_E, "E"), (KEY_R, "R"), (KEY_T, "T"),
+    (KEY_Y, "Y"), (KEY_U, "U"), (KEY_I, "I"), (KEY_O, "O"), (KEY_P, "P"), (KEY_LEFTBRACE, "["),
+    (KEY_RIGHTBRACE, "]"), (KEY_BACKSLASH, "\\"),
+    (KEY_CAPSLOCK, "Caps Lock"), (KEY_A, "A"), (KEY_S, "S"), (KEY_D, "D"), (KEY_F, "F"),
+    (KEY_G, "G"), (KEY_H, "H"), (KEY_J, "J"), (KEY_K, "K"), (KEY_L, "L"), (KEY_SEMICOLON, ";"),
+    (KEY_APOSTROPHE, "'"), (KEY_HASHTILDE, "ISO #"), (KEY_ENTER, "Enter"),
+    (KEY_LEFTSHIFT, "Left Shift"), (KEY_102ND, "ISO \\"), (KEY_Z, "Z"), (KEY_X, "X"),
+    (KEY_C, "C"), (KEY_V, "V"), (KEY_B, "B"), (KEY_N, "N"), (KEY_M, "M"), (KEY_COMMA, ","),
+    (KEY_DOT, "."), (KEY_SLASH, "/"), (KEY_RIGHTSHIFT, "Right Shift"),
+    (KEY_LEFTCTRL, "Left Ctrl"), (KEY_LEFTMETA, "Left GUI"), (KEY_LEFTALT, "Left Alt"),
+    (KEY_SPACE, "Space"), (KEY_RIGHTALT, "Right Alt"), (KEY_RIGHTMETA, "Right GUI"),
+    (KEY_COMPOSE, "Menu"), (KEY_RIGHTCTRL, "Right Ctrl"),
+    (KEY_INSERT, "Insert"), (KEY_HOME, "Home"), (KEY_PAGEUP, "Page Up"), (KEY_DELETE, "Delete"),
+    (KEY_END, "End"), (KEY_PAGEDOWN, "Page Down"), (KEY_UP, "Up"), (KEY_LEFT, "Left"),
+    (KEY_DOWN, "Down"), (KEY_RIGHT, "Right"),
+    (KEY_NUMLOCK, "Num Lock"), (KEY_KPSLASH, "Keypad /"), (KEY_KPASTERISK, "Keypad *"),
+    (KEY_KPMINUS, "Keypad -"), (KEY_KP7, "Keypad 7"), (KEY_KP8, "Keypad 8"),
+    (KEY_KP9, "Keypad 9"), (KEY_KPPLUS, "Keypad +"), (KEY_KP4, "Keypad 4"),
+    (KEY_KP5, "Keypad 5"), (KEY_KP6, "Keypad 6"), (KEY_KP1, "Keypad 1"), (KEY_KP2, "Keypad 2"),
+    (KEY_KP3, "Keypad 3"), (KEY_KPENTER, "Keypad Enter"), (KEY_KP0, "Keypad 0"),
+    (KEY_KPDOT, "Keypad ."),
+];
+
+/// What a host key does with the default mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Function {
+    Key(KeyPos),
+    Chord(Chord),
+    /// Switches on a layer.
+    Layer(u8),
+    ShiftLock,
+    ModeToggle,
+}
+
+/// The positions of all CBM-II keys, row by row.
+pub fn positions() -> impl Iterator<Item = KeyPos> {
+    (0..6)
+        .flat_map(|row| (0..16).map(move |col| (row, col)))
+        .filter(|&(row, col)| !LABELS[row][col].is_empty())
+}
+
+/// The position of the CBM key with the usage `key` in [`KEYMAP`].
+fn position(key: u8) -> Option<KeyPos> {
+    positions().find(|&(row, col)| KEYMAP[row][col] == key)
+}
+
+/// Everything the host key `key` does, there's more than one function only if mappings collide.
+pub fn functions(key: u8) -> impl Iterator<Item = Function> {
+    // the base layer hides the rest of the mapping
+    let base = LAYERS[0].action(key);
+    let layered = match base {
+        Action::Key(cbm) => position(cbm).map(Function::Key),
+        Action::Chord(chord) => Some(Function::Chord(chord)),
+        Action::Momentary(layer) | Action::Toggle(layer) => Some(Function::Layer(layer)),
+        Action::Transparent | Action::None => None,
+    };
+    let mapped = base == Action::Transparent;
+
+    let aliased = KEY_ALIASES
+        .iter()
+        .filter(move |&&(alias, _)| alias == key)
+        .filter_map(|&(_, key)| position(key));
+    let chords = CHORDS
+        .iter()
+        .filter(move |&&(host, _)| host == key)
+        .map(|&(_, chord)| Function::Chord(chord));
+    let special = [
+        (SHIFT_LOCK_KEY, Function::ShiftLock),
+        (Some(MODE_KEY), Function::ModeToggle),
+    ]
+    .into_iter()
+    .filter(move |&(special, _)| special == Some(key))
+    .map(|(_, function)| function);
+
+    (key != KEY_NONE)
+        .then(|| position(key))
+        .flatten()
+        .into_iter()
+        .chain(aliased)
+        .map(Function::Key)
+        .chain(chords)
+        .filter(move |_| mapped)
+        .chain(layered)
+        .chain(special)
+}
+
+/// The host keys reaching the CBM key at `pos`, standard keys and others.
+pub fn host_keys(pos: KeyPos) -> impl Iterator<Item = u8> {
+    (0..=u8::MAX).filter(move |&key| functions(key).any(|function| function == Function::Key(pos)))
+}
+
+/// The name of a standard key, `None` for others.
+pub fn name(key: u8) -> Option<&'static str> {
+    STANDARD_KEYS
+        .iter()
+        .find(|&&(standard, _)| standard == key)
+        .map(|&(_, name)| name)
+}
+
+/// Width of the first column of the [`Report`].
+const WIDTH: usize = 14;
+
+/// The coverage as a table: every CBM key with its host keys, then the chords, the other
+/// functions like the Fn key and the standard keys without one.
+pub struct Report;
+
+impl Report {
+    fn write_keys(f: &mut fmt::Formatter<'_>, keys: impl IntoIterator<Item = u8>) -> fmt::Result {
+        for (i, key) in keys.into_iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match name(key) {
+                Some(name) => write!(f, "{name}")?,
+                None => write!(f, "{key:#04x}")?,
+            }
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<WIDTH$}host keys", "CBM-II key")?;
+        for pos in positions() {
+            write!(f, "{:<WIDTH$}", LABELS[pos.0][pos.1])?;
+            Self::write_keys(f, host_keys(pos))?;
+        }
+
+        writeln!(f, "\nchords")?;
+        for (key, chord) in CHORDS {
+            let Some((row, col)) = position(chord.key) else {
+                continue;
+            };
+            let modifiers = [
+                (MOD_LEFTSHIFT, "SHIFT+"),
+                (MOD_LEFTCTRL, "CTRL+"),
+                (MOD_RIGHTMETA, "C=+"),
+            ];
+            let mut width = WIDTH;
+            for (bit, modifier) in modifiers {
+                if chord.modifiers & bit != 0 {
+                    write!(f, "{modifier}")?;
+                    width = width.saturating_sub(modifier.len());
+                }
+            }
+            write!(f, "{:<width$}", LABELS[row][col])?;
+            Self::write_keys(f, [key])?;
+        }
+
+        writeln!(f, "\nother functions")?;
+        if let Some(key) = SHIFT_LOCK_KEY {
+            write!(f, "{:<WIDTH$}", "shift lock")?;
+            Self::write_keys(f, [key])?;
+        }
+        write!(f, "{:<WIDTH$}", "mode toggle")?;
+        Self::write_keys(f, [MODE_KEY])?;
+        write!(f, "{:<WIDTH$}", "Fn layer")?;
+        let fn_keys = (0..=u8::MAX)
+            .filter(|&key| functions(key).any(|function| function == Function::Layer(FN_LAYER)));
+        Self::write_keys(f, fn_keys)?;
+
+        write!(f, "\nunused: ")?;
+        let unused = STANDARD_KEYS
+            .iter()
+            .map(|&(key, _)| key)
+            .filter(|&key| functions(key).next().is_none());
+        Self::write_keys(f, unused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::petscii::{self, COMMODORE_KEY, CTRL_KEY, NO_KEY, SHIFT_KEY};
+
+    #[test]
+    fn every_cbm_key_has_a_standard_host_key() {
+        assert_eq!(positions().count(), 94);
+        for pos in positions() {
+            let standard = host_keys(pos).filter(|&key| name(key).is_some()).count();
+            // left and right, and ANSI's backslash and ISO's # in the same place
+            let shared = [SHIFT_KEY, CTRL_KEY, position(KEY_BACKSLASH).unwrap()];
+            let expected = if shared.contains(&pos) { 2 } else { 1 };
+            assert_eq!(standard, expected, "{}", LABELS[pos.0][pos.1]);
+        }
+    }
+
+    #[test]
+    fn host_keys_do_one_thing() {
+        for key in 0..=u8::MAX {
+            assert!(functions(key).count() <= 1, "host key {key:#04x}");
+        }
+    }
+
+    #[test]
+    fn common_host_keys_do_something() {
+        for key in [KEY_F11, KEY_DELETE, KEY_END, KEY_CAPSLOCK, KEY_NUMLOCK] {
+            assert!(functions(key).next().is_some(), "{}", name(key).unwrap());
+        }
+    }
+
+    #[test]
+    fn labels_match_the_matrix() {
+        for (row, labels) in LABELS.iter().enumerate() {
+            for (col, label) in labels.iter().enumerate() {
+                // the modifiers have no code of their own
+                let is_modifier = [SHIFT_KEY, CTRL_KEY, COMMODORE_KEY].contains(&(row, col));
+                let is_key = is_modifier || petscii::NORMAL[row][col] != NO_KEY;
+                assert_eq!(label.is_empty(), !is_key, "{row}, {col}");
+            }
+        }
+    }
+
+    #[test]
+    fn report_lists_everything() {
+        let report = Report.to_string();
+        assert!(report.contains("RVS           F11\n"));
+        assert!(report.contains("CE            Num Lock, 0xd9\n"));
+        assert!(report.contains("SHIFT+DEL     Delete\n"));
+        assert!(report.contains("Fn layer      Left GUI\n"));
+        assert!(report.contains("unused: ISO \\, Left Alt\n"));
+    }
+}
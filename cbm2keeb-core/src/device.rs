@@ -0,0 +1,294 @@
+//! Keeps track of the connected keyboards and their keys, so nothing stays pressed on the CBM when
+//! a keyboard goes away or reports garbage.
+//!
+//! Each keyboard (e.g. a main keyboard and a keypad behind a hub) has its own key state, the
+//! matrix shows all of them merged: a key is pressed while any keyboard holds it. Every transition
+//! returns the key events that get the merged state from old to new, to be run through the
+//! [`Pipeline`](crate::pipeline::Pipeline).
+//!
+//! Each keyboard's keys go through the [`Keymap`] of its profile before they're merged. The rest
+//! of its [`Config`] applies to the events from its reports, see
+//! [`Pipeline::configure`](crate::pipeline::Pipeline::configure).
+
+use crate::{
+    key_state::{KeyEvent, KeyState},
+    keys::{KEY_ERR_OVF, KEY_ERR_UNDEFINED},
+    layout::{HostLayout, DEFAULT_LAYOUT},
+    profile::Keymap,
+    tap_hold::Behavior,
+};
+
+/// Keyboards tracked at the same time, reports from further ones are ignored.
+pub const MAX_DEVICES: usize = 4;
+
+/// How a keyboard's keys are handled, from its [`Profile`](crate::profile::Profile).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub keymap: Keymap,
+    /// The layout its keys type in, see [`Profile::layout`](crate::profile::Profile::layout).
+    pub layout: HostLayout,
+    /// Its tap-hold and one-shot keys, see [`TapHold`](crate::tap_hold::TapHold).
+    pub behaviors: &'static [(u8, Behavior)],
+}
+
+impl Config {
+    /// For keyboards without a profile.
+    pub const DEFAULT: Self = Self {
+        keymap: Keymap::IDENTITY,
+        layout: DEFAULT_LAYOUT,
+        behaviors: &[],
+    };
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Keyboard {
+    addr: u8,
+    config: Config,
+    /// After the keymap.
+    keys: KeyState,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Devices {
+    keyboards: [Option<Keyboard>; MAX_DEVICES],
+}
+
+impl Devices {
+    pub const fn new() -> Self {
+        Self {
+            keyboards: [None; MAX_DEVICES],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keyboards.iter().all(Option::is_none)
+    }
+
+    /// Addresses of the tracked keyboards.
+    pub fn addrs(&self) -> impl Iterator<Item = u8> + '_ {
+        self.keyboards
+            .iter()
+            .flatten()
+            .map(|keyboard| keyboard.addr)
+    }
+
+    /// Keys pressed on any keyboard.
+    pub fn keys(&self) -> KeyState {
+        let mut merged = KeyState::new();
+        for key in self
+            .keyboards
+            .iter()
+            .flatten()
+            .flat_map(|kb| kb.keys.iter())
+        {
+            merged.press(key);
+        }
+        merged
+    }
+
+    /// A keyboard was enumerated, its reports are handled as `config` says. Keys still held from
+    /// before, e.g. when the same address was enumerated again without a removal, are released.
+    pub fn added(&mut self, addr: u8, config: Config) -> impl Iterator<Item = KeyEvent> {
+        let old = self.keys();
+        if let Some(slot) = self.slot(addr).or_else(|| self.free_slot()) {
+            self.keyboards[slot] = Some(Keyboard {
+                addr,
+                config,
+                keys: KeyState::new(),
+            });
+        }
+        old.diff(&self.keys())
+    }
+
+    /// The config of the keyboard at `addr`, [`Config::DEFAULT`] if it isn't tracked.
+    pub fn config(&self, addr: u8) -> Config {
+        self.slot(addr)
+            .and_then(|slot| self.keyboards[slot])
+            .map_or(Config::DEFAULT, |keyboard| keyboard.config)
+    }
+
+    /// Releases the keys of the keyboard at `addr`, keys held on other keyboards stay.
+    pub fn removed(&mut self, addr: u8) -> impl Iterator<Item = KeyEvent> {
+        let old = self.keys();
+        if let Some(slot) = self.slot(addr) {
+            self.keyboards[slot] = None;
+        }
+        old.diff(&self.keys())
+    }
+
+    /// All keyboards are gone without a removal, after a bus error or when the host reports no
+    /// device at all. Releases all keys.
+    pub fn lost(&mut self) -> impl Iterator<Item = KeyEvent> {
+        let old = self.keys();
+        self.keyboards = [None; MAX_DEVICES];
+        old.diff(&KeyState::new())
+    }
+
+    /// A boot protocol report from the keyboard at `addr`, see [`Devices::report_keys`].
+    pub fn report(
+        &mut self,
+        addr: u8,
+        modifiers: u8,
+        keys: impl IntoIterator<Item = u8>,
+    ) -> Option<impl Iterator<Item = KeyEvent>> {
+        self.report_keys(addr, KeyState::from_boot_report(modifiers, keys))
+    }
+
+    /// The pressed keys of the keyboard at `addr`, from a report of any protocol. Returns `None`
+    /// if the report is ignored: for reports with error codes instead of keys, like the phantom
+    /// state on rollover, the keyboard's last valid state is kept. Reports from unknown addresses
+    /// are taken as an addition with [`Config::DEFAULT`], unless all slots are taken.
+    pub fn report_keys(
+        &mut self,
+        addr: u8,
+        keys: KeyState,
+    ) -> Option<impl Iterator<Item = KeyEvent>> {
+        if (KEY_ERR_OVF..=KEY_ERR_UNDEFINED).any(|error| keys.is_pressed(error)) {
+            return None;
+        }
+
+        let slot = self.slot(addr).or_else(|| self.free_slot())?;
+        let config = self.keyboards[slot].map_or(Config::DEFAULT, |keyboard| keyboard.config);
+        let old = self.keys();
+        self.keyboards[slot] = Some(Keyboard {
+            addr,
+            config,
+            keys: config.keymap.apply(&keys),
+        });
+        Some(old.diff(&self.keys()))
+    }
+
+    fn slot(&self, addr: u8) -> Option<usize> {
+        self.keyboards
+            .iter()
+            .position(|keyboard| keyboard.is_some_and(|keyboard| keyboard.addr == addr))
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.keyboards.iter().position(Option::is_none)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::*;
+
+    fn events(events: impl Iterator<Item = KeyEvent>) -> Vec<KeyEvent> {
+        events.collect()
+    }
+
+    #[test]
+    fn removal_releases_everything() {
+        let mut devices = Devices::new();
+        events(devices.added(1, Config::DEFAULT));
+        let pressed = events(devices.report(1, MOD_LEFTSHIFT, [KEY_A]).unwrap());
+        assert_eq!(
+            pressed,
+            [KeyEvent::Press(KEY_LEFTSHIFT), KeyEvent::Press(KEY_A)]
+        );
+
+        assert_eq!(
+            events(devices.removed(1)),
+            [KeyEvent::Release(KEY_A), KeyEvent::Release(KEY_LEFTSHIFT)]
+        );
+        assert!(devices.is_empty());
+        assert!(devices.keys().is_empty());
+    }
+
+    #[test]
+    fn bus_errors_and_reenumeration_release_everything() {
+        let mut devices = Devices::new();
+        events(devices.report(1, 0, [KEY_A]).unwrap());
+        assert_eq!(events(devices.lost()), [KeyEvent::Release(KEY_A)]);
+
+        events(devices.report(1, 0, [KEY_B]).unwrap());
+        assert_eq!(
+            events(devices.added(1, Config::DEFAULT)),
+            [KeyEvent::Release(KEY_B)]
+        );
+    }
+
+    #[test]
+    fn rollover_keeps_last_state() {
+        let mut devices = Devices::new();
+        events(devices.report(1, 0, [KEY_A, KEY_B]).unwrap());
+
+        assert!(devices.report(1, 0, [KEY_ERR_OVF; 6]).is_none());
+        assert_eq!(devices.keys().iter().collect::<Vec<_>>(), [KEY_A, KEY_B]);
+
+        assert_eq!(
+            events(devices.report(1, 0, [KEY_A]).unwrap()),
+            [KeyEvent::Release(KEY_B)]
+        );
+    }
+
+    #[test]
+    fn keyboards_are_merged() {
+        let mut devices = Devices::new();
+        events(devices.added(1, Config::DEFAULT));
+        events(devices.added(2, Config::DEFAULT));
+        events(devices.report(1, MOD_LEFTSHIFT, [KEY_A]).unwrap());
+
+        // the keypad pressing and releasing a key the keyboard holds doesn't release it
+        assert_eq!(
+            events(devices.report(2, MOD_LEFTSHIFT, [KEY_KP1]).unwrap()),
+            [KeyEvent::Press(KEY_KP1)]
+        );
+        assert_eq!(
+            events(devices.report(2, 0, []).unwrap()),
+            [KeyEvent::Release(KEY_KP1)]
+        );
+        assert_eq!(
+            devices.keys().iter().collect::<Vec<_>>(),
+            [KEY_A, KEY_LEFTSHIFT]
+        );
+    }
+
+    #[test]
+    fn adding_and_removing_keeps_other_keyboards() {
+        let mut devices = Devices::new();
+        events(devices.report(1, 0, [KEY_A]).unwrap());
+        events(devices.report(2, 0, [KEY_A, KEY_B]).unwrap());
+
+        assert_eq!(events(devices.added(3, Config::DEFAULT)), []);
+        assert_eq!(events(devices.removed(2)), [KeyEvent::Release(KEY_B)]);
+        assert_eq!(events(devices.removed(4)), []);
+        assert_eq!(devices.addrs().collect::<Vec<_>>(), [1, 3]);
+    }
+
+    #[test]
+    fn configs_apply_per_keyboard() {
+        let swap = Config {
+            keymap: Keymap::remap(&[(KEY_Y, KEY_Z), (KEY_Z, KEY_Y)]),
+            layout: HostLayout::German,
+            behaviors: &[],
+        };
+        let mut devices = Devices::new();
+        events(devices.added(1, swap));
+        events(devices.added(2, Config::DEFAULT));
+
+        assert_eq!(
+            events(devices.report(1, 0, [KEY_Y]).unwrap()),
+            [KeyEvent::Press(KEY_Z)]
+        );
+        assert_eq!(
+            events(devices.report(2, 0, [KEY_Y]).unwrap()),
+            [KeyEvent::Press(KEY_Y)]
+        );
+
+        assert_eq!(devices.config(1), swap);
+        assert_eq!(devices.config(2), Config::DEFAULT);
+        assert_eq!(devices.config(3), Config::DEFAULT);
+    }
+
+    #[test]
+    fn further_keyboards_are_ignored() {
+        let mut devices = Devices::new();
+        for addr in 1..=MAX_DEVICES as u8 {
+            events(devices.added(addr, Config::DEFAULT));
+        }
+        assert!(devices.report(9, 0, [KEY_A]).is_none());
+        assert!(devices.keys().is_empty());
+    }
+}
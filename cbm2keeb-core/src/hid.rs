@@ -0,0 +1,891 @@
+//! HID report descriptor parsing, and decoding input reports with the parsed layout.
+//!
+//! Only what's needed to find the pressed usages in an input report is kept: the position, size
+//! and usages of every data field of the Input items. Array fields hold usage indices (like the 6
+//! key slots of the boot protocol), variable fields a value per usage (like NKRO bitmaps). Of the
+//! Output items, only where the LEDs are is kept.
+
+use crate::{consumer, key_state::KeyState};
+
+pub const PAGE_GENERIC_DESKTOP: u16 = 0x01;
+pub const PAGE_KEYBOARD: u16 = 0x07;
+pub const PAGE_LED: u16 = 0x08;
+pub const PAGE_CONSUMER: u16 = 0x0C;
+
+/// Input fields kept per descriptor, further ones are ignored.
+pub const MAX_FIELDS: usize = 24;
+/// Report IDs kept per descriptor, fields of further ones are ignored.
+pub const MAX_REPORT_IDS: usize = 8;
+/// Nesting of Push items.
+const MAX_PUSH: usize = 4;
+/// Usage items kept per main item.
+const MAX_USAGES: usize = 16;
+/// Longest output report with LEDs that's used, including the report ID.
+pub const MAX_OUTPUT_REPORT: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    /// An item extends past the end of the descriptor.
+    Truncated,
+    /// More Pop than Push items, or too many Push items.
+    Stack,
+    /// A report is longer than 65535 bits.
+    TooLong,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FieldKind {
+    /// Each element holds the index of a usage that's active, or a value outside the logical range
+    /// for none.
+    Array,
+    /// One element per usage, active if not zero.
+    Variable,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Field {
+    /// 0 if the descriptor doesn't use report IDs.
+    pub report_id: u8,
+    pub kind: FieldKind,
+    pub page: u16,
+    pub usage_min: u16,
+    pub usage_max: u16,
+    pub logical_min: i32,
+    pub logical_max: i32,
+    /// Position in the report, not counting the report ID.
+    pub bit_offset: u16,
+    pub size: u8,
+    pub count: u16,
+}
+
+impl Field {
+    /// Whether the field carries keys: keyboard usages, or consumer and system control usages
+    /// with a keyboard usage in [`consumer`].
+    pub fn has_keys(&self) -> bool {
+        self.page == PAGE_KEYBOARD || consumer::has_keys(self.page, self.usage_min..=self.usage_max)
+    }
+
+    /// The active usages in `data`, the report without its ID.
+    pub fn usages<'a>(&self, data: &'a [u8]) -> impl Iterator<Item = u16> + 'a {
+        let field = *self;
+        (0..field.count).filter_map(move |i| {
+            let offset = field.bit_offset as usize + i as usize * field.size as usize;
+            let value = read_bits(data, offset, field.size)?;
+            match field.kind {
+                FieldKind::Array => {
+                    let value = field.extend(value);
+                    if value < field.logical_min || value > field.logical_max {
+                        return None;
+                    }
+                    // the logical range may span all of i32
+                    let usage = field.usage_min as i64 + (value as i64 - field.logical_min as i64);
+                    (usage > 0 && usage <= field.usage_max as i64).then_some(usage as u16)
+                }
+                FieldKind::Variable => {
+                    // further elements repeat the last usage
+                    (value != 0).then_some(field.usage_min.saturating_add(i).min(field.usage_max))
+                }
+            }
+        })
+    }
+
+    /// Sign extends `value` if the logical range is signed.
+    fn extend(&self, value: u32) -> i32 {
+        if self.logical_min < 0 && self.size < 32 && value & (1 << (self.size - 1)) != 0 {
+            (value | !0 << self.size) as i32
+        } else {
+            value as i32
+        }
+    }
+}
+
+/// `size` bits (at most 32) starting at bit `offset`, least significant bit first. `None` if the
+/// report is too short.
+fn read_bits(data: &[u8], offset: usize, size: u8) -> Option<u32> {
+    let mut value = 0u32;
+    for bit in 0..size as usize {
+        let pos = offset + bit;
+        let byte = *data.get(pos / 8)?;
+        value |= ((byte >> (pos % 8)) as u32 & 1) << bit;
+    }
+    Some(value)
+}
+
+/// Where the LEDs are in an output report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LedOutput {
+    /// 0 if the descriptor doesn't use report IDs.
+    pub report_id: u8,
+    /// Bit of the LED `usage_min`, not counting the report ID. One bit per LED.
+    pub bit_offset: u16,
+    pub usage_min: u16,
+    pub usage_max: u16,
+    /// Length of the report in bytes, not counting the report ID.
+    pub len: u16,
+}
+
+impl LedOutput {
+    /// The LED byte of boot protocol keyboards.
+    pub const BOOT: Self = Self {
+        report_id: 0,
+        bit_offset: 0,
+        usage_min: 1,
+        usage_max: 5,
+        len: 1,
+    };
+
+    /// The output report lighting `leds` (see [`leds`](crate::leds)), starting with the report
+    /// ID if there is one.
+    pub fn report<'a>(&self, leds: u8, buf: &'a mut [u8; MAX_OUTPUT_REPORT]) -> &'a [u8] {
+        *buf = [0; MAX_OUTPUT_REPORT];
+        let start = (self.report_id != 0) as usize;
+        buf[0] = self.report_id;
+        for usage in self.usage_min.max(1)..=self.usage_max.min(8) {
+            if leds & (1 << (usage - 1)) != 0 {
+                let pos = self.bit_offset as usize + (usage - self.usage_min) as usize;
+                if let Some(byte) = buf[start..].get_mut(pos / 8) {
+                    *byte |= 1 << (pos % 8);
+                }
+            }
+        }
+        &buf[..start + self.len as usize]
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Globals {
+    page: u16,
+    logical_min: i32,
+    logical_max: i32,
+    /// Logical maximum as unsigned, for descriptors giving e.g. 0xFF in a single byte.
+    logical_max_unsigned: u32,
+    size: u8,
+    count: u16,
+    report_id: u8,
+}
+
+/// Local items, reset after each main item.
+#[derive(Clone, Copy, Debug, Default)]
+struct Locals {
+    /// Extended usages, with the page in the upper 16 bits if the item had 4 bytes.
+    usages: [u32; MAX_USAGES],
+    usage_count: usize,
+    usage_min: Option<u32>,
+    usage_max: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReportDescriptor {
+    fields: [Option<Field>; MAX_FIELDS],
+    report_ids: bool,
+    leds: Option<LedOutput>,
+}
+
+impl ReportDescriptor {
+    /// The fixed layout of boot protocol keyboard reports: a modifier bitmap, a reserved byte and
+    /// 6 key slots.
+    pub const fn boot_keyboard() -> Self {
+        let mut fields = [None; MAX_FIELDS];
+        fields[0] = Some(Field {
+            report_id: 0,
+            kind: FieldKind::Variable,
+            page: PAGE_KEYBOARD,
+            usage_min: 0xE0,
+            usage_max: 0xE7,
+            logical_min: 0,
+            logical_max: 1,
+            bit_offset: 0,
+            size: 1,
+            count: 8,
+        });
+        fields[1] = Some(Field {
+            report_id: 0,
+            kind: FieldKind::Array,
+            page: PAGE_KEYBOARD,
+            usage_min: 0,
+            usage_max: 0xFF,
+            logical_min: 0,
+            logical_max: 0xFF,
+            bit_offset: 16,
+            size: 8,
+            count: 6,
+        });
+        Self {
+            fields,
+            report_ids: false,
+            leds: Some(LedOutput::BOOT),
+        }
+    }
+
+    pub fn parse(mut bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            descriptor: Self {
+                fields: [None; MAX_FIELDS],
+                report_ids: false,
+                leds: None,
+            },
+            globals: Globals::default(),
+            locals: Locals::default(),
+            offsets: [None; MAX_REPORT_IDS],
+            output_offsets: [None; MAX_REPORT_IDS],
+        };
+        let mut stack = [Globals::default(); MAX_PUSH];
+        let mut depth = 0;
+
+        while let Some((&prefix, rest)) = bytes.split_first() {
+            if prefix == 0xFE {
+                // long item, none are defined
+                let len = *rest.first().ok_or(ParseError::Truncated)? as usize;
+                bytes = rest.get(2 + len..).ok_or(ParseError::Truncated)?;
+                continue;
+            }
+
+            let len = [0, 1, 2, 4][prefix as usize & 0b11];
+            let data = rest.get(..len).ok_or(ParseError::Truncated)?;
+            bytes = &rest[len..];
+            let value = data
+                .iter()
+                .rev()
+                .fold(0u32, |value, &byte| value << 8 | byte as u32);
+            let signed = match len {
+                1 => value as i8 as i32,
+                2 => value as i16 as i32,
+                _ => value as i32,
+            };
+
+            let usage = if len == 4 { value } else { value & 0xFFFF };
+            let globals = &mut parser.globals;
+            let locals = &mut parser.locals;
+            match (prefix >> 2 & 0b11, prefix >> 4) {
+                // main items: Input, Output, Collection, Feature, End Collection
+                (0, 0x8) => parser.input(value)?,
+                (0, 0x9) => parser.output(value)?,
+                (0, _) => parser.locals = Locals::default(),
+
+                (1, 0x0) => globals.page = value as u16,
+                (1, 0x1) => globals.logical_min = signed,
+                (1, 0x2) => {
+                    globals.logical_max = signed;
+                    globals.logical_max_unsigned = value;
+                }
+                (1, 0x7) => globals.size = value.min(u8::MAX as u32) as u8,
+                (1, 0x8) => {
+                    globals.report_id = value as u8;
+                    parser.descriptor.report_ids = true;
+                }
+                (1, 0x9) => globals.count = value.min(u16::MAX as u32) as u16,
+                (1, 0xA) => {
+                    *stack.get_mut(depth).ok_or(ParseError::Stack)? = *globals;
+                    depth += 1;
+                }
+                (1, 0xB) => {
+                    depth = depth.checked_sub(1).ok_or(ParseError::Stack)?;
+                    *globals = stack[depth];
+                }
+
+                (2, 0x0) => {
+                    if let Some(slot) = locals.usages.get_mut(locals.usage_count) {
+                        *slot = usage;
+                        locals.usage_count += 1;
+                    }
+                }
+                (2, 0x1) => locals.usage_min = Some(usage),
+                (2, 0x2) => locals.usage_max = Some(usage),
+                _ => {}
+            }
+        }
+
+        let mut descriptor = parser.descriptor;
+        // the LEDs' report is complete now
+        descriptor.leds = descriptor.leds.and_then(|leds| {
+            let (_, bits) = parser
+                .output_offsets
+                .into_iter()
+                .flatten()
+                .find(|&(id, _)| id == leds.report_id)?;
+            let len = bits.div_ceil(8);
+            (len as usize + descriptor.report_ids as usize <= MAX_OUTPUT_REPORT)
+                .then_some(LedOutput { len, ..leds })
+        });
+        Ok(descriptor)
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = &Field> {
+        self.fields.iter().flatten()
+    }
+
+    /// Where the LEDs are in the output reports, if the device has any.
+    pub fn leds(&self) -> Option<LedOutput> {
+        self.leds
+    }
+
+    /// Whether the reports start with a report ID byte.
+    pub fn uses_report_ids(&self) -> bool {
+        self.report_ids
+    }
+
+    /// Whether any input field has usages on `page`.
+    pub fn has_page(&self, page: u16) -> bool {
+        self.fields().any(|field| field.page == page)
+    }
+
+    /// The active usages on `page` in an input report, or `None` if the report has no fields on
+    /// `page` (like a consumer control report of a keyboard that sends its keys in another one).
+    pub fn usages<'a>(
+        &'a self,
+        report: &'a [u8],
+        page: u16,
+    ) -> Option<impl Iterator<Item = u16> + 'a> {
+        let (report_id, data) = self.split(report)?;
+        let mut fields = self
+            .fields()
+            .filter(move |field| field.report_id == report_id && field.page == page)
+            .peekable();
+        fields.peek()?;
+        Some(fields.flat_map(move |field| field.usages(data)))
+    }
+
+    /// The report ID of an input report and its data without the ID, 0 if the descriptor doesn't
+    /// use report IDs. `None` for an empty report with IDs.
+    fn split<'a>(&self, report: &'a [u8]) -> Option<(u8, &'a [u8])> {
+        match self.report_ids {
+            true => report.split_first().map(|(&id, data)| (id, data)),
+            false => Some((0, report)),
+        }
+    }
+
+    /// Whether any input field carries keys, see [`Field::has_keys`].
+    pub fn has_keys(&self) -> bool {
+        self.fields().any(Field::has_keys)
+    }
+
+    /// The pressed keys in an input report, with modifiers as their usages like
+    /// [`KeyState::from_boot_report`], and consumer and system control usages translated to keys.
+    /// `None` if the report has no field carrying keys, like the reports of a mouse.
+    pub fn keys(&self, report: &[u8]) -> Option<KeyState> {
+        let (report_id, _) = self.split(report)?;
+        let has_keys = |field: &Field| field.report_id == report_id && field.has_keys();
+        if !self.fields().any(has_keys) {
+            return None;
+        }
+
+        let mut keys = KeyState::new();
+        for page in [PAGE_KEYBOARD, PAGE_CONSUMER, PAGE_GENERIC_DESKTOP] {
+            let Some(usages) = self.usages(report, page) else {
+                continue;
+            };
+            for usage in usages {
+                let key = match page {
+                    PAGE_KEYBOARD => u8::try_from(usage).ok(),
+                    _ => consumer::key_for(page, usage),
+                };
+                if let Some(key) = key {
+                    keys.press(key);
+                }
+            }
+        }
+        Some(keys)
+    }
+}
+
+/// The keys pressed on an interface. Each report only replaces the keys of its report ID, e.g. a
+/// consumer control report doesn't release the keys of the keyboard report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputKeys {
+    reports: [Option<(u8, KeyState)>; MAX_REPORT_IDS],
+}
+
+impl InputKeys {
+    pub const fn new() -> Self {
+        Self {
+            reports: [None; MAX_REPORT_IDS],
+        }
+    }
+
+    /// Takes the keys of an input report, see [`ReportDescriptor::keys`]. Returns whether the
+    /// report carries keys, the others leave the keys alone.
+    pub fn update(&mut self, descriptor: &ReportDescriptor, report: &[u8]) -> bool {
+        let (Some((report_id, _)), Some(keys)) =
+            (descriptor.split(report), descriptor.keys(report))
+        else {
+            return false;
+        };
+        let slot = self
+            .reports
+            .iter()
+            .position(|entry| entry.is_some_and(|(id, _)| id == report_id))
+            .or_else(|| self.reports.iter().position(Option::is_none));
+        // the descriptor keeps no more report IDs than there are slots
+        if let Some(slot) = slot {
+            self.reports[slot] = Some((report_id, keys));
+        }
+        true
+    }
+
+    /// The keys of all report IDs.
+    pub fn keys(&self) -> KeyState {
+        let mut merged = KeyState::new();
+        for key in self
+            .reports
+            .iter()
+            .flatten()
+            .flat_map(|(_, keys)| keys.iter())
+        {
+            merged.press(key);
+        }
+        merged
+    }
+}
+
+impl Default for InputKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Parser {
+    descriptor: ReportDescriptor,
+    globals: Globals,
+    locals: Locals,
+    /// Input bit offset per report ID.
+    offsets: [Option<(u8, u16)>; MAX_REPORT_IDS],
+    /// Output bit offset per report ID.
+    output_offsets: [Option<(u8, u16)>; MAX_REPORT_IDS],
+}
+
+/// Moves the bit offset of `report_id` past a main item of `globals`, returns where the item
+/// starts. `None` if there are too many report IDs.
+fn advance(
+    offsets: &mut [Option<(u8, u16)>],
+    globals: &Globals,
+) -> Result<Option<u16>, ParseError> {
+    let Some(slot) = offsets
+        .iter()
+        .position(|entry| entry.is_some_and(|(id, _)| id == globals.report_id))
+        .or_else(|| offsets.iter().position(Option::is_none))
+    else {
+        return Ok(None);
+    };
+    let (_, bit_offset) = offsets[slot].get_or_insert((globals.report_id, 0));
+    let start = *bit_offset;
+    *bit_offset = (globals.size as u16)
+        .checked_mul(globals.count)
+        .and_then(|bits| start.checked_add(bits))
+        .ok_or(ParseError::TooLong)?;
+    Ok(Some(start))
+}
+
+impl Parser {
+    fn input(&mut self, flags: u32) -> Result<(), ParseError> {
+        let globals = self.globals;
+        let locals = core::mem::take(&mut self.locals);
+
+        let Some(start) = advance(&mut self.offsets, &globals)? else {
+            return Ok(());
+        };
+
+        let constant = flags & 1 != 0;
+        let variable = flags & 2 != 0;
+        if constant || globals.size == 0 || globals.size > 32 || globals.count == 0 {
+            return Ok(());
+        }
+
+        let logical_max = if globals.logical_max < globals.logical_min {
+            globals.logical_max_unsigned.min(i32::MAX as u32) as i32
+        } else {
+            globals.logical_max
+        };
+        let field = Field {
+            report_id: globals.report_id,
+            kind: if variable {
+                FieldKind::Variable
+            } else {
+                FieldKind::Array
+            },
+            page: globals.page,
+            usage_min: 0,
+            usage_max: 0,
+            logical_min: globals.logical_min,
+            logical_max,
+            bit_offset: start,
+            size: globals.size,
+            count: globals.count,
+        };
+        // extended usages override the usage page
+        let split = |usage: u32| match usage >> 16 {
+            0 => (globals.page, usage as u16),
+            page => (page as u16, usage as u16),
+        };
+
+        if let (Some(min), Some(max)) = (locals.usage_min, locals.usage_max) {
+            let (page, usage_min) = split(min);
+            let (_, usage_max) = split(max);
+            self.push(Field {
+                page,
+                usage_min,
+                usage_max,
+                ..field
+            });
+            return Ok(());
+        }
+
+        let usages = &locals.usages[..locals.usage_count];
+        match (field.kind, usages) {
+            (_, []) => {}
+            (FieldKind::Array, [first, ..]) => {
+                // assumes the listed usages are consecutive
+                let (page, usage_min) = split(*first);
+                let (_, usage_max) = split(usages[usages.len() - 1]);
+                self.push(Field {
+                    page,
+                    usage_min,
+                    usage_max,
+                    ..field
+                });
+            }
+            (FieldKind::Variable, _) => {
+                // a field per run of consecutive usages, the last usage repeats for the remaining
+                // elements
+                let mut element = 0;
+                while element < field.count {
+                    let index = (element as usize).min(usages.len() - 1);
+                    let (page, usage_min) = split(usages[index]);
+                    let mut len = 1;
+                    while index + len < usages.len()
+                        && usages[index + len].checked_sub(usages[index]) == Some(len as u32)
+                    {
+                        len += 1;
+                    }
+                    let count = if index + len == usages.len() {
+                        field.count - element
+                    } else {
+                        len as u16
+                    };
+                    self.push(Field {
+                        page,
+                        usage_min,
+                        usage_max: usage_min.saturating_add(len as u16 - 1),
+                        bit_offset: start + element * field.size as u16,
+                        count,
+                        ..field
+                    });
+                    element += count;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Only the first variable field of one bit LEDs is kept.
+    fn output(&mut self, flags: u32) -> Result<(), ParseError> {
+        let globals = self.globals;
+        let locals = core::mem::take(&mut self.locals);
+
+        let Some(start) = advance(&mut self.output_offsets, &globals)? else {
+            return Ok(());
+        };
+        let constant = flags & 1 != 0;
+        let variable = flags & 2 != 0;
+        if constant
+            || !variable
+            || globals.size != 1
+            || globals.count == 0
+            || self.descriptor.leds.is_some()
+        {
+            return Ok(());
+        }
+
+        let usages = &locals.usages[..locals.usage_count];
+        // assumes listed usages are consecutive
+        let range = match (locals.usage_min, locals.usage_max, usages) {
+            (Some(min), Some(max), _) => Some((min, max)),
+            (_, _, [first, .., last]) => Some((*first, *last)),
+            (_, _, [usage]) => Some((*usage, *usage)),
+            _ => None,
+        };
+        let page = |usage: u32| match usage >> 16 {
+            0 => globals.page,
+            page => page as u16,
+        };
+        if let Some((min, max)) = range.filter(|&(min, _)| page(min) == PAGE_LED) {
+            self.descriptor.leds = Some(LedOutput {
+                report_id: globals.report_id,
+                bit_offset: start,
+                usage_min: min as u16,
+                usage_max: (max as u16).min((min as u16).saturating_add(globals.count - 1)),
+                len: 0,
+            });
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, field: Field) {
+        if let Some(slot) = self
+            .descriptor
+            .fields
+            .iter_mut()
+            .find(|slot| slot.is_none())
+        {
+            *slot = Some(field);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{keys::*, leds::*};
+
+    /// The example keyboard descriptor from the HID spec, appendix B.1.
+    const BOOT_DESCRIPTOR: &[u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, 0x15, 0x00, 0x25,
+        0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05,
+        0x75, 0x01, 0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91,
+        0x01, 0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65,
+        0x81, 0x00, 0xC0,
+    ];
+
+    /// Modifiers and a 120 key bitmap with report ID 1, a consumer control array with ID 2.
+    const NKRO_DESCRIPTOR: &[u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x85, 0x01, 0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, 0x15,
+        0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01,
+        0x19, 0x00, 0x29, 0x77, 0x95, 0x78, 0x75, 0x01, 0x81, 0x02, 0xC0, 0x05, 0x0C, 0x09, 0x01,
+        0xA1, 0x01, 0x85, 0x02, 0x19, 0x00, 0x2A, 0x3C, 0x02, 0x15, 0x00, 0x26, 0x3C, 0x02, 0x75,
+        0x10, 0x95, 0x01, 0x81, 0x00, 0xC0,
+    ];
+
+    fn keys(keys: KeyState) -> Vec<u8> {
+        keys.iter().collect()
+    }
+
+    #[test]
+    fn boot_descriptor_matches_boot_layout() {
+        let parsed = ReportDescriptor::parse(BOOT_DESCRIPTOR).unwrap();
+        let boot = ReportDescriptor::boot_keyboard();
+        assert!(!parsed.uses_report_ids());
+
+        let report = [MOD_LEFTSHIFT | MOD_RIGHTALT, 0, KEY_A, KEY_Z, 0, 0, 0, 0];
+        let expected = KeyState::from_boot_report(report[0], report[2..].iter().copied());
+        assert_eq!(parsed.keys(&report), Some(expected));
+        assert_eq!(boot.keys(&report), Some(expected));
+
+        // the phantom state is passed on, it's up to the caller to ignore it
+        let phantom = [0, 0, KEY_ERR_OVF, KEY_ERR_OVF, 1, 1, 1, 1];
+        assert_eq!(keys(parsed.keys(&phantom).unwrap()), [KEY_ERR_OVF]);
+
+        assert_eq!(parsed.leds(), Some(LedOutput::BOOT));
+        assert_eq!(boot.leds(), Some(LedOutput::BOOT));
+    }
+
+    #[test]
+    fn nkro_bitmap_with_report_ids() {
+        let descriptor = ReportDescriptor::parse(NKRO_DESCRIPTOR).unwrap();
+        assert!(descriptor.uses_report_ids());
+        assert!(descriptor.has_page(PAGE_KEYBOARD));
+        assert!(descriptor.has_page(PAGE_CONSUMER));
+
+        // more than 6 keys at once
+        let mut report = [0u8; 17];
+        report[0] = 1;
+        report[1] = MOD_LEFTCTRL;
+        for key in [KEY_A, KEY_B, KEY_C, KEY_D, KEY_E, KEY_F, KEY_G, KEY_1] {
+            report[3 + key as usize / 8] |= 1 << (key % 8);
+        }
+        assert_eq!(
+            keys(descriptor.keys(&report).unwrap()),
+            [
+                KEY_A,
+                KEY_B,
+                KEY_C,
+                KEY_D,
+                KEY_E,
+                KEY_F,
+                KEY_G,
+                KEY_1,
+                KEY_LEFTCTRL
+            ]
+        );
+
+        // the consumer report has its own ID, its usages are translated to keys
+        let consumer = [2, 0x92, 0x01];
+        let usages: Vec<u16> = descriptor
+            .usages(&consumer, PAGE_CONSUMER)
+            .unwrap()
+            .collect();
+        assert_eq!(usages, [consumer::CC_AL_CALCULATOR]);
+        assert_eq!(
+            keys(descriptor.keys(&consumer).unwrap()),
+            [KEY_KPCLEARENTRY]
+        );
+        assert!(descriptor.usages(&report, PAGE_CONSUMER).is_none());
+        // volume keys have no place in the matrix, but are keys all the same
+        assert_eq!(
+            keys(descriptor.keys(&[2, 0xE9, 0x00]).unwrap()),
+            [KEY_VOLUMEUP]
+        );
+    }
+
+    #[test]
+    fn reports_only_replace_their_own_keys() {
+        // a mouse with report ID 3 after the keyboard
+        let mouse = [
+            0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x85, 0x03, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81,
+            0x25, 0x7F, 0x75, 0x08, 0x95, 0x02, 0x81, 0x06, 0xC0,
+        ];
+        let descriptor = ReportDescriptor::parse(&[NKRO_DESCRIPTOR, &mouse].concat()).unwrap();
+        let mut input = InputKeys::new();
+
+        let mut report = [0u8; 17];
+        report[0] = 1;
+        report[3 + KEY_A as usize / 8] |= 1 << (KEY_A % 8);
+        assert!(input.update(&descriptor, &report));
+        assert!(input.update(&descriptor, &[2, 0xE9, 0x00]));
+        assert_eq!(keys(input.keys()), [KEY_A, KEY_VOLUMEUP]);
+
+        // the mouse moving has no keys
+        assert_eq!(descriptor.keys(&[3, 5, 5]), None);
+        assert!(!input.update(&descriptor, &[3, 5, 5]));
+        // releasing the consumer key leaves the keyboard's
+        assert!(input.update(&descriptor, &[2, 0x00, 0x00]));
+        assert_eq!(keys(input.keys()), [KEY_A]);
+    }
+
+    #[test]
+    fn variable_fields_with_usage_lists() {
+        // Volume Up, Volume Down, Mute, Play/Pause as single bits
+        let descriptor = ReportDescriptor::parse(&[
+            0x05, 0x0C, 0x09, 0xE9, 0x09, 0xEA, 0x09, 0xE2, 0x09, 0xCD, 0x15, 0x00, 0x25, 0x01,
+            0x75, 0x01, 0x95, 0x04, 0x81, 0x02,
+        ])
+        .unwrap();
+        assert_eq!(descriptor.fields().count(), 3);
+
+        let usages: Vec<u16> = descriptor
+            .usages(&[0b1101], PAGE_CONSUMER)
+            .unwrap()
+            .collect();
+        assert_eq!(usages, [0xE9, 0xE2, 0xCD]);
+    }
+
+    #[test]
+    fn led_output_reports() {
+        let mut buf = [0; MAX_OUTPUT_REPORT];
+        let leds = LED_CAPS_LOCK | LED_SCROLL_LOCK;
+        assert_eq!(LedOutput::BOOT.report(leds, &mut buf), [0b110]);
+
+        // report ID 3: a padding byte, then Caps Lock and Scroll Lock as a usage list
+        let descriptor = ReportDescriptor::parse(&[
+            0x05, 0x08, 0x85, 0x03, 0x75, 0x08, 0x95, 0x01, 0x91, 0x01, 0x09, 0x02, 0x09, 0x03,
+            0x75, 0x01, 0x95, 0x02, 0x91, 0x02, 0x75, 0x06, 0x95, 0x01, 0x91, 0x01,
+        ])
+        .unwrap();
+        let output = descriptor.leds().unwrap();
+        assert_eq!(
+            output,
+            LedOutput {
+                report_id: 3,
+                bit_offset: 8,
+                usage_min: 2,
+                usage_max: 3,
+                len: 2,
+            }
+        );
+        assert_eq!(output.report(leds | LED_NUM_LOCK, &mut buf), [3, 0, 0b11]);
+
+        // no LEDs without output items
+        let descriptor = ReportDescriptor::parse(&BOOT_DESCRIPTOR[..22]).unwrap();
+        assert_eq!(descriptor.leds(), None);
+    }
+
+    #[test]
+    fn oversized_reports_are_rejected() {
+        // 255 fields of 32 bits, then 65535 more
+        let descriptor = [
+            0x05, 0x07, 0x75, 0x20, 0x95, 0xFF, 0x81, 0x02, 0x75, 0x20, 0x96, 0xFF, 0xFF, 0x81,
+            0x02,
+        ];
+        assert_eq!(
+            ReportDescriptor::parse(&descriptor).unwrap_err(),
+            ParseError::TooLong
+        );
+        // the whole logical range of an array
+        let descriptor = ReportDescriptor::parse(&[
+            0x05, 0x07, 0x19, 0x00, 0x29, 0xFF, 0x17, 0x00, 0x00, 0x00, 0x80, 0x27, 0xFF, 0xFF,
+            0xFF, 0x7F, 0x75, 0x20, 0x95, 0x01, 0x81, 0x00,
+        ])
+        .unwrap();
+        assert_eq!(
+            keys(descriptor.keys(&[0xFF, 0xFF, 0xFF, 0x7F]).unwrap()),
+            []
+        );
+    }
+
+    /// Random descriptors made of real items with random data, and random reports for the ones
+    /// that parse. Nothing may panic.
+    #[test]
+    fn hostile_descriptors() {
+        const PREFIXES: [u8; 30] = [
+            0x05, 0x06, 0x09, 0x0B, 0x15, 0x16, 0x17, 0x19, 0x1B, 0x25, 0x26, 0x27, 0x29, 0x2B,
+            0x75, 0x76, 0x77, 0x85, 0x95, 0x96, 0x97, 0x81, 0x82, 0x91, 0x92, 0xA1, 0xC0, 0xA4,
+            0xB4, 0xFE,
+        ];
+        // xorshift, the same sequence every run
+        let mut state = 0x2545_F491_u32;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        for _ in 0..20_000 {
+            let mut descriptor = Vec::new();
+            for _ in 0..random() % 24 {
+                let prefix = match random() % 8 {
+                    0 => random() as u8,
+                    _ => PREFIXES[random() as usize % PREFIXES.len()],
+                };
+                descriptor.push(prefix);
+                for _ in 0..[0, 1, 2, 4][prefix as usize & 0b11] {
+                    // mostly the extremes
+                    descriptor.push(
+                        [0x00, 0x01, 0x7F, 0x80, 0xFF, random() as u8][random() as usize % 6],
+                    );
+                }
+            }
+
+            let Ok(parsed) = ReportDescriptor::parse(&descriptor) else {
+                continue;
+            };
+            let mut input = InputKeys::new();
+            for _ in 0..4 {
+                let report: Vec<u8> = (0..random() % 40).map(|_| random() as u8).collect();
+                parsed.keys(&report);
+                input.update(&parsed, &report);
+            }
+            if let Some(leds) = parsed.leds() {
+                leds.report(0xFF, &mut [0; MAX_OUTPUT_REPORT]);
+            }
+        }
+    }
+
+    #[test]
+    fn short_reports_and_descriptors() {
+        let descriptor = ReportDescriptor::parse(BOOT_DESCRIPTOR).unwrap();
+        // the missing key slots read as none
+        assert_eq!(keys(descriptor.keys(&[0, 0, KEY_A]).unwrap()), [KEY_A]);
+
+        assert_eq!(
+            ReportDescriptor::parse(&BOOT_DESCRIPTOR[..9]).unwrap_err(),
+            ParseError::Truncated
+        );
+        assert_eq!(
+            ReportDescriptor::parse(&[0xB4]).unwrap_err(),
+            ParseError::Stack
+        );
+    }
+}
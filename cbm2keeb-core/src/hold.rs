@@ -0,0 +1,402 @@
+//! Keeps every key state visible to the CBM for a minimum number of complete scans. A tap from a
+//! fast keyboard can otherwise press and release a key between two KERNAL scans, and the CBM never
+//! sees it.
+//!
+//! Releases are held back until the press was visible long enough, and the released state is kept
+//! just as long before the next press of that key. Taps arriving in the meantime are queued
+//! instead of being merged, so every one of them reaches the CBM.
+//!
+//! Keys are stretched independently of each other, except for the modifiers: what a key types
+//! depends on them, so a modifier change waits for the keys held back before it, and the events
+//! after it wait for it, keeping their distance to it.
+
+use crate::{
+    key_state::KeyEvent,
+    keys::{KEY_LEFTCTRL, KEY_RIGHTMETA},
+    pipeline::{Duration, Events, Instant, Processor, TimedEvent},
+    scan::ScanTiming,
+};
+
+/// Complete scans each key state stays visible for.
+pub const DEFAULT_SCANS: u32 = 2;
+/// How long each key state stays visible without measured scan timing: two scans at 50 Hz.
+pub const DEFAULT_FALLBACK: Duration = Duration::millis(40);
+
+/// Keys held back at the same time, further keys pass through unchanged.
+const SLOTS: usize = 16;
+/// Queued transitions per key, further taps are merged.
+const MAX_PENDING: u8 = 8;
+/// Events waiting behind a modifier change, a full queue is passed on early.
+const MAX_WAITING: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    key: u8,
+    /// State passed on to the next stage.
+    pressed: bool,
+    /// Until when `pressed` has to stay.
+    until: Instant,
+    /// Transitions still to be passed on, they alternate between release and press.
+    pending: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct HoldStretcher {
+    scans: u32,
+    fallback: Duration,
+    timing: Option<ScanTiming>,
+    slots: [Option<Slot>; SLOTS],
+    /// A modifier change that has to wait, and the events after it, in order.
+    waiting: [Option<TimedEvent>; MAX_WAITING],
+    /// The modifiers changed at `unseen_at`, with no other key passed on or held back after them.
+    /// The CBM can't have seen them yet.
+    unseen: u8,
+    unseen_at: Instant,
+}
+
+impl HoldStretcher {
+    /// Holds every state for `scans` complete scans, or for `fallback` while there's no current
+    /// scan timing. Zero scans turn the stage off.
+    pub const fn new(scans: u32, fallback: Duration) -> Self {
+        Self {
+            scans,
+            fallback,
+            timing: None,
+            slots: [None; SLOTS],
+            waiting: [None; MAX_WAITING],
+            unseen: 0,
+            unseen_at: Instant::from_ticks(0),
+        }
+    }
+
+    /// Updates the measured scan timing, see [`ScanAnalyzer`](crate::scan::ScanAnalyzer).
+    pub fn set_scan_timing(&mut self, timing: Option<ScanTiming>) {
+        self.timing = timing;
+    }
+
+    fn hold_until(&self, time: Instant) -> Instant {
+        if self.scans == 0 {
+            return time;
+        }
+
+        match self.timing.filter(|timing| timing.is_current(time)) {
+            Some(timing) => timing.next_full_scan_end(time) + timing.period * (self.scans - 1),
+            None => time + self.fallback,
+        }
+    }
+
+    fn free_slot(&mut self, now: Instant) -> Option<&mut Option<Slot>> {
+        self.slots.iter_mut().find(|slot| match slot {
+            None => true,
+            Some(slot) => !slot.pressed && slot.pending == 0 && slot.until <= now,
+        })
+    }
+
+    fn is_held_back(&self) -> bool {
+        self.slots.iter().flatten().any(|slot| slot.pending > 0)
+    }
+
+    /// When the modifier `key` can change without overtaking another key, `None` while keys are
+    /// held back.
+    fn modifier_free_at(&self, key: u8) -> Option<Instant> {
+        if self.is_held_back() {
+            return None;
+        }
+        let slot = self.slots.iter().flatten().find(|slot| slot.key == key);
+        Some(slot.map_or(Instant::from_ticks(0), |slot| slot.until))
+    }
+
+    /// Whether `event` takes back a modifier change before the CBM could see it.
+    fn takes_back(&self, event: TimedEvent) -> bool {
+        event.time == self.unseen_at && self.unseen & modifier_bit(event.event.key()) != 0
+    }
+
+    /// When the first waiting event can be passed on.
+    fn waiting_deadline(&self) -> Option<Instant> {
+        let first = self.waiting[0]?;
+        match first.event.key() {
+            key @ KEY_LEFTCTRL..=KEY_RIGHTMETA => Some(first.time.max(self.modifier_free_at(key)?)),
+            _ => Some(first.time),
+        }
+    }
+
+    /// Passes on the waiting events that are due at `now`.
+    fn pass_waiting(&mut self, now: Instant, out: &mut Events) {
+        while let Some(first) = self.waiting[0] {
+            if self
+                .waiting_deadline()
+                .is_none_or(|deadline| deadline > now)
+            {
+                return;
+            }
+            self.waiting[0] = None;
+            self.waiting.rotate_left(1);
+
+            let late = now - first.time;
+            if late > Duration::from_ticks(0) {
+                for event in self.waiting.iter_mut().flatten() {
+                    event.time += late;
+                }
+            }
+            self.stretch(TimedEvent { time: now, ..first }, out);
+        }
+    }
+
+    /// Passes `event` on, or holds it back until the key's last state was visible long enough.
+    fn stretch(&mut self, event: TimedEvent, out: &mut Events) {
+        let key = event.event.key();
+        let pressed = matches!(event.event, KeyEvent::Press(_));
+        let until = self.hold_until(event.time);
+        let taken_back = self.takes_back(event);
+
+        let slot = self.slots.iter_mut().flatten().find(|slot| slot.key == key);
+        match slot {
+            // there's nothing the CBM has seen to keep visible
+            Some(slot) if taken_back => {
+                slot.pressed = pressed;
+                slot.until = event.time;
+            }
+            Some(slot) if slot.pending > 0 || event.time < slot.until => {
+                if slot.pending < MAX_PENDING {
+                    slot.pending += 1;
+                } else {
+                    // cancels out with the last queued transition
+                    slot.pending -= 1;
+                }
+                self.unseen = 0;
+                return;
+            }
+            Some(slot) => {
+                slot.pressed = pressed;
+                slot.until = until;
+            }
+            None => {
+                if pressed && self.scans > 0 {
+                    if let Some(free) = self.free_slot(event.time) {
+                        *free = Some(Slot {
+                            key,
+                            pressed,
+                            until,
+                            pending: 0,
+                        });
+                    }
+                }
+            }
+        }
+        match modifier_bit(key) {
+            0 => self.unseen = 0,
+            bit if event.time == self.unseen_at => self.unseen |= bit,
+            bit => (self.unseen, self.unseen_at) = (bit, event.time),
+        }
+        out.emit(event);
+    }
+}
+
+/// The bit of `key` among the modifiers, 0 for other keys.
+fn modifier_bit(key: u8) -> u8 {
+    match key {
+        KEY_LEFTCTRL..=KEY_RIGHTMETA => 1 << (key - KEY_LEFTCTRL),
+        _ => 0,
+    }
+}
+
+impl Default for HoldStretcher {
+    fn default() -> Self {
+        Self::new(DEFAULT_SCANS, DEFAULT_FALLBACK)
+    }
+}
+
+impl Processor for HoldStretcher {
+    fn process(&mut self, event: TimedEvent, out: &mut Events) {
+        let wait = match event.event.key() {
+            _ if self.waiting[0].is_some() => true,
+            _ if self.takes_back(event) => false,
+            key @ KEY_LEFTCTRL..=KEY_RIGHTMETA => self
+                .modifier_free_at(key)
+                .is_none_or(|free| free > event.time),
+            _ => false,
+        };
+        if wait {
+            // a full queue is passed on early rather than letting `event` overtake it
+            if self.waiting.iter().all(Option::is_some) {
+                let waiting = core::mem::replace(&mut self.waiting, [None; MAX_WAITING]);
+                for waiting in waiting.into_iter().flatten() {
+                    self.stretch(waiting, out);
+                }
+            }
+            if let Some(slot) = self.waiting.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(event);
+            }
+            return;
+        }
+        self.stretch(event, out);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        let pending = self
+            .slots
+            .iter()
+            .flatten()
+            .filter(|slot| slot.pending > 0)
+            .map(|slot| slot.until);
+        pending.chain(self.waiting_deadline()).min()
+    }
+
+    fn timeout(&mut self, now: Instant, out: &mut Events) {
+        let until = self.hold_until(now);
+        for slot in self.slots.iter_mut().flatten() {
+            if slot.pending > 0 && slot.until <= now {
+                slot.pending -= 1;
+                slot.pressed = !slot.pressed;
+                slot.until = until;
+                self.unseen = 0;
+                out.emit(TimedEvent {
+                    time: now,
+                    event: if slot.pressed {
+                        KeyEvent::Press(slot.key)
+                    } else {
+                        KeyEvent::Release(slot.key)
+                    },
+                });
+            }
+        }
+        self.pass_waiting(now, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keys::*,
+        pipeline::fixtures::{at, press, release, run},
+    };
+
+    #[test]
+    fn stretches_taps_without_scan_timing() {
+        let mut hold = HoldStretcher::default();
+        let passed = run(&mut hold, &[press(0, KEY_A), release(5, KEY_A)]);
+        assert_eq!(passed, [press(0, KEY_A), release(40, KEY_A)]);
+    }
+
+    #[test]
+    fn stretches_taps_to_complete_scans() {
+        let mut hold = HoldStretcher::default();
+        hold.set_scan_timing(Some(ScanTiming {
+            start: at(0),
+            period: Duration::millis(20),
+            active: Duration::millis(1),
+            dwell: Duration::micros(10),
+            any_key_check: true,
+            columns: 16,
+            order: 0,
+        }));
+
+        // the scan that started at 0 ms is already underway, the ones at 20 and 40 ms count
+        let passed = run(&mut hold, &[press(5, KEY_A), release(6, KEY_A)]);
+        assert_eq!(passed, [press(5, KEY_A), release(41, KEY_A)]);
+    }
+
+    #[test]
+    fn queues_repeated_taps() {
+        let mut hold = HoldStretcher::default();
+        let input = [
+            press(0, KEY_A),
+            release(1, KEY_A),
+            press(2, KEY_A),
+            release(3, KEY_A),
+        ];
+        let passed = run(&mut hold, &input);
+        assert_eq!(
+            passed,
+            [
+                press(0, KEY_A),
+                release(40, KEY_A),
+                press(80, KEY_A),
+                release(120, KEY_A)
+            ]
+        );
+    }
+
+    #[test]
+    fn long_presses_and_other_keys_pass_through() {
+        let mut hold = HoldStretcher::default();
+        let input = [press(0, KEY_A), press(1, KEY_B), release(50, KEY_A)];
+        assert_eq!(run(&mut hold, &input), input);
+    }
+
+    #[test]
+    fn modifiers_stay_in_order_with_other_keys() {
+        let mut hold = HoldStretcher::default();
+        // shift goes down for a shifted 2, 5 ms after a tap of the unshifted one
+        let input = [
+            press(0, KEY_2),
+            release(10, KEY_2),
+            press(15, KEY_LEFTSHIFT),
+            press(35, KEY_2),
+            release(45, KEY_2),
+            release(45, KEY_LEFTSHIFT),
+        ];
+        assert_eq!(
+            run(&mut hold, &input),
+            [
+                press(0, KEY_2),
+                release(40, KEY_2),
+                // shift waits for the release, the 2 for shift and for its own release to be seen
+                press(40, KEY_LEFTSHIFT),
+                press(80, KEY_2),
+                release(120, KEY_2),
+                release(120, KEY_LEFTSHIFT),
+            ]
+        );
+    }
+
+    #[test]
+    fn modifier_changes_taken_back_at_once_pass() {
+        let mut hold = HoldStretcher::default();
+        // a chord letting go of the host's Ctrl as soon as it's pressed
+        let input = [
+            press(0, KEY_LEFTCTRL),
+            press(0, KEY_LEFTSHIFT),
+            release(0, KEY_LEFTCTRL),
+            press(20, KEY_ENTER),
+            release(30, KEY_ENTER),
+            release(30, KEY_LEFTSHIFT),
+        ];
+        assert_eq!(
+            run(&mut hold, &input),
+            [
+                press(0, KEY_LEFTCTRL),
+                press(0, KEY_LEFTSHIFT),
+                release(0, KEY_LEFTCTRL),
+                press(20, KEY_ENTER),
+                release(60, KEY_ENTER),
+                release(60, KEY_LEFTSHIFT),
+            ]
+        );
+    }
+
+    #[test]
+    fn full_waiting_queue_keeps_the_order() {
+        let mut hold = HoldStretcher::default();
+        // shift waits for its own release, more taps wait behind it than fit into the queue
+        let mut input = vec![press(0, KEY_LEFTSHIFT), release(10, KEY_LEFTSHIFT)];
+        for key in KEY_A..KEY_A + MAX_WAITING as u8 {
+            input.extend([press(20, key), release(20, key)]);
+        }
+        let output = run(&mut hold, &input);
+
+        assert_eq!(output.len(), input.len());
+        for key in KEY_A..KEY_A + MAX_WAITING as u8 {
+            let position = |event| output.iter().position(|out| out.event == event);
+            assert!(position(KeyEvent::Press(key)) < position(KeyEvent::Release(key)));
+        }
+    }
+
+    #[test]
+    fn zero_scans_turn_it_off() {
+        let mut hold = HoldStretcher::new(0, DEFAULT_FALLBACK);
+        let input = [press(0, KEY_A), release(1, KEY_A), press(2, KEY_A)];
+        assert_eq!(run(&mut hold, &input), input);
+    }
+}
@@ -0,0 +1,168 @@
+//! Pressed state of all 256 HID keyboard usages, and the press/release events between two states.
+
+use crate::keys::{KEY_LEFTCTRL, KEY_NONE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyEvent {
+    Press(u8),
+    Release(u8),
+}
+
+impl KeyEvent {
+    pub fn key(self) -> u8 {
+        match self {
+            KeyEvent::Press(key) | KeyEvent::Release(key) => key,
+        }
+    }
+}
+
+/// One bit per HID usage. Boot protocol modifier bits are stored as their usages
+/// `KEY_LEFTCTRL ... KEY_RIGHTMETA`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyState([u32; 8]);
+
+impl KeyState {
+    pub const fn new() -> Self {
+        Self([0; 8])
+    }
+
+    pub fn from_boot_report(modifiers: u8, keys: impl IntoIterator<Item = u8>) -> Self {
+        let mut state = Self::new();
+        for bit in 0..8 {
+            if modifiers & (1 << bit) != 0 {
+                state.press(KEY_LEFTCTRL + bit);
+            }
+        }
+        for key in keys.into_iter().filter(|&key| key != KEY_NONE) {
+            state.press(key);
+        }
+        state
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.0[key as usize / 32] & (1 << (key % 32)) != 0
+    }
+
+    pub fn press(&mut self, key: u8) {
+        self.0[key as usize / 32] |= 1 << (key % 32);
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.0[key as usize / 32] &= !(1 << (key % 32));
+    }
+
+    pub fn apply(&mut self, event: KeyEvent) {
+        match event {
+            KeyEvent::Press(key) => self.press(key),
+            KeyEvent::Release(key) => self.release(key),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == [0; 8]
+    }
+
+    /// All pressed keys, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u8> {
+        keys_in(self.0)
+    }
+
+    /// The events that turn `self` into `new`, all releases before all presses, each in
+    /// ascending order. Modifiers are pressed before the other keys, so keys pressed together with
+    /// a modifier are modified, and they are released after them anyway.
+    pub fn diff(&self, new: &KeyState) -> impl Iterator<Item = KeyEvent> {
+        let (old, new) = (self.0, new.0);
+        let released = core::array::from_fn(|i| old[i] & !new[i]);
+        let pressed: [u32; 8] = core::array::from_fn(|i| new[i] & !old[i]);
+        let modifiers = core::array::from_fn(|i| pressed[i] & MODIFIERS[i]);
+        let others = core::array::from_fn(|i| pressed[i] & !MODIFIERS[i]);
+
+        keys_in(released)
+            .map(KeyEvent::Release)
+            .chain(keys_in(modifiers).map(KeyEvent::Press))
+            .chain(keys_in(others).map(KeyEvent::Press))
+    }
+}
+
+/// `KEY_LEFTCTRL ... KEY_RIGHTMETA`, as the words of a [`KeyState`].
+const MODIFIERS: [u32; 8] = {
+    let mut words = [0; 8];
+    words[KEY_LEFTCTRL as usize / 32] = 0xFF << (KEY_LEFTCTRL % 32);
+    words
+};
+
+fn keys_in(words: [u32; 8]) -> impl Iterator<Item = u8> {
+    (0..8).flat_map(move |word| {
+        let mut bits = words[word];
+        core::iter::from_fn(move || {
+            if bits == 0 {
+                return None;
+            }
+            let bit = bits.trailing_zeros();
+            bits &= bits - 1;
+            Some((word as u32 * 32 + bit) as u8)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::*;
+
+    #[test]
+    fn boot_report_modifiers_become_usages() {
+        let state = KeyState::from_boot_report(MOD_RIGHTMETA | MOD_LEFTSHIFT, [KEY_A, KEY_NONE]);
+        assert_eq!(
+            state.iter().collect::<Vec<_>>(),
+            [KEY_A, KEY_LEFTSHIFT, KEY_RIGHTMETA]
+        );
+    }
+
+    #[test]
+    fn press_release_every_usage() {
+        for key in 0..=255 {
+            let mut state = KeyState::new();
+            state.press(key);
+            assert!(state.is_pressed(key));
+            assert_eq!(state.iter().collect::<Vec<_>>(), [key]);
+            state.release(key);
+            assert!(state.is_empty());
+        }
+    }
+
+    #[test]
+    fn diff_releases_before_presses() {
+        let old = KeyState::from_boot_report(MOD_LEFTSHIFT, [KEY_A, KEY_B]);
+        let new = KeyState::from_boot_report(MOD_RIGHTALT, [KEY_B, KEY_C]);
+
+        assert_eq!(
+            old.diff(&new).collect::<Vec<_>>(),
+            [
+                KeyEvent::Release(KEY_A),
+                KeyEvent::Release(KEY_LEFTSHIFT),
+                KeyEvent::Press(KEY_RIGHTALT),
+                KeyEvent::Press(KEY_C),
+            ]
+        );
+    }
+
+    #[test]
+    fn applying_diff_reaches_new_state() {
+        let states = [
+            KeyState::new(),
+            KeyState::from_boot_report(0xFF, [KEY_Z, KEY_KP0, KEY_F24]),
+            KeyState::from_boot_report(MOD_LEFTCTRL, [KEY_Z, KEY_1]),
+            KeyState::new(),
+        ];
+
+        for pair in states.windows(2) {
+            let mut state = pair[0];
+            for event in pair[0].diff(&pair[1]) {
+                state.apply(event);
+            }
+            assert_eq!(state, pair[1]);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! QMK style layers over the default mapping. Each [`Layer`] gives some host keys an [`Action`],
+//! the others fall through to the active layer below, and below the lowest one to the default
+//! mapping of the [`Translator`](crate::translate::Translator). Layer 0 is always active, further
+//! layers are switched on while a key is held, toggled, or held with the Fn key.
+//!
+//! Layers are built at compile time with [`Layer::new`] and checked by [`check`].
+
+use crate::keys::*;
+
+/// Layers there can be, each one is a bit of [`Layers::active`].
+pub const MAX_LAYERS: usize = 8;
+/// Momentary layer keys held at the same time, further ones do nothing.
+const MAX_HELD: usize = 4;
+
+/// Switched on by the Fn key of [`LAYERS`].
+pub const FN_LAYER: u8 = 1;
+/// A number pad on the right hand letters, toggled with Fn+N.
+pub const KEYPAD_LAYER: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Action {
+    /// Whatever the layers below or the default mapping do.
+    Transparent,
+    /// Nothing, the layers below are hidden.
+    None,
+    /// The CBM key with the usage in [`KEYMAP`], with the host's modifiers.
+    Key(u8),
+    Chord(Chord),
+    /// The layer is active while the key is held.
+    Momentary(u8),
+    /// Every press switches the layer on or off.
+    Toggle(u8),
+}
+
+/// An [`Action`] for every host key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layer([Action; 256]);
+
+impl Layer {
+    /// `(key, action)`, the other keys are [`Action::Transparent`].
+    pub const fn new(actions: &[(u8, Action)]) -> Self {
+        let mut layer = [Action::Transparent; 256];
+        let mut i = 0;
+        while i < actions.len() {
+            let (key, action) = actions[i];
+            layer[key as usize] = action;
+            i += 1;
+        }
+        Self(layer)
+    }
+
+    pub fn action(&self, key: u8) -> Action {
+        self.0[key as usize]
+    }
+}
+
+/// Panics, at compile time when used in a constant, if there are too many layers or an action
+/// switches a layer that doesn't exist.
+pub const fn check(layers: &[Layer]) {
+    assert!(!layers.is_empty() && layers.len() <= MAX_LAYERS);
+    let mut i = 0;
+    while i < layers.len() {
+        let mut key = 0;
+        while key < 256 {
+            if let Action::Momentary(layer) | Action::Toggle(layer) = layers[i].0[key] {
+                assert!((layer as usize) < layers.len(), "no such layer");
+            }
+            key += 1;
+        }
+        i += 1;
+    }
+}
+
+/// The default layers. Left GUI is Fn, which reaches the CBM keys a compact keyboard lacks.
+pub static LAYERS: [Layer; 3] = [
+    Layer::new(&[(KEY_LEFTMETA, Action::Momentary(FN_LAYER))]),
+    Layer::new(&[
+        // F1 ... F10, RVS, GRAPH on the number row
+        (KEY_1, Action::Key(KEY_F1)),
+        (KEY_2, Action::Key(KEY_F2)),
+        (KEY_3, Action::Key(KEY_F3)),
+        (KEY_4, Action::Key(KEY_F4)),
+        (KEY_5, Action::Key(KEY_F5)),
+        (KEY_6, Action::Key(KEY_F6)),
+        (KEY_7, Action::Key(KEY_F7)),
+        (KEY_8, Action::Key(KEY_F8)),
+        (KEY_9, Action::Key(KEY_F9)),
+        (KEY_0, Action::Key(KEY_F10)),
+        (KEY_MINUS, Action::Key(KEY_F11)),
+        (KEY_EQUAL, Action::Key(KEY_F12)),
+        // the cursor keys on IJKL
+        (KEY_I, Action::Key(KEY_UP)),
+        (KEY_J, Action::Key(KEY_LEFT)),
+        (KEY_K, Action::Key(KEY_DOWN)),
+        (KEY_L, Action::Key(KEY_RIGHT)),
+        (KEY_H, Action::Key(KEY_HOME)),
+        (KEY_P, Action::Key(KEY_PAUSE)),
+        // INST
+        (
+            KEY_BACKSPACE,
+            Action::Chord(Chord {
+                key: KEY_BACKSPACE,
+                modifiers: MOD_LEFTSHIFT,
+            }),
+        ),
+        (KEY_N, Action::Toggle(KEYPAD_LAYER)),
+    ]),
+    Layer::new(&[
+        (KEY_7, Action::Key(KEY_KP7)),
+        (KEY_8, Action::Key(KEY_KP8)),
+        (KEY_9, Action::Key(KEY_KP9)),
+        (KEY_0, Action::Key(KEY_KPSLASH)),
+        (KEY_U, Action::Key(KEY_KP4)),
+        (KEY_I, Action::Key(KEY_KP5)),
+        (KEY_O, Action::Key(KEY_KP6)),
+        (KEY_P, Action::Key(KEY_KPASTERISK)),
+        (KEY_J, Action::Key(KEY_KP1)),
+        (KEY_K, Action::Key(KEY_KP2)),
+        (KEY_L, Action::Key(KEY_KP3)),
+        (KEY_SEMICOLON, Action::Key(KEY_KPMINUS)),
+        (KEY_M, Action::Key(KEY_KP0)),
+        (KEY_DOT, Action::Key(KEY_KPDOT)),
+        (KEY_SLASH, Action::Key(KEY_KPPLUS)),
+        (KEY_ENTER, Action::Key(KEY_KPENTER)),
+    ]),
+];
+
+const _: () = check(&LAYERS);
+
+/// The layers and which of them are active.
+#[derive(Clone, Debug)]
+pub struct Layers {
+    layers: &'static [Layer],
+    toggled: u8,
+    /// `(key, layer)` of the momentary layer keys held.
+    held: [Option<(u8, u8)>; MAX_HELD],
+}
+
+impl Layers {
+    /// See [`check`] for what `layers` must be like.
+    pub const fn new(layers: &'static [Layer]) -> Self {
+        check(layers);
+        Self {
+            layers,
+            toggled: 0,
+            held: [None; MAX_HELD],
+        }
+    }
+
+    /// One bit per active layer.
+    pub fn active(&self) -> u8 {
+        self.held
+            .iter()
+            .flatten()
+            .fold(1 | self.toggled, |active, &(_, layer)| active | 1 << layer)
+    }
+
+    /// The topmost active layer.
+    pub fn top(&self) -> u8 {
+        7 - self.active().leading_zeros() as u8
+    }
+
+    /// What `key` does with the active layers.
+    pub fn action(&self, key: u8) -> Action {
+        let active = self.active();
+        self.layers
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|&(layer, _)| active & 1 << layer != 0)
+            .map(|(_, layer)| layer.action(key))
+            .find(|&action| action != Action::Transparent)
+            .unwrap_or(Action::Transparent)
+    }
+
+    /// The action of a pressed key, after switching layers if that's what it does.
+    /// [`Action::None`] for a momentary layer key beyond [`MAX_HELD`], its layer couldn't be
+    /// switched off again.
+    pub fn press(&mut self, key: u8) -> Action {
+        let action = self.action(key);
+        match action {
+            Action::Momentary(layer) => {
+                let Some(slot) = self.held.iter_mut().find(|slot| slot.is_none()) else {
+                    return Action::None;
+                };
+                *slot = Some((key, layer));
+            }
+            Action::Toggle(layer) => self.toggled ^= 1 << layer,
+            _ => {}
+        }
+        action
+    }
+
+    /// Switches off the layer of a momentary layer key. Returns whether `key` was one.
+    pub fn release(&mut self, key: u8) -> bool {
+        let slot = self
+            .held
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|(held, _)| held == key));
+        slot.map(|slot| slot.take()).is_some()
+    }
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self::new(&LAYERS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn momentary_and_toggled_layers() {
+        let mut layers = Layers::default();
+        assert_eq!(layers.action(KEY_1), Action::Transparent);
+        assert_eq!(layers.top(), 0);
+
+        assert_eq!(layers.press(KEY_LEFTMETA), Action::Momentary(FN_LAYER));
+        assert_eq!(layers.action(KEY_1), Action::Key(KEY_F1));
+        assert_eq!(layers.press(KEY_N), Action::Toggle(KEYPAD_LAYER));
+        assert_eq!(layers.top(), KEYPAD_LAYER);
+        // the keypad is on top, Fn below it
+        assert_eq!(layers.action(KEY_I), Action::Key(KEY_KP5));
+        assert_eq!(layers.action(KEY_H), Action::Key(KEY_HOME));
+
+        assert!(layers.release(KEY_LEFTMETA));
+        assert!(!layers.release(KEY_N));
+        assert_eq!(layers.active(), 1 | 1 << KEYPAD_LAYER);
+        assert_eq!(layers.action(KEY_H), Action::Transparent);
+    }
+
+    #[test]
+    fn momentary_keys_beyond_the_held_ones_do_nothing() {
+        let mut layers = Layers::default();
+        // e.g. the Fn keys of several keyboards
+        for _ in 0..MAX_HELD {
+            assert_eq!(layers.press(KEY_LEFTMETA), Action::Momentary(FN_LAYER));
+        }
+        assert_eq!(layers.press(KEY_LEFTMETA), Action::None);
+
+        for _ in 0..MAX_HELD {
+            assert!(layers.release(KEY_LEFTMETA));
+        }
+        assert!(!layers.release(KEY_LEFTMETA));
+        assert_eq!(layers.active(), 1);
+    }
+
+    #[test]
+    fn layers_press_cbm_keys() {
+        for (layer, key) in LAYERS
+            .iter()
+            .flat_map(|layer| (0..=255).map(move |key| (layer, key)))
+        {
+            let cbm = match layer.action(key) {
+                Action::Key(cbm) => cbm,
+                Action::Chord(chord) => chord.key,
+                _ => continue,
+            };
+            assert_ne!(INVERSE_KEYMAP[cbm as usize].1, 0, "{key:#04x} → {cbm:#04x}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "no such layer")]
+    fn checks_layer_numbers() {
+        static BROKEN: [Layer; 1] = [Layer::new(&[(KEY_A, Action::Toggle(1))])];
+        Layers::new(&BROKEN);
+    }
+}
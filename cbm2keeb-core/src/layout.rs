@@ -0,0 +1,353 @@
+//! The layout of a host keyboard, which decides where its letters are and which characters its
+//! keys type. Many keyboards report their layout in the `bCountryCode` of their HID descriptor, a
+//! profile can override it when they don't or get it wrong.
+
+use crate::{keys::*, profile::Keymap};
+
+/// Used when neither the keyboard nor its profile says otherwise.
+pub const DEFAULT_LAYOUT: HostLayout = HostLayout::Us;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HostLayout {
+    Us,
+    UsInternational,
+    German,
+    Uk,
+    French,
+    Swedish,
+}
+
+/// Every layout.
+pub const LAYOUTS: [HostLayout; 6] = [
+    HostLayout::Us,
+    HostLayout::UsInternational,
+    HostLayout::German,
+    HostLayout::Uk,
+    HostLayout::French,
+    HostLayout::Swedish,
+];
+
+/// Country codes from the HID specification, section 6.2.1.
+const COUNTRY_FRENCH: u8 = 8;
+const COUNTRY_GERMAN: u8 = 9;
+const COUNTRY_INTERNATIONAL: u8 = 13;
+const COUNTRY_SWEDISH: u8 = 26;
+const COUNTRY_SWISS_GERMAN: u8 = 28;
+const COUNTRY_UK: u8 = 32;
+const COUNTRY_US: u8 = 33;
+
+static GERMAN: Keymap = Keymap::remap(&[(KEY_Y, KEY_Z), (KEY_Z, KEY_Y)]);
+static FRENCH: Keymap = Keymap::remap(&[
+    (KEY_Q, KEY_A),
+    (KEY_A, KEY_Q),
+    (KEY_W, KEY_Z),
+    (KEY_Z, KEY_W),
+    // M is right of L, the keys after it move along
+    (KEY_SEMICOLON, KEY_M),
+    (KEY_M, KEY_COMMA),
+    (KEY_COMMA, KEY_SEMICOLON),
+]);
+
+/// The combining accents dead keys put on the next character.
+pub const DEAD_GRAVE: char = '\u{300}';
+pub const DEAD_ACUTE: char = '\u{301}';
+pub const DEAD_CIRCUMFLEX: char = '\u{302}';
+pub const DEAD_TILDE: char = '\u{303}';
+pub const DEAD_DIAERESIS: char = '\u{308}';
+
+/// Which characters of a key are typed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Level {
+    Normal,
+    Shifted,
+    /// Right Alt on layouts that have AltGr, with or without shift.
+    AltGr,
+}
+
+/// The characters of a key, `(usage, [normal, shifted, AltGr])`. `'\0'` where the key types
+/// nothing, the `DEAD_*` accents for dead keys. Letters only have an entry if they type something
+/// with AltGr, the others follow [`HostLayout::keymap`].
+type KeyChars = (u8, [char; 3]);
+
+/// For every layout.
+static COMMON_CHARS: [KeyChars; 1] = [(KEY_SPACE, [' ', ' ', '\0'])];
+
+#[rustfmt::skip]
+static US_CHARS: [KeyChars; 21] = [
+    (KEY_1, ['1', '!', '\0']), (KEY_2, ['2', '@', '\0']), (KEY_3, ['3', '#', '\0']),
+    (KEY_4, ['4', '$', '\0']), (KEY_5, ['5', '%', '\0']), (KEY_6, ['6', '^', '\0']),
+    (KEY_7, ['7', '&', '\0']), (KEY_8, ['8', '*', '\0']), (KEY_9, ['9', '(', '\0']),
+    (KEY_0, ['0', ')', '\0']),
+    (KEY_MINUS, ['-', '_', '\0']), (KEY_EQUAL, ['=', '+', '\0']),
+    (KEY_LEFTBRACE, ['[', '{', '\0']), (KEY_RIGHTBRACE, [']', '}', '\0']),
+    (KEY_BACKSLASH, ['\\', '|', '\0']), (KEY_SEMICOLON, [';', ':', '\0']),
+    (KEY_APOSTROPHE, ['\'', '"', '\0']), (KEY_GRAVE, ['`', '~', '\0']),
+    (KEY_COMMA, [',', '<', '\0']), (KEY_DOT, ['.', '>', '\0']), (KEY_SLASH, ['/', '?', '\0']),
+];
+
+/// The Windows variant: `'`, `"`, `` ` ``, `~` and `^` are dead keys.
+#[rustfmt::skip]
+static US_INTERNATIONAL_CHARS: [KeyChars; 37] = [
+    (KEY_1, ['1', '!', '¡']), (KEY_2, ['2', '@', '²']), (KEY_3, ['3', '#', '³']),
+    (KEY_4, ['4', '$', '¤']), (KEY_5, ['5', '%', '€']), (KEY_6, ['6', DEAD_CIRCUMFLEX, '¼']),
+    (KEY_7, ['7', '&', '½']), (KEY_8, ['8', '*', '¾']), (KEY_9, ['9', '(', '‘']),
+    (KEY_0, ['0', ')', '’']),
+    (KEY_MINUS, ['-', '_', '¥']), (KEY_EQUAL, ['=', '+', '×']),
+    (KEY_LEFTBRACE, ['[', '{', '«']), (KEY_RIGHTBRACE, [']', '}', '»']),
+    (KEY_BACKSLASH, ['\\', '|', '¬']), (KEY_SEMICOLON, [';', ':', '¶']),
+    (KEY_APOSTROPHE, [DEAD_ACUTE, DEAD_DIAERESIS, '´']), (KEY_GRAVE, [DEAD_GRAVE, DEAD_TILDE, '\0']),
+    (KEY_COMMA, [',', '<', 'ç']), (KEY_DOT, ['.', '>', '\0']), (KEY_SLASH, ['/', '?', '¿']),
+    (KEY_Q, ['q', 'Q', 'ä']), (KEY_W, ['w', 'W', 'å']), (KEY_E, ['e', 'E', 'é']),
+    (KEY_R, ['r', 'R', '®']), (KEY_T, ['t', 'T', 'þ']), (KEY_Y, ['y', 'Y', 'ü']),
+    (KEY_U, ['u', 'U', 'ú']), (KEY_I, ['i', 'I', 'í']), (KEY_O, ['o', 'O', 'ó']),
+    (KEY_P, ['p', 'P', 'ö']), (KEY_A, ['a', 'A', 'á']), (KEY_S, ['s', 'S', 'ß']),
+    (KEY_L, ['l', 'L', 'ø']), (KEY_Z, ['z', 'Z', 'æ']), (KEY_N, ['n', 'N', 'ñ']),
+    (KEY_M, ['m', 'M', 'µ']),
+];
+
+/// QWERTZ, `^`, `´` and `` ` `` are dead keys.
+#[rustfmt::skip]
+static GERMAN_CHARS: [KeyChars; 25] = [
+    (KEY_GRAVE, [DEAD_CIRCUMFLEX, '°', '\0']),
+    (KEY_1, ['1', '!', '\0']), (KEY_2, ['2', '"', '²']), (KEY_3, ['3', '§', '³']),
+    (KEY_4, ['4', '$', '\0']), (KEY_5, ['5', '%', '\0']), (KEY_6, ['6', '&', '\0']),
+    (KEY_7, ['7', '/', '{']), (KEY_8, ['8', '(', '[']), (KEY_9, ['9', ')', ']']),
+    (KEY_0, ['0', '=', '}']),
+    (KEY_MINUS, ['ß', '?', '\\']), (KEY_EQUAL, [DEAD_ACUTE, DEAD_GRAVE, '\0']),
+    (KEY_LEFTBRACE, ['ü', 'Ü', '\0']), (KEY_RIGHTBRACE, ['+', '*', '~']),
+    (KEY_SEMICOLON, ['ö', 'Ö', '\0']), (KEY_APOSTROPHE, ['ä', 'Ä', '\0']),
+    (KEY_HASHTILDE, ['#', '\'', '\0']), (KEY_102ND, ['<', '>', '|']),
+    (KEY_COMMA, [',', ';', '\0']), (KEY_DOT, ['.', ':', '\0']), (KEY_SLASH, ['-', '_', '\0']),
+    (KEY_Q, ['q', 'Q', '@']), (KEY_E, ['e', 'E', '€']), (KEY_M, ['m', 'M', 'µ']),
+];
+
+#[rustfmt::skip]
+static UK_CHARS: [KeyChars; 24] = [
+    (KEY_GRAVE, ['`', '¬', '¦']),
+    (KEY_1, ['1', '!', '\0']), (KEY_2, ['2', '"', '\0']), (KEY_3, ['3', '£', '\0']),
+    (KEY_4, ['4', '$', '€']), (KEY_5, ['5', '%', '\0']), (KEY_6, ['6', '^', '\0']),
+    (KEY_7, ['7', '&', '\0']), (KEY_8, ['8', '*', '\0']), (KEY_9, ['9', '(', '\0']),
+    (KEY_0, ['0', ')', '\0']),
+    (KEY_MINUS, ['-', '_', '\0']), (KEY_EQUAL, ['=', '+', '\0']),
+    (KEY_LEFTBRACE, ['[', '{', '\0']), (KEY_RIGHTBRACE, [']', '}', '\0']),
+    (KEY_SEMICOLON, [';', ':', '\0']), (KEY_APOSTROPHE, ['\'', '@', '\0']),
+    (KEY_HASHTILDE, ['#', '~', '\0']), (KEY_102ND, ['\\', '|', '\0']),
+    (KEY_COMMA, [',', '<', '\0']), (KEY_DOT, ['.', '>', '\0']), (KEY_SLASH, ['/', '?', '\0']),
+    (KEY_A, ['a', 'A', 'á']), (KEY_E, ['e', 'E', 'é']),
+];
+
+/// AZERTY, digits need shift. `^` and `¨` are dead keys, and `~` and `` ` `` with AltGr.
+#[rustfmt::skip]
+static FRENCH_CHARS: [KeyChars; 23] = [
+    (KEY_GRAVE, ['²', '\0', '\0']),
+    (KEY_1, ['&', '1', '\0']), (KEY_2, ['é', '2', DEAD_TILDE]), (KEY_3, ['"', '3', '#']),
+    (KEY_4, ['\'', '4', '{']), (KEY_5, ['(', '5', '[']), (KEY_6, ['-', '6', '|']),
+    (KEY_7, ['è', '7', DEAD_GRAVE]), (KEY_8, ['_', '8', '\\']), (KEY_9, ['ç', '9', '^']),
+    (KEY_0, ['à', '0', '@']),
+    (KEY_MINUS, [')', '°', ']']), (KEY_EQUAL, ['=', '+', '}']),
+    (KEY_LEFTBRACE, [DEAD_CIRCUMFLEX, DEAD_DIAERESIS, '\0']), (KEY_RIGHTBRACE, ['$', '£', '¤']),
+    (KEY_APOSTROPHE, ['ù', '%', '\0']), (KEY_HASHTILDE, ['*', 'µ', '\0']),
+    (KEY_102ND, ['<', '>', '\0']),
+    // the keys after M
+    (KEY_M, [',', '?', '\0']), (KEY_COMMA, [';', '.', '\0']), (KEY_DOT, [':', '/', '\0']),
+    (KEY_SLASH, ['!', '§', '\0']),
+    (KEY_E, ['e', 'E', '€']),
+];
+
+/// `´`, `` ` ``, `¨`, `^` and `~` are dead keys.
+#[rustfmt::skip]
+static SWEDISH_CHARS: [KeyChars; 24] = [
+    (KEY_GRAVE, ['§', '½', '\0']),
+    (KEY_1, ['1', '!', '\0']), (KEY_2, ['2', '"', '@']), (KEY_3, ['3', '#', '£']),
+    (KEY_4, ['4', '¤', '$']), (KEY_5, ['5', '%', '€']), (KEY_6, ['6', '&', '\0']),
+    (KEY_7, ['7', '/', '{']), (KEY_8, ['8', '(', '[']), (KEY_9, ['9', ')', ']']),
+    (KEY_0, ['0', '=', '}']),
+    (KEY_MINUS, ['+', '?', '\\']), (KEY_EQUAL, [DEAD_ACUTE, DEAD_GRAVE, '\0']),
+    (KEY_LEFTBRACE, ['å', 'Å', '\0']),
+    (KEY_RIGHTBRACE, [DEAD_DIAERESIS, DEAD_CIRCUMFLEX, DEAD_TILDE]),
+    (KEY_SEMICOLON, ['ö', 'Ö', '\0']), (KEY_APOSTROPHE, ['ä', 'Ä', '\0']),
+    (KEY_HASHTILDE, ['\'', '*', '\0']), (KEY_102ND, ['<', '>', '|']),
+    (KEY_COMMA, [',', ';', '\0']), (KEY_DOT, ['.', ':', '\0']), (KEY_SLASH, ['-', '_', '\0']),
+    (KEY_E, ['e', 'E', '€']), (KEY_M, ['m', 'M', 'µ']),
+];
+
+/// What the dead keys make of the characters typed after them, `(accent, characters, accented,
+/// the accent alone)`. The accent alone is typed with space.
+#[rustfmt::skip]
+static ACCENTS: [(char, &str, &str, char); 5] = [
+    (DEAD_GRAVE, "aeiouAEIOU", "àèìòùÀÈÌÒÙ", '`'),
+    (DEAD_ACUTE, "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ", '´'),
+    (DEAD_CIRCUMFLEX, "aeiouAEIOU", "âêîôûÂÊÎÔÛ", '^'),
+    (DEAD_TILDE, "anoANO", "ãñõÃÑÕ", '~'),
+    (DEAD_DIAERESIS, "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ", '¨'),
+];
+
+/// Whether `char` is one of the `DEAD_*` accents.
+pub fn is_dead(char: char) -> bool {
+    ACCENTS.iter().any(|&(accent, ..)| accent == char)
+}
+
+/// `char` with the accent of a dead key. `None` if there's no such character, like most
+/// operating systems the accent is then dropped.
+pub fn compose(accent: char, char: char) -> Option<char> {
+    let &(_, chars, accented, alone) = ACCENTS.iter().find(|&&(dead, ..)| dead == accent)?;
+    if char == ' ' {
+        return Some(alone);
+    }
+    let index = chars.chars().position(|plain| plain == char)?;
+    accented.chars().nth(index)
+}
+
+impl HostLayout {
+    /// `None` for 0 (not given) and layouts without a translation.
+    pub fn from_country_code(code: u8) -> Option<Self> {
+        match code {
+            COUNTRY_FRENCH => Some(HostLayout::French),
+            COUNTRY_GERMAN | COUNTRY_SWISS_GERMAN => Some(HostLayout::German),
+            COUNTRY_INTERNATIONAL => Some(HostLayout::UsInternational),
+            COUNTRY_SWEDISH => Some(HostLayout::Swedish),
+            COUNTRY_UK => Some(HostLayout::Uk),
+            COUNTRY_US => Some(HostLayout::Us),
+            _ => None,
+        }
+    }
+
+    /// The layout of a keyboard: the profile's if it has one, otherwise the one of the country
+    /// code, otherwise [`DEFAULT_LAYOUT`].
+    pub fn select(profile: Option<HostLayout>, country_code: u8) -> Self {
+        profile
+            .or_else(|| Self::from_country_code(country_code))
+            .unwrap_or(DEFAULT_LAYOUT)
+    }
+
+    /// Whether Right Alt is AltGr. Without it, it stays the CBM's π key.
+    pub fn has_altgr(self) -> bool {
+        self != HostLayout::Us
+    }
+
+    /// The character `usage` types, `None` for keys that don't type one. Dead keys give their
+    /// accent, see [`is_dead`] and [`compose`].
+    pub fn char(self, usage: u8, level: Level) -> Option<char> {
+        let table: &[KeyChars] = match self {
+            HostLayout::Us => &US_CHARS,
+            HostLayout::UsInternational => &US_INTERNATIONAL_CHARS,
+            HostLayout::German => &GERMAN_CHARS,
+            HostLayout::Uk => &UK_CHARS,
+            HostLayout::French => &FRENCH_CHARS,
+            HostLayout::Swedish => &SWEDISH_CHARS,
+        };
+        let chars = table
+            .iter()
+            .chain(&COMMON_CHARS)
+            .find(|&&(key, _)| key == usage)
+            .map(|&(_, chars)| chars)
+            .or_else(|| {
+                let letter = match self.keymap().key(usage) {
+                    letter @ KEY_A..=KEY_Z => (b'a' + letter - KEY_A) as char,
+                    _ => return None,
+                };
+                Some([letter, letter.to_ascii_uppercase(), '\0'])
+            })?;
+        let char = chars[level as usize];
+        (char != '\0').then_some(char)
+    }
+
+    /// All keys typing a character, in usage order.
+    pub fn keys(self) -> impl Iterator<Item = u8> {
+        (0..=u8::MAX).filter(move |&usage| {
+            [Level::Normal, Level::Shifted, Level::AltGr]
+                .into_iter()
+                .any(|level| self.char(usage, level).is_some())
+        })
+    }
+
+    /// Moves the letters to the keys labelled with them.
+    pub fn keymap(self) -> &'static Keymap {
+        match self {
+            HostLayout::Us | HostLayout::UsInternational | HostLayout::Uk | HostLayout::Swedish => {
+                &Keymap::IDENTITY
+            }
+            HostLayout::German => &GERMAN,
+            HostLayout::French => &FRENCH,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_overrides_country_code() {
+        assert_eq!(HostLayout::select(None, 9), HostLayout::German);
+        assert_eq!(HostLayout::select(None, 0), DEFAULT_LAYOUT);
+        // Arabic has no translation
+        assert_eq!(HostLayout::select(None, 1), DEFAULT_LAYOUT);
+        assert_eq!(HostLayout::select(Some(HostLayout::Uk), 33), HostLayout::Uk);
+    }
+
+    #[test]
+    fn characters() {
+        let us = HostLayout::Us;
+        assert_eq!(us.char(KEY_2, Level::Shifted), Some('@'));
+        assert_eq!(us.char(KEY_APOSTROPHE, Level::Normal), Some('\''));
+        assert_eq!(us.char(KEY_Q, Level::Shifted), Some('Q'));
+        assert_eq!(us.char(KEY_Q, Level::AltGr), None);
+        assert_eq!(us.char(KEY_ENTER, Level::Normal), None);
+
+        let german = HostLayout::German;
+        assert_eq!(german.char(KEY_Y, Level::Normal), Some('z'));
+        assert_eq!(german.char(KEY_Q, Level::AltGr), Some('@'));
+        assert_eq!(german.char(KEY_2, Level::Shifted), Some('"'));
+        let french = HostLayout::French;
+        assert_eq!(french.char(KEY_SEMICOLON, Level::Shifted), Some('M'));
+        assert_eq!(french.char(KEY_1, Level::Normal), Some('&'));
+        assert_eq!(
+            HostLayout::Swedish.char(KEY_LEFTBRACE, Level::Normal),
+            Some('å')
+        );
+        assert_eq!(HostLayout::Uk.char(KEY_3, Level::Shifted), Some('£'));
+    }
+
+    #[test]
+    fn dead_keys() {
+        let accent = HostLayout::UsInternational.char(KEY_APOSTROPHE, Level::Normal);
+        assert_eq!(accent, Some(DEAD_ACUTE));
+        assert!(is_dead(DEAD_ACUTE));
+        assert!(!is_dead('\''));
+        assert_eq!(compose(DEAD_ACUTE, 'e'), Some('é'));
+        assert_eq!(compose(DEAD_DIAERESIS, 'U'), Some('Ü'));
+        assert_eq!(compose(DEAD_CIRCUMFLEX, ' '), Some('^'));
+        assert_eq!(compose(DEAD_TILDE, 'x'), None);
+    }
+
+    #[test]
+    fn every_layout_types_letters_and_digits() {
+        for layout in LAYOUTS {
+            let chars: Vec<_> = layout
+                .keys()
+                .flat_map(|key| {
+                    [
+                        layout.char(key, Level::Normal),
+                        layout.char(key, Level::Shifted),
+                    ]
+                })
+                .flatten()
+                .collect();
+            for char in ('a'..='z').chain('A'..='Z').chain('0'..='9') {
+                assert!(chars.contains(&char), "{layout:?} {char}");
+            }
+        }
+    }
+
+    #[test]
+    fn letters_follow_the_labels() {
+        let french = HostLayout::French.keymap();
+        assert_eq!(french.key(KEY_Q), KEY_A);
+        assert_eq!(french.key(KEY_SEMICOLON), KEY_M);
+        assert_eq!(HostLayout::German.keymap().key(KEY_Y), KEY_Z);
+        assert_eq!(HostLayout::Us.keymap().key(KEY_Y), KEY_Y);
+    }
+}
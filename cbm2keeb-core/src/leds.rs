@@ -0,0 +1,153 @@
+//! The LEDs of the host keyboards show the adapter's state. Each LED is bound to a piece of that
+//! state through [`LedBindings`], the result is the LED byte sent in the keyboards' output reports.
+
+/// LED bits, in the order of the LED usages 1 ... 5 like in boot protocol output reports.
+pub const LED_NUM_LOCK: u8 = 1 << 0;
+pub const LED_CAPS_LOCK: u8 = 1 << 1;
+pub const LED_SCROLL_LOCK: u8 = 1 << 2;
+pub const LED_COMPOSE: u8 = 1 << 3;
+pub const LED_KANA: u8 = 1 << 4;
+
+/// What an LED shows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LedSource {
+    Off,
+    On,
+    /// The shift lock is latched, see [`ShiftLock`](crate::shift_lock::ShiftLock).
+    ShiftLock,
+    /// The given layer is active.
+    Layer(u8),
+    /// The given profile is active.
+    Profile(u8),
+    /// The USB side has errors it hasn't recovered from yet.
+    Error,
+    /// The CBM isn't scanning the keyboard, e.g. it's off or hangs.
+    NotScanning,
+}
+
+/// The adapter state LEDs can be bound to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AdapterState {
+    pub shift_lock: bool,
+    pub layer: u8,
+    pub profile: u8,
+    pub error: bool,
+    pub scanning: bool,
+}
+
+impl LedSource {
+    pub fn is_lit(self, state: &AdapterState) -> bool {
+        match self {
+            LedSource::Off => false,
+            LedSource::On => true,
+            LedSource::ShiftLock => state.shift_lock,
+            LedSource::Layer(layer) => state.layer == layer,
+            LedSource::Profile(profile) => state.profile == profile,
+            LedSource::Error => state.error,
+            LedSource::NotScanning => !state.scanning,
+        }
+    }
+}
+
+/// One source per LED, `(led, source)`. An LED bound more than once is lit if any source is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LedBindings(pub [(u8, LedSource); 3]);
+
+impl LedBindings {
+    /// Caps Lock shows the shift lock, Num Lock USB errors, Scroll Lock a CBM that isn't scanning.
+    pub const DEFAULT: Self = Self([
+        (LED_NUM_LOCK, LedSource::Error),
+        (LED_CAPS_LOCK, LedSource::ShiftLock),
+        (LED_SCROLL_LOCK, LedSource::NotScanning),
+    ]);
+
+    /// The LED bits for `state`.
+    pub fn leds(&self, state: &AdapterState) -> u8 {
+        self.0
+            .iter()
+            .filter(|(_, source)| source.is_lit(state))
+            .fold(0, |leds, (led, _)| leds | led)
+    }
+}
+
+impl Default for LedBindings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keys::KEY_CAPSLOCK,
+        layer::LAYERS,
+        pipeline::{fixtures::press, Pipeline},
+        profile::PROFILES,
+    };
+
+    #[test]
+    fn default_bindings() {
+        let bindings = LedBindings::default();
+        let scanning = AdapterState {
+            scanning: true,
+            ..AdapterState::default()
+        };
+        assert_eq!(bindings.leds(&scanning), 0);
+        assert_eq!(bindings.leds(&AdapterState::default()), LED_SCROLL_LOCK);
+
+        let state = AdapterState {
+            shift_lock: true,
+            error: true,
+            ..scanning
+        };
+        assert_eq!(bindings.leds(&state), LED_NUM_LOCK | LED_CAPS_LOCK);
+    }
+
+    #[test]
+    fn default_bindings_can_light_every_led() {
+        for (led, source) in LedBindings::DEFAULT.0 {
+            let can_light = match source {
+                LedSource::Off => false,
+                LedSource::ShiftLock => {
+                    let mut pipeline = Pipeline::new();
+                    pipeline.process([press(0, KEY_CAPSLOCK)]);
+                    pipeline.shift_lock.is_latched()
+                }
+                LedSource::Layer(layer) => usize::from(layer) < LAYERS.len(),
+                LedSource::Profile(profile) => usize::from(profile) < PROFILES.len(),
+                LedSource::On | LedSource::Error | LedSource::NotScanning => true,
+            };
+            assert!(can_light, "LED {led:#x} shows {source:?}");
+        }
+    }
+
+    #[test]
+    fn layers_profiles_and_shared_leds() {
+        let bindings = LedBindings([
+            (LED_NUM_LOCK, LedSource::Layer(1)),
+            (LED_SCROLL_LOCK, LedSource::Profile(2)),
+            (LED_SCROLL_LOCK, LedSource::Error),
+        ]);
+        let state = AdapterState {
+            layer: 1,
+            scanning: true,
+            ..AdapterState::default()
+        };
+        assert_eq!(bindings.leds(&state), LED_NUM_LOCK);
+
+        let state = AdapterState {
+            profile: 2,
+            ..AdapterState::default()
+        };
+        assert_eq!(bindings.leds(&state), LED_SCROLL_LOCK);
+        let state = AdapterState {
+            error: true,
+            ..AdapterState::default()
+        };
+        assert_eq!(bindings.leds(&state), LED_SCROLL_LOCK);
+    }
+}
@@ -0,0 +1,31 @@
+//! Translation logic of the cbm2keeb adapter, kept free of any hardware dependencies so it can be
+//! shared between the RP2040 firmware and host-side tools and tests.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod consumer;
+pub mod coverage;
+pub mod device;
+pub mod hid;
+pub mod hold;
+pub mod key_state;
+pub mod keys;
+pub mod layer;
+pub mod layout;
+pub mod leds;
+pub mod matrix;
+pub mod petscii;
+pub mod pipeline;
+pub mod profile;
+pub mod recovery;
+pub mod responder;
+pub mod scan;
+pub mod shift_lock;
+pub mod tap_hold;
+pub mod translate;
+pub mod watchdog;
+
+/// GPIO0..GPIO15 are the column strobe inputs (TPI2 PA0..PA7, PB0..PB7)
+pub const PINS_IN_MASK: u32 = 0b1111_1111_1111_1111;
+/// GPIO16..GPIO21 are the row outputs (TPI2 PC0..PC5)
+pub const PINS_OUT_SHIFT: u8 = 16;
@@ -0,0 +1,208 @@
+//! Conversion of pressed HID keys into the CBM-II matrix state, and the strobe → row response
+//! computed from it.
+
+use crate::keys::INVERSE_KEYMAP;
+
+/// Row bits per column, one byte per column (PA0 ... PA7, then PB0 ... PB7), packed into words so
+/// the responder only needs four loads per strobe.
+pub type ColumnBits = [u32; 4];
+
+// 4 bits to 0xFF in those byte positions
+const LOOKUP: [u32; 16] = [
+    0x00000000, 0x000000FF, 0x0000FF00, 0x0000FFFF, 0x00FF0000, 0x00FF00FF, 0x00FFFF00, 0x00FFFFFF,
+    0xFF000000, 0xFF0000FF, 0xFF00FF00, 0xFF00FFFF, 0xFFFF0000, 0xFFFF00FF, 0xFFFFFF00, 0xFFFFFFFF,
+];
+
+pub fn key_set(key: u8, col_gpio_bits: &mut [u8; 16]) {
+    unsafe {
+        let (col, row_bits) = *INVERSE_KEYMAP.get_unchecked(key as usize);
+        *col_gpio_bits.get_unchecked_mut(col as usize) |= row_bits;
+    };
+}
+
+/// Builds the matrix state for a set of pressed HID keys.
+pub fn col_bits_from_keys(keys: impl IntoIterator<Item = u8>) -> ColumnBits {
+    let mut col_gpio_bits = [0u8; 16];
+    for key in keys {
+        key_set(key, &mut col_gpio_bits);
+    }
+
+    pack_col_bits(col_gpio_bits)
+}
+
+pub fn pack_col_bits(col_gpio_bits: [u8; 16]) -> ColumnBits {
+    let mut packed = [0u32; 4];
+    for (word, bytes) in packed.iter_mut().zip(col_gpio_bits.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    packed
+}
+
+pub fn unpack_col_bits(col_bits: ColumnBits) -> [u8; 16] {
+    let mut unpacked = [0u8; 16];
+    for (bytes, word) in unpacked.chunks_exact_mut(4).zip(col_bits) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    unpacked
+}
+
+/// Computes the row outputs for the strobed columns.
+///
+/// `cols_in` has a bit set for every column currently pulled low by the CBM. The result is
+/// active-low like the row lines: a cleared bit pulls that row low.
+#[inline(always)]
+pub fn row_response(cols_in: u16, col_enabled_pins: ColumnBits) -> u8 {
+    let mask = [
+        LOOKUP[(cols_in & 0b1111) as usize],
+        LOOKUP[((cols_in >> 4) & 0b1111) as usize],
+        LOOKUP[((cols_in >> 8) & 0b1111) as usize],
+        LOOKUP[((cols_in >> 12) & 0b1111) as usize],
+    ];
+
+    let out = (col_enabled_pins[0] & mask[0])
+        | (col_enabled_pins[1] & mask[1])
+        | (col_enabled_pins[2] & mask[2])
+        | (col_enabled_pins[3] & mask[3]);
+    let [out0, out1, out2, out3] = out.to_ne_bytes();
+    !(out0 | out1 | out2 | out3)
+}
+
+/// Fills `table` with the row response for every raw column input, indexed by GPIO0 ... GPIO15 as
+/// read from the pins (active low). Each entry is what [`row_response`] returns.
+pub fn fill_response_table(table: &mut [u8; 0x10000], col_bits: ColumnBits) {
+    let col_gpio_bits = unpack_col_bits(col_bits);
+
+    // every entry adds one strobed column to an entry computed before
+    table[0xFFFF] = 0xFF;
+    for strobed in 1..=0xFFFFu32 {
+        let rest = strobed & (strobed - 1);
+        let col = strobed.trailing_zeros() as usize;
+        table[(!strobed & 0xFFFF) as usize] =
+            table[(!rest & 0xFFFF) as usize] & !col_gpio_bits[col];
+    }
+}
+
+/// Entry of a column table answering the any-key check, where all columns are strobed.
+pub const COLUMN_TABLE_ALL: usize = 16;
+/// Entry of a column table answering when no column is strobed.
+pub const COLUMN_TABLE_NONE: usize = 31;
+
+/// Fills `table` with the row response for a single strobed column (indexed by GPIO), plus
+/// [`COLUMN_TABLE_ALL`] and [`COLUMN_TABLE_NONE`]. The KERNAL never strobes any other combination.
+pub fn fill_column_table(table: &mut [u8; 32], col_bits: ColumnBits) {
+    table.fill(0xFF);
+    for (col, response) in table[..16].iter_mut().enumerate() {
+        *response = row_response(1 << col, col_bits);
+    }
+    table[COLUMN_TABLE_ALL] = row_response(0xFFFF, col_bits);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::*;
+
+    fn naive_row_response(cols_in: u16, col_gpio_bits: &[u8; 16]) -> u8 {
+        let mut rows = 0;
+        for (col, &row_bits) in col_gpio_bits.iter().enumerate() {
+            if cols_in & (1 << col) != 0 {
+                rows |= row_bits;
+            }
+        }
+        !rows
+    }
+
+    #[test]
+    fn pack_unpack_round_trip() {
+        let bytes = core::array::from_fn(|i| (i as u8).wrapping_mul(37));
+        assert_eq!(unpack_col_bits(pack_col_bits(bytes)), bytes);
+    }
+
+    #[test]
+    fn single_key_only_answers_its_column() {
+        for key in KEYMAP
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&key| key != KEY_NONE)
+        {
+            let (col, row_bits) = INVERSE_KEYMAP[key as usize];
+            let col_bits = col_bits_from_keys([key]);
+
+            for strobe in 0..16 {
+                let expected = if strobe == col { !row_bits } else { 0xFF };
+                assert_eq!(
+                    row_response(1 << strobe, col_bits),
+                    expected,
+                    "key {key:#04x}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn row_response_matches_naive_for_all_strobe_patterns() {
+        let pressed = [
+            KEY_A,
+            KEY_LEFTSHIFT,
+            KEY_F1,
+            KEY_KP9,
+            KEY_SPACE,
+            KEY_KPENTER,
+        ];
+        let col_bits = col_bits_from_keys(pressed);
+        let col_gpio_bits = unpack_col_bits(col_bits);
+
+        for cols_in in 0..=u16::MAX {
+            assert_eq!(
+                row_response(cols_in, col_bits),
+                naive_row_response(cols_in, &col_gpio_bits)
+            );
+        }
+    }
+
+    #[test]
+    fn response_table_matches_row_response() {
+        let key_sets: [&[u8]; 3] = [
+            &[],
+            &[KEY_A, KEY_LEFTSHIFT],
+            &[KEY_F1, KEY_KP9, KEY_SPACE, KEY_Q],
+        ];
+        let mut table = Box::new([0u8; 0x10000]);
+
+        for keys in key_sets {
+            let col_bits = col_bits_from_keys(keys.iter().copied());
+            fill_response_table(&mut table, col_bits);
+
+            for raw in 0..=u16::MAX {
+                assert_eq!(
+                    table[raw as usize],
+                    row_response(!raw, col_bits),
+                    "{raw:#06x}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn column_table_matches_row_response() {
+        let col_bits = col_bits_from_keys([KEY_A, KEY_LEFTSHIFT, KEY_F1, KEY_KP9]);
+        let mut table = [0u8; 32];
+        fill_column_table(&mut table, col_bits);
+
+        for (col, &response) in table[..16].iter().enumerate() {
+            assert_eq!(response, row_response(1 << col, col_bits), "column {col}");
+        }
+        assert_eq!(table[COLUMN_TABLE_ALL], row_response(0xFFFF, col_bits));
+        assert_eq!(table[COLUMN_TABLE_NONE], 0xFF);
+    }
+
+    #[test]
+    fn nothing_pressed_leaves_rows_high() {
+        assert_eq!(row_response(0xFFFF, col_bits_from_keys([])), 0xFF);
+        assert_eq!(
+            row_response(0xFFFF, col_bits_from_keys([KEY_NONE, KEY_ERR_OVF])),
+            0xFF
+        );
+    }
+}
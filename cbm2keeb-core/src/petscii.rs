@@ -0,0 +1,182 @@
+//! The KERNAL's keyboard decode tables, indexed like [`KEYMAP`](crate::keys::KEYMAP).
+//!
+//! The scan picks the table by the modifier keys held: CTRL over C= over SHIFT over none.
+//! [`NO_KEY`] marks modifier keys and unused positions. F1 ... F10 produce the codes the KERNAL
+//! expands into the programmed function key strings.
+
+use core::ops::RangeInclusive;
+
+/// Position of a key in the tables, `(row, column)`
+pub type KeyPos = (usize, usize);
+
+pub const NO_KEY: u8 = 0xFF;
+
+pub const SHIFT_KEY: KeyPos = (4, 0);
+pub const CTRL_KEY: KeyPos = (5, 0);
+pub const COMMODORE_KEY: KeyPos = (4, 11);
+/// The keypad's `00` key puts two `0`s into the buffer
+pub const DOUBLE_ZERO_KEY: KeyPos = (5, 14);
+
+#[rustfmt::skip]
+pub static NORMAL: [[u8; 16]; 6] = [
+    //  F1    F2    F3    F4    F5    F6    F7    F8    F9   F10  CRSR↓ CRSR↑  HOME   RVS GRAPH  STOP
+    [ 0xE0, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0x11, 0x91, 0x13, 0x12, 0x0E, 0x03],
+    // ESC     1     2     3     4     5     7     8     9     0     = CRSR←     ?    CE     *     /
+    [ 0x1B, 0x31, 0x32, 0x33, 0x34, 0x35, 0x37, 0x38, 0x39, 0x30, 0x3D, 0x9D, 0x3F, 0x0F, 0x2A, 0x2F],
+    // TAB     Q     W     E     R     6     U     I     O     -     @ CRSR→   KP7   KP8   KP9   KP-
+    [ 0x09, 0x51, 0x57, 0x45, 0x52, 0x36, 0x55, 0x49, 0x4F, 0x2D, 0x40, 0x1D, 0x37, 0x38, 0x39, 0x2D],
+    //           A     S     D     T     Y     J     K     L     P     ]   DEL   KP4   KP5   KP6   KP+
+    [ 0xFF, 0x41, 0x53, 0x44, 0x54, 0x59, 0x4A, 0x4B, 0x4C, 0x50, 0x5D, 0x14, 0x34, 0x35, 0x36, 0x2B],
+    // SHIFT   Z     X     F     G     H     M     ,     ;     [   RET    C=   KP1   KP2   KP3 ENTER
+    [ 0xFF, 0x5A, 0x58, 0x46, 0x47, 0x48, 0x4D, 0x2C, 0x3B, 0x5B, 0x0D, 0xFF, 0x31, 0x32, 0x33, 0x0D],
+    // CTRL    ←     C     V     B     N SPACE     .     /     '     π     ↑   KP0   KP.    00
+    [ 0xFF, 0x5F, 0x43, 0x56, 0x42, 0x4E, 0x20, 0x2E, 0x2F, 0x27, 0xDE, 0x5E, 0x30, 0x2E, 0x30, 0xFF],
+];
+
+#[rustfmt::skip]
+pub static SHIFTED: [[u8; 16]; 6] = [
+    // F11   F12   F13   F14   F15   F16   F17   F18   F19   F20  CRSR↓ CRSR↑   CLR   OFF  NORM   RUN
+    [ 0xEA, 0xEB, 0xEC, 0xED, 0xEE, 0xEF, 0xF0, 0xF1, 0xF2, 0xF3, 0x11, 0x91, 0x93, 0x92, 0x8E, 0x83],
+    // ESC     !     "     #     $     %     '     (     )     0     + CRSR←     ?    CE     *     /
+    [ 0x1B, 0x21, 0x22, 0x23, 0x24, 0x25, 0x27, 0x28, 0x29, 0x30, 0x2B, 0x9D, 0x3F, 0x0F, 0x2A, 0x2F],
+    // TAB     Q     W     E     R     &     U     I     O                CRSR→   KP7   KP8   KP9   KP-
+    [ 0x09, 0xD1, 0xD7, 0xC5, 0xD2, 0x26, 0xD5, 0xC9, 0xCF, 0xAD, 0xC0, 0x1D, 0x37, 0x38, 0x39, 0x2D],
+    //           A     S     D     T     Y     J     K     L     P         INST   KP4   KP5   KP6   KP+
+    [ 0xFF, 0xC1, 0xD3, 0xC4, 0xD4, 0xD9, 0xCA, 0xCB, 0xCC, 0xD0, 0xDD, 0x94, 0x34, 0x35, 0x36, 0x2B],
+    // SHIFT   Z     X     F     G     H     M     <     :         RET    C=   KP1   KP2   KP3 ENTER
+    [ 0xFF, 0xDA, 0xD8, 0xC6, 0xC7, 0xC8, 0xCD, 0x3C, 0x3A, 0xDB, 0x8D, 0xFF, 0x31, 0x32, 0x33, 0x0D],
+    // CTRL          C     V     B     N SPACE     >     ?     "     π     π   KP0   KP.    00
+    [ 0xFF, 0xDF, 0xC3, 0xD6, 0xC2, 0xCE, 0xA0, 0x3E, 0x3F, 0x22, 0xDE, 0xDE, 0x30, 0x2E, 0x30, 0xFF],
+];
+
+/// CTRL turns letters into control codes, `[` and `]` into ESC and CRSR→, other keys are unchanged
+pub static CTRL: [[u8; 16]; 6] = shift_range(NORMAL, 0x41..=0x5D, 0x01);
+
+/// C= selects the graphics in `0xA1 ... 0xBA` for letters, other keys are unchanged
+pub static COMMODORE: [[u8; 16]; 6] = shift_range(NORMAL, 0x41..=0x5A, 0xA1);
+
+/// The code of a character the CBM-II can type, in the lower/upper case character set the
+/// KERNAL starts with: unshifted letters are lower case.
+pub fn from_char(char: char) -> Option<u8> {
+    match char {
+        'a'..='z' => Some(char as u8 - b'a' + 0x41),
+        'A'..='Z' => Some(char as u8 - b'A' + 0xC1),
+        ' '..='@' | '[' | ']' => Some(char as u8),
+        // shown as ↑ and ←
+        '^' | '↑' => Some(0x5E),
+        '_' | '←' => Some(0x5F),
+        'π' => Some(0xDE),
+        _ => None,
+    }
+}
+
+/// Stand-ins for characters the CBM-II can't type, `(stand-in, characters)`.
+#[rustfmt::skip]
+static SIMILAR: [(char, &str); 21] = [
+    ('a', "àáâãäåæ"), ('c', "ç"), ('e', "èéêë"), ('i', "ìíîï"), ('n', "ñ"), ('o', "òóôõöø"),
+    ('s', "ß"), ('u', "ùúûü"), ('y', "ýÿ"),
+    ('A', "ÀÁÂÃÄÅÆ"), ('C', "Ç"), ('E', "ÈÉÊË"), ('I', "ÌÍÎÏ"), ('N', "Ñ"), ('O', "ÒÓÔÕÖØ"),
+    ('U', "ÙÚÛÜ"), ('Y', "Ý"),
+    ('[', "{«"), (']', "}»"), ('\'', "`´‘’"), ('"', "¨"),
+];
+
+/// A character the CBM-II can type in place of `char`, `None` if there's no sensible one.
+/// Accented letters lose their accent.
+pub fn similar(char: char) -> Option<char> {
+    SIMILAR
+        .iter()
+        .find(|(_, chars)| chars.contains(char))
+        .map(|&(similar, _)| similar)
+}
+
+/// The key typing `code`, and whether it needs shift. Unshifted keys are preferred, then the
+/// main keys over the keypad.
+pub fn find(code: u8) -> Option<(KeyPos, bool)> {
+    let position = |table: &[[u8; 16]; 6]| {
+        (0..6)
+            .flat_map(|row| (0..16).map(move |col| (row, col)))
+            .find(|&(row, col)| table[row][col] == code)
+    };
+    match code {
+        NO_KEY => None,
+        _ => position(&NORMAL)
+            .map(|pos| (pos, false))
+            .or_else(|| position(&SHIFTED).map(|pos| (pos, true))),
+    }
+}
+
+/// Moves all codes in `range` so that the range starts at `new_start`.
+const fn shift_range(
+    mut table: [[u8; 16]; 6],
+    range: RangeInclusive<u8>,
+    new_start: u8,
+) -> [[u8; 16]; 6] {
+    let (start, end) = (*range.start(), *range.end());
+
+    let mut row = 0;
+    while row < table.len() {
+        let mut col = 0;
+        while col < table[row].len() {
+            let code = table[row][col];
+            if code >= start && code <= end {
+                table[row][col] = code - start + new_start;
+            }
+            col += 1;
+        }
+        row += 1;
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KEYMAP;
+
+    #[test]
+    fn modifier_and_unused_positions_have_no_code() {
+        for table in [&NORMAL, &SHIFTED, &CTRL, &COMMODORE] {
+            for (row, col) in [SHIFT_KEY, CTRL_KEY, COMMODORE_KEY] {
+                assert_eq!(table[row][col], NO_KEY);
+            }
+        }
+    }
+
+    #[test]
+    fn characters_find_their_keys() {
+        let find_char = |char| find(from_char(char).unwrap());
+        assert_eq!(find_char('a'), Some(((3, 1), false)));
+        assert_eq!(find_char('A'), Some(((3, 1), true)));
+        assert_eq!(find_char('@'), Some(((2, 10), false)));
+        assert_eq!(find_char('"'), Some(((1, 2), true)));
+        // the main keys before the keypad
+        assert_eq!(find_char('7'), Some(((1, 6), false)));
+        assert_eq!(find_char('-'), Some(((2, 9), false)));
+        assert_eq!(from_char('{'), None);
+    }
+
+    #[test]
+    fn similar_characters() {
+        assert_eq!(similar('é'), Some('e'));
+        assert_eq!(similar('Ö'), Some('O'));
+        assert_eq!(similar('{'), Some('['));
+        assert_eq!(similar('§'), None);
+        for (similar, _) in SIMILAR {
+            assert!(from_char(similar).is_some(), "{similar}");
+        }
+    }
+
+    #[test]
+    fn every_mapped_key_has_a_code() {
+        for (row, keys) in KEYMAP.iter().enumerate() {
+            for (col, &key) in keys.iter().enumerate() {
+                let pos = (row, col);
+                if key != 0 && ![SHIFT_KEY, CTRL_KEY, COMMODORE_KEY].contains(&pos) {
+                    assert_ne!(NORMAL[row][col], NO_KEY, "{pos:?}");
+                    assert_ne!(SHIFTED[row][col], NO_KEY, "{pos:?}");
+                }
+            }
+        }
+    }
+}
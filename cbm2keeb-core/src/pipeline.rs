@@ -0,0 +1,445 @@
+//! Key events on their way from the keyboard to the matrix pass through an ordered list of
+//! [`Processor`]s. Each one turns the events it gets into any number of events for the next one,
+//! and can ask to be woken up at a later point in time to emit delayed events. The events leaving
+//! the last processor end up in the [`MatrixOutput`].
+
+use crate::{
+    device::Config,
+    hold::{self, HoldStretcher},
+    key_state::{KeyEvent, KeyState},
+    keys::{KEY_CAPSLOCK, KEY_SCROLLLOCK},
+    layer::{Layers, LAYERS},
+    matrix::{self, ColumnBits},
+    responder::MatrixCell,
+    scan::ScanTiming,
+    shift_lock::ShiftLock,
+    tap_hold::TapHold,
+    translate::{self, Mode, Translator},
+    watchdog::{self, StuckKeyWatchdog},
+};
+
+/// Timestamps, in ticks of the RP2040's 1 MHz timer like the firmware's `Mono`
+pub type Instant = fugit::TimerInstantU64<1_000_000>;
+pub type Duration = fugit::TimerDurationU64<1_000_000>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimedEvent {
+    pub time: Instant,
+    pub event: KeyEvent,
+}
+
+/// Events passed from one stage to the next. Events beyond the capacity are dropped and counted.
+pub struct Events {
+    buf: [Option<TimedEvent>; Self::CAPACITY],
+    len: usize,
+    dropped: usize,
+}
+
+impl Events {
+    /// Enough for every key of a [`KeyState`] changing at once. Keyboards only report usages
+    /// from `KEY_A` to `KEY_RIGHTMETA`, which leaves room for the modifier changes stages add.
+    pub const CAPACITY: usize = 256;
+
+    pub const fn new() -> Self {
+        Self {
+            buf: [None; Self::CAPACITY],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn emit(&mut self, event: TimedEvent) {
+        match self.buf.get_mut(self.len) {
+            Some(slot) => {
+                *slot = Some(event);
+                self.len += 1;
+            }
+            None => self.dropped += 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TimedEvent> {
+        self.buf[..self.len].iter().flatten()
+    }
+
+    fn take(&mut self) -> impl Iterator<Item = TimedEvent> + '_ {
+        let len = core::mem::take(&mut self.len);
+        self.buf[..len].iter_mut().filter_map(Option::take)
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Passes `event` on at `time`.
+pub(crate) fn emit(out: &mut Events, time: Instant, event: KeyEvent) {
+    out.emit(TimedEvent { time, event });
+}
+
+pub trait Processor {
+    /// Handles an incoming event, passing the resulting events on through `out`.
+    fn process(&mut self, event: TimedEvent, out: &mut Events);
+
+    /// The point in time at which [`Processor::timeout`] should be called next, if any.
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Called at or after the [`Processor::deadline`].
+    fn timeout(&mut self, _now: Instant, _out: &mut Events) {}
+}
+
+/// The last stage, holds the keys pressed in the matrix.
+#[derive(Clone, Debug, Default)]
+pub struct MatrixOutput {
+    keys: KeyState,
+}
+
+impl MatrixOutput {
+    pub const fn new() -> Self {
+        Self {
+            keys: KeyState::new(),
+        }
+    }
+
+    pub fn apply(&mut self, event: TimedEvent) {
+        self.keys.apply(event.event);
+    }
+
+    pub fn keys(&self) -> &KeyState {
+        &self.keys
+    }
+
+    pub fn col_bits(&self) -> ColumnBits {
+        matrix::col_bits_from_keys(self.keys.iter())
+    }
+
+    /// Hands the matrix state over to the responder.
+    pub fn commit(&self, col_enabled_pins: &MatrixCell) {
+        col_enabled_pins.store(self.col_bits());
+    }
+}
+
+/// Runs `events` through `stages`, starting at the first one. Returns the number of dropped
+/// events.
+pub fn process(
+    stages: &mut [&mut dyn Processor],
+    events: impl IntoIterator<Item = TimedEvent>,
+    output: &mut MatrixOutput,
+) -> usize {
+    let mut input = Events::new();
+    for event in events {
+        input.emit(event);
+    }
+    run_from(stages, 0, input, output)
+}
+
+/// Calls [`Processor::timeout`] on all stages whose deadline has passed, running the events they
+/// emit through the stages after them. Returns the number of dropped events.
+pub fn timeout(
+    stages: &mut [&mut dyn Processor],
+    now: Instant,
+    output: &mut MatrixOutput,
+) -> usize {
+    let mut dropped = 0;
+    for idx in 0..stages.len() {
+        if stages[idx]
+            .deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            let mut emitted = Events::new();
+            stages[idx].timeout(now, &mut emitted);
+            dropped += run_from(stages, idx + 1, emitted, output);
+        }
+    }
+    dropped
+}
+
+/// The earliest deadline of all stages.
+pub fn deadline(stages: &[&dyn Processor]) -> Option<Instant> {
+    stages.iter().filter_map(|stage| stage.deadline()).min()
+}
+
+fn run_from(
+    stages: &mut [&mut dyn Processor],
+    first: usize,
+    mut events: Events,
+    output: &mut MatrixOutput,
+) -> usize {
+    let mut dropped = events.dropped;
+    for stage in stages.iter_mut().skip(first) {
+        let mut next = Events::new();
+        for event in events.take() {
+            stage.process(event, &mut next);
+        }
+        dropped += next.dropped;
+        events = next;
+    }
+
+    for event in events.take() {
+        output.apply(event);
+    }
+    dropped
+}
+
+/// Latches the CBM's shift, see [`ShiftLock`]. Caps Lock takes the place of the CBM's SHIFT LOCK,
+/// `None` turns the latch off.
+pub const SHIFT_LOCK_KEY: Option<u8> = Some(KEY_CAPSLOCK);
+/// Switches between positional and symbolic translation, see [`Translator`].
+pub const MODE_KEY: u8 = KEY_SCROLLLOCK;
+
+/// The firmware's processing stages, in order.
+#[derive(Default)]
+pub struct Pipeline {
+    pub watchdog: StuckKeyWatchdog,
+    /// Before the shift lock, so a tap-hold key can tap the lock key.
+    pub tap_hold: TapHold,
+    /// After the watchdog, which would release the latched shift otherwise.
+    pub shift_lock: ShiftLock,
+    pub translator: Translator,
+    pub hold: HoldStretcher,
+    pub output: MatrixOutput,
+}
+
+impl Pipeline {
+    pub const fn new() -> Self {
+        Self {
+            watchdog: StuckKeyWatchdog::new(Some(watchdog::DEFAULT_LIMIT)),
+            tap_hold: TapHold::new(&[]),
+            shift_lock: ShiftLock::new(SHIFT_LOCK_KEY),
+            translator: Translator::new(
+                Mode::Positional,
+                Some(MODE_KEY),
+                Layers::new(&LAYERS),
+                translate::DEFAULT_SETTLE,
+            ),
+            hold: HoldStretcher::new(hold::DEFAULT_SCANS, hold::DEFAULT_FALLBACK),
+            output: MatrixOutput::new(),
+        }
+    }
+
+    /// Has the next events handled as the keyboard sending them wants, see
+    /// [`Devices::config`](crate::device::Devices::config).
+    pub fn configure(&mut self, config: &Config) {
+        self.translator.set_layout(config.layout);
+        self.tap_hold.set_behaviors(config.behaviors);
+    }
+
+    /// Updates the measured scan timing of the stages that wait for scans.
+    pub fn set_scan_timing(&mut self, timing: Option<ScanTiming>) {
+        self.translator.set_scan_timing(timing);
+        self.hold.set_scan_timing(timing);
+    }
+
+    /// Runs events coming from the keyboard. Returns the number of dropped events.
+    pub fn process(&mut self, events: impl IntoIterator<Item = TimedEvent>) -> usize {
+        process(
+            &mut [
+                &mut self.watchdog,
+                &mut self.tap_hold,
+                &mut self.shift_lock,
+                &mut self.translator,
+                &mut self.hold,
+            ],
+            events,
+            &mut self.output,
+        )
+    }
+
+    /// See [`timeout`]. Returns the number of dropped events.
+    pub fn timeout(&mut self, now: Instant) -> usize {
+        timeout(
+            &mut [
+                &mut self.watchdog,
+                &mut self.tap_hold,
+                &mut self.shift_lock,
+                &mut self.translator,
+                &mut self.hold,
+            ],
+            now,
+            &mut self.output,
+        )
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        deadline(&[
+            &self.watchdog,
+            &self.tap_hold,
+            &self.shift_lock,
+            &self.translator,
+            &self.hold,
+        ])
+    }
+}
+
+/// Events and a driver for the tests of the stages, times are in milliseconds.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    pub fn at(ms: u64) -> Instant {
+        Instant::from_ticks(ms * 1000)
+    }
+
+    pub fn press(ms: u64, key: u8) -> TimedEvent {
+        TimedEvent {
+            time: at(ms),
+            event: KeyEvent::Press(key),
+        }
+    }
+
+    pub fn release(ms: u64, key: u8) -> TimedEvent {
+        TimedEvent {
+            time: at(ms),
+            event: KeyEvent::Release(key),
+        }
+    }
+
+    /// Feeds `input` to `stage` and calls its timeouts in between, returns everything passed on.
+    pub fn run(stage: &mut impl Processor, input: &[TimedEvent]) -> Vec<TimedEvent> {
+        let mut passed = Vec::new();
+        let mut input = input.iter().peekable();
+        loop {
+            let mut out = Events::new();
+            let deadline = stage.deadline();
+            let event =
+                input.next_if(|event| deadline.is_none_or(|deadline| event.time <= deadline));
+
+            if let Some(event) = event {
+                stage.process(*event, &mut out);
+            } else if let Some(deadline) = deadline {
+                stage.timeout(deadline, &mut out);
+            } else {
+                return passed;
+            }
+            passed.extend(out.iter().copied());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::*;
+    use fixtures::{at, press};
+
+    /// Holds back every event for a fixed time
+    #[derive(Default)]
+    struct Delay {
+        pending: Option<TimedEvent>,
+    }
+
+    impl Processor for Delay {
+        fn process(&mut self, event: TimedEvent, _out: &mut Events) {
+            self.pending = Some(TimedEvent {
+                time: event.time + Duration::millis(10),
+                ..event
+            });
+        }
+
+        fn deadline(&self) -> Option<Instant> {
+            self.pending.map(|event| event.time)
+        }
+
+        fn timeout(&mut self, _now: Instant, out: &mut Events) {
+            out.emit(self.pending.take().unwrap());
+        }
+    }
+
+    /// Turns every key into `KEY_B`
+    struct ToB;
+
+    impl Processor for ToB {
+        fn process(&mut self, event: TimedEvent, out: &mut Events) {
+            let mapped = match event.event {
+                KeyEvent::Press(_) => KeyEvent::Press(KEY_B),
+                KeyEvent::Release(_) => KeyEvent::Release(KEY_B),
+            };
+            out.emit(TimedEvent {
+                event: mapped,
+                ..event
+            });
+        }
+    }
+
+    #[test]
+    fn stages_run_in_order() {
+        let mut output = MatrixOutput::new();
+        process(&mut [&mut ToB], [press(0, KEY_A)], &mut output);
+        assert_eq!(output.keys().iter().collect::<Vec<_>>(), [KEY_B]);
+        assert_eq!(output.col_bits(), matrix::col_bits_from_keys([KEY_B]));
+    }
+
+    #[test]
+    fn delayed_events_continue_with_the_next_stage() {
+        let mut delay = Delay::default();
+        let mut output = MatrixOutput::new();
+
+        process(&mut [&mut delay, &mut ToB], [press(5, KEY_A)], &mut output);
+        assert!(output.keys().is_empty());
+        assert_eq!(deadline(&[&delay, &ToB]), Some(at(15)));
+
+        timeout(&mut [&mut delay, &mut ToB], at(14), &mut output);
+        assert!(output.keys().is_empty());
+
+        timeout(&mut [&mut delay, &mut ToB], at(15), &mut output);
+        assert_eq!(output.keys().iter().collect::<Vec<_>>(), [KEY_B]);
+        assert_eq!(deadline(&[&delay, &ToB]), None);
+    }
+
+    #[test]
+    fn overflowing_events_are_dropped_and_counted() {
+        let mut output = MatrixOutput::new();
+        let events = (0..Events::CAPACITY + 3).map(|key| press(0, key as u8));
+        assert_eq!(process(&mut [], events, &mut output), 3);
+        assert_eq!(output.keys().iter().count(), Events::CAPACITY);
+    }
+
+    #[test]
+    fn every_key_changing_at_once_fits() {
+        let mut pipeline = Pipeline::new();
+        let mut all = KeyState::new();
+        // but the shift lock, which would keep shift latched
+        for key in (KEY_A..=KEY_RIGHTMETA).filter(|&key| Some(key) != SHIFT_LOCK_KEY) {
+            all.press(key);
+        }
+        let at_time = |time| {
+            move |event| TimedEvent {
+                time: at(time),
+                event,
+            }
+        };
+        let presses = KeyState::new().diff(&all).map(at_time(0));
+        assert_eq!(pipeline.process(presses), 0);
+
+        // like a keyboard that's lost
+        let releases = all.diff(&KeyState::new()).map(at_time(100));
+        assert_eq!(pipeline.process(releases), 0);
+        while let Some(deadline) = pipeline.deadline() {
+            assert_eq!(pipeline.timeout(deadline), 0);
+        }
+        assert!(pipeline.output.keys().is_empty());
+    }
+
+    #[test]
+    fn commit_stores_col_bits() {
+        let cell = MatrixCell::new();
+        let mut pipeline = Pipeline::new();
+        pipeline.process([press(0, KEY_SPACE)]);
+        pipeline.output.commit(&cell);
+
+        assert_eq!(cell.load().1, matrix::col_bits_from_keys([KEY_SPACE]));
+    }
+}
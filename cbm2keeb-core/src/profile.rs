@@ -0,0 +1,351 @@
+//! Per-keyboard profiles, picked by the USB vendor and product ID, optionally narrowed down by the
+//! product string. A profile's [`Keymap`] adjusts the usages a keyboard sends before they reach
+//! the [`KEYMAP`](crate::keys::KEYMAP), and it can set the keyboard's [`HostLayout`]. Keyboards
+//! without a matching profile use [`PROFILES`]`[0]`, which leaves every usage alone.
+
+use crate::{
+    device::Config,
+    key_state::KeyState,
+    keys::*,
+    layout::HostLayout,
+    leds::{LedBindings, LedSource, LED_CAPS_LOCK, LED_SCROLL_LOCK},
+    tap_hold::{self, Behavior, Flavor},
+};
+
+/// Characters of the product string that are kept.
+pub const MAX_PRODUCT: usize = 32;
+
+const DESCRIPTOR_STRING: u8 = 0x03;
+
+/// What a keyboard is, from its device descriptor, HID descriptor and product string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub vid: u16,
+    pub pid: u16,
+    /// `bCountryCode` of the HID descriptor, 0 if not given.
+    pub country_code: u8,
+    product: [u8; MAX_PRODUCT],
+    product_len: u8,
+}
+
+impl DeviceInfo {
+    pub const fn new(vid: u16, pid: u16) -> Self {
+        Self {
+            vid,
+            pid,
+            country_code: 0,
+            product: [0; MAX_PRODUCT],
+            product_len: 0,
+        }
+    }
+
+    /// From a device descriptor, `None` if it's too short.
+    pub fn from_device_descriptor(data: &[u8]) -> Option<Self> {
+        let &[vid_lo, vid_hi, pid_lo, pid_hi] = data.get(8..12)? else {
+            return None;
+        };
+        Some(Self::new(
+            u16::from_le_bytes([vid_lo, vid_hi]),
+            u16::from_le_bytes([pid_lo, pid_hi]),
+        ))
+    }
+
+    /// Takes the product string from a string descriptor. Characters outside of ASCII become `?`,
+    /// the string is cut off after [`MAX_PRODUCT`] characters.
+    pub fn set_product(&mut self, descriptor: &[u8]) {
+        self.product_len = 0;
+        let [len, DESCRIPTOR_STRING, chars @ ..] = descriptor else {
+            return;
+        };
+        let chars = &chars[..(*len as usize).saturating_sub(2).min(chars.len())];
+        for (slot, char) in self.product.iter_mut().zip(chars.chunks_exact(2)) {
+            *slot = match u16::from_le_bytes([char[0], char[1]]) {
+                char @ 0x20..=0x7E => char as u8,
+                _ => b'?',
+            };
+            self.product_len += 1;
+        }
+    }
+
+    /// `None` if the keyboard has no product string, or it wasn't read.
+    pub fn product(&self) -> Option<&str> {
+        let product = &self.product[..self.product_len as usize];
+        // only ever holds ASCII
+        (!product.is_empty()).then(|| core::str::from_utf8(product).unwrap_or_default())
+    }
+}
+
+/// Translates the usages a keyboard sends, one entry per usage. 0 drops the key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keymap([u8; 256]);
+
+impl Keymap {
+    /// Every usage stays as it is.
+    pub const IDENTITY: Self = Self::remap(&[]);
+
+    /// Replaces the usages in `remap`, `(from, to)`, and leaves the others alone.
+    pub const fn remap(remap: &[(u8, u8)]) -> Self {
+        let mut keymap = [0; 256];
+        let mut key = 0;
+        while key < keymap.len() {
+            keymap[key] = key as u8;
+            key += 1;
+        }
+
+        let mut i = 0;
+        while i < remap.len() {
+            let (from, to) = remap[i];
+            keymap[from as usize] = to;
+            i += 1;
+        }
+        Self(keymap)
+    }
+
+    pub fn key(&self, key: u8) -> u8 {
+        self.0[key as usize]
+    }
+
+    pub fn apply(&self, keys: &KeyState) -> KeyState {
+        let mut mapped = KeyState::new();
+        for key in keys.iter().map(|key| self.key(key)) {
+            if key != KEY_NONE {
+                mapped.press(key);
+            }
+        }
+        mapped
+    }
+}
+
+/// Keyboards a profile is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceMatch {
+    pub vid: u16,
+    /// `None` matches every product of the vendor.
+    pub pid: Option<u16>,
+    /// `None` matches any product string, or none.
+    pub product: Option<&'static str>,
+}
+
+impl DeviceMatch {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        self.vid == info.vid
+            && self.pid.is_none_or(|pid| pid == info.pid)
+            && self
+                .product
+                .is_none_or(|product| info.product() == Some(product))
+    }
+}
+
+#[derive(Debug)]
+pub struct Profile {
+    pub name: &'static str,
+    pub devices: &'static [DeviceMatch],
+    /// Overrides the layout of the country code, for keyboards that don't give one or give the
+    /// wrong one.
+    pub layout: Option<HostLayout>,
+    /// Physical differences to full size keyboards, the layout is taken care of by the
+    /// [`Translator`](crate::translate::Translator).
+    pub keymap: Keymap,
+    /// Tap-hold and one-shot keys, see [`TapHold`](crate::tap_hold::TapHold).
+    pub behaviors: &'static [(u8, Behavior)],
+    pub leds: LedBindings,
+}
+
+impl Profile {
+    pub fn layout(&self, info: &DeviceInfo) -> HostLayout {
+        HostLayout::select(self.layout, info.country_code)
+    }
+
+    /// How the keyboard's keys are handled, see [`Devices::added`](crate::device::Devices::added).
+    pub fn config(&self, info: &DeviceInfo) -> Config {
+        Config {
+            keymap: self.keymap,
+            layout: self.layout(info),
+            behaviors: self.behaviors,
+        }
+    }
+}
+
+/// The profiles to pick from, the first one is the default. The German and the compact keyboard
+/// are matched by placeholders, the pid.codes test VID and PIDs: replace them with the VID/PID of
+/// the actual keyboards, see `lsusb`.
+pub static PROFILES: [Profile; 3] = [
+    Profile {
+        name: "US ANSI",
+        devices: &[],
+        layout: None,
+        keymap: Keymap::IDENTITY,
+        behaviors: &[],
+        leds: LedBindings::DEFAULT,
+    },
+    Profile {
+        name: "German ISO",
+        devices: &[DeviceMatch {
+            vid: 0x1209,
+            pid: Some(0x0001),
+            product: None,
+        }],
+        // for the keyboards that don't give their country code
+        layout: Some(HostLayout::German),
+        keymap: Keymap::IDENTITY,
+        behaviors: &[],
+        leds: LedBindings::DEFAULT,
+    },
+    Profile {
+        name: "Compact 60%",
+        devices: &[DeviceMatch {
+            vid: 0x1209,
+            pid: Some(0x0002),
+            product: None,
+        }],
+        layout: None,
+        // the Menu key sits where full size keyboards have their right GUI, which is C=
+        keymap: Keymap::remap(&[(KEY_COMPOSE, KEY_RIGHTMETA)]),
+        // Caps Lock is CTRL while held, there's no left CTRL close to the letters
+        behaviors: &[(
+            KEY_CAPSLOCK,
+            Behavior::TapHold {
+                tap: KEY_CAPSLOCK,
+                hold: KEY_LEFTCTRL,
+                term: tap_hold::DEFAULT_TERM,
+                flavor: Flavor::PermissiveHold,
+            },
+        )],
+        // usually only Caps Lock has an LED, it shows errors as well
+        leds: LedBindings([
+            (LED_CAPS_LOCK, LedSource::ShiftLock),
+            (LED_CAPS_LOCK, LedSource::Error),
+            (LED_SCROLL_LOCK, LedSource::NotScanning),
+        ]),
+    },
+];
+
+/// The index of the first profile in `profiles` matching the keyboard, or 0.
+pub fn select(profiles: &[Profile], info: &DeviceInfo) -> usize {
+    profiles
+        .iter()
+        .position(|profile| profile.devices.iter().any(|device| device.matches(info)))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TEST_PROFILES: [Profile; 3] = [
+        Profile {
+            name: "default",
+            devices: &[],
+            layout: None,
+            keymap: Keymap::IDENTITY,
+            behaviors: &[],
+            leds: LedBindings::DEFAULT,
+        },
+        Profile {
+            name: "by product string",
+            devices: &[DeviceMatch {
+                vid: 0x1234,
+                pid: Some(0x0001),
+                product: Some("ISO Keyboard"),
+            }],
+            layout: None,
+            keymap: Keymap::IDENTITY,
+            behaviors: &[],
+            leds: LedBindings::DEFAULT,
+        },
+        Profile {
+            name: "by vendor",
+            devices: &[DeviceMatch {
+                vid: 0x1234,
+                pid: None,
+                product: None,
+            }],
+            layout: None,
+            keymap: Keymap::IDENTITY,
+            behaviors: &[],
+            leds: LedBindings::DEFAULT,
+        },
+    ];
+
+    /// A string descriptor for `string`.
+    fn descriptor(string: &str) -> Vec<u8> {
+        let chars = string.encode_utf16().flat_map(u16::to_le_bytes);
+        let mut descriptor = vec![0, DESCRIPTOR_STRING];
+        descriptor.extend(chars);
+        descriptor[0] = descriptor.len() as u8;
+        descriptor
+    }
+
+    #[test]
+    fn device_info_from_descriptors() {
+        let device = [
+            0x12, 0x01, 0x00, 0x02, 0, 0, 0, 0x40, 0x34, 0x12, 0x01, 0x00, 0, 1, 1, 2, 0, 1,
+        ];
+        let mut info = DeviceInfo::from_device_descriptor(&device).unwrap();
+        assert_eq!((info.vid, info.pid), (0x1234, 0x0001));
+        assert_eq!(info.product(), None);
+        assert_eq!(DeviceInfo::from_device_descriptor(&device[..10]), None);
+
+        info.set_product(&descriptor("Tastatur für 🙂"));
+        assert_eq!(info.product(), Some("Tastatur f?r ??"));
+        info.set_product(&descriptor(&"x".repeat(40)));
+        assert_eq!(info.product().unwrap().len(), MAX_PRODUCT);
+        info.set_product(&[]);
+        assert_eq!(info.product(), None);
+    }
+
+    #[test]
+    fn selects_the_first_match() {
+        let mut info = DeviceInfo::new(0x1234, 0x0001);
+        assert_eq!(select(&TEST_PROFILES, &info), 2);
+        info.set_product(&descriptor("ISO Keyboard"));
+        assert_eq!(select(&TEST_PROFILES, &info), 1);
+
+        // unknown keyboards get the default
+        assert_eq!(select(&TEST_PROFILES, &DeviceInfo::new(0x4321, 1)), 0);
+        assert_eq!(select(&PROFILES, &DeviceInfo::new(0x4321, 1)), 0);
+    }
+
+    #[test]
+    fn every_profile_has_its_keyboards() {
+        for (i, profile) in PROFILES.iter().enumerate().skip(1) {
+            assert!(!profile.devices.is_empty(), "{}", profile.name);
+            for device in profile.devices {
+                let mut info = DeviceInfo::new(device.vid, device.pid.unwrap_or(0));
+                if let Some(product) = device.product {
+                    info.set_product(&descriptor(product));
+                }
+                assert_eq!(select(&PROFILES, &info), i, "{}", profile.name);
+            }
+        }
+    }
+
+    #[test]
+    fn keymaps_translate_usages() {
+        let compact = &PROFILES[2].keymap;
+        let keys = KeyState::from_boot_report(MOD_LEFTSHIFT, [KEY_Y, KEY_A, KEY_COMPOSE]);
+        let mapped = compact.apply(&keys);
+        assert_eq!(
+            mapped.iter().collect::<Vec<_>>(),
+            [KEY_A, KEY_Y, KEY_LEFTSHIFT, KEY_RIGHTMETA]
+        );
+        assert_eq!(Keymap::IDENTITY.apply(&keys), keys);
+
+        let dropping = Keymap::remap(&[(KEY_A, KEY_NONE)]);
+        assert_eq!(
+            dropping.apply(&keys).iter().collect::<Vec<_>>(),
+            [KEY_Y, KEY_COMPOSE, KEY_LEFTSHIFT]
+        );
+    }
+
+    #[test]
+    fn layout_from_profile_or_country_code() {
+        let mut info = DeviceInfo::new(0x4321, 1);
+        assert_eq!(PROFILES[1].layout(&info), HostLayout::German);
+        assert_eq!(PROFILES[0].layout(&info), HostLayout::Us);
+
+        info.country_code = 32;
+        assert_eq!(PROFILES[1].layout(&info), HostLayout::German);
+        assert_eq!(PROFILES[0].layout(&info), HostLayout::Uk);
+    }
+}
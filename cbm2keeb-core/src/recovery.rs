@@ -0,0 +1,172 @@
+//! Decides when the USB host has to be reset after repeated bus or discovery errors. Cheap
+//! keyboards can get the host stuck in a state it doesn't recover from by itself, a reset
+//! re-enumerates everything.
+//!
+//! Resets back off: after each one, the next is only allowed after twice the previous wait, up to
+//! [`MAX_BACKOFF`]. Errors in between are still counted, the host keeps retrying by itself. Any
+//! successful input resets both the error streak and the backoff.
+
+use crate::pipeline::{Duration, Instant};
+
+/// Errors in a row before the host is reset.
+pub const DEFAULT_MAX_ERRORS: u32 = 5;
+pub const MIN_BACKOFF: Duration = Duration::millis(250);
+pub const MAX_BACKOFF: Duration = Duration::secs(8);
+
+/// Totals since startup, for logging.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ErrorCounts {
+    pub bus_errors: u32,
+    pub discovery_errors: u32,
+    /// Optional requests like SET_IDLE that a device rejected.
+    pub optional_requests_failed: u32,
+    pub host_resets: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RecoveryAction {
+    Continue,
+    /// Reset the USB controller and drop all device state.
+    ResetHost,
+}
+
+#[derive(Clone, Debug)]
+pub struct BusRecovery {
+    max_errors: u32,
+    streak: u32,
+    backoff: Duration,
+    next_reset: Option<Instant>,
+    counts: ErrorCounts,
+}
+
+impl BusRecovery {
+    pub const fn new(max_errors: u32) -> Self {
+        Self {
+            max_errors,
+            streak: 0,
+            backoff: MIN_BACKOFF,
+            next_reset: None,
+            counts: ErrorCounts {
+                bus_errors: 0,
+                discovery_errors: 0,
+                optional_requests_failed: 0,
+                host_resets: 0,
+            },
+        }
+    }
+
+    pub fn counts(&self) -> &ErrorCounts {
+        &self.counts
+    }
+
+    pub fn bus_error(&mut self, now: Instant) -> RecoveryAction {
+        self.counts.bus_errors += 1;
+        self.error(now)
+    }
+
+    pub fn discovery_error(&mut self, now: Instant) -> RecoveryAction {
+        self.counts.discovery_errors += 1;
+        self.error(now)
+    }
+
+    /// Errors happened since the last success.
+    pub fn has_errors(&self) -> bool {
+        self.streak > 0 || self.next_reset.is_some()
+    }
+
+    /// An optional request failed, the device keeps working without it.
+    pub fn optional_request_failed(&mut self) {
+        self.counts.optional_requests_failed += 1;
+    }
+
+    /// A device was added or sent input, the bus works again.
+    pub fn success(&mut self) {
+        self.streak = 0;
+        self.backoff = MIN_BACKOFF;
+        self.next_reset = None;
+    }
+
+    fn error(&mut self, now: Instant) -> RecoveryAction {
+        self.streak += 1;
+        if self.streak < self.max_errors || self.next_reset.is_some_and(|next| now < next) {
+            return RecoveryAction::Continue;
+        }
+
+        self.streak = 0;
+        self.counts.host_resets += 1;
+        self.next_reset = Some(now + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        RecoveryAction::ResetHost
+    }
+}
+
+impl Default for BusRecovery {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ERRORS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::fixtures::at;
+
+    /// Errors every `interval` ms from `start` on, returns the times of the resets.
+    fn errors(recovery: &mut BusRecovery, start: u64, interval: u64, count: u64) -> Vec<u64> {
+        (0..count)
+            .map(|i| start + i * interval)
+            .filter(|&ms| recovery.bus_error(at(ms)) == RecoveryAction::ResetHost)
+            .collect()
+    }
+
+    #[test]
+    fn resets_after_errors_in_a_row() {
+        let mut recovery = BusRecovery::new(3);
+        assert_eq!(errors(&mut recovery, 0, 10, 3), [20]);
+        assert_eq!(recovery.counts().bus_errors, 3);
+        assert_eq!(recovery.counts().host_resets, 1);
+    }
+
+    #[test]
+    fn success_ends_the_streak() {
+        let mut recovery = BusRecovery::new(3);
+        errors(&mut recovery, 0, 10, 2);
+        assert!(recovery.has_errors());
+        recovery.success();
+        assert!(!recovery.has_errors());
+        assert_eq!(errors(&mut recovery, 100, 10, 2), []);
+        assert_eq!(recovery.discovery_error(at(200)), RecoveryAction::ResetHost);
+    }
+
+    #[test]
+    fn resets_back_off() {
+        let mut recovery = BusRecovery::new(1);
+        // allowed after 250 ms, then 500 ms, then 1 s
+        assert_eq!(errors(&mut recovery, 0, 50, 40), [0, 250, 750, 1750]);
+
+        // back to the shortest wait
+        recovery.success();
+        assert_eq!(errors(&mut recovery, 2000, 50, 5), [2000]);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut recovery = BusRecovery::new(1);
+        let resets = errors(&mut recovery, 0, 1000, 30);
+        let waits: Vec<u64> = resets.windows(2).map(|w| w[1] - w[0]).collect();
+        assert_eq!(waits, [1000, 1000, 1000, 2000, 4000, 8000, 8000]);
+    }
+
+    #[test]
+    fn optional_requests_are_only_counted() {
+        let mut recovery = BusRecovery::default();
+        for _ in 0..10 {
+            recovery.optional_request_failed();
+        }
+        assert_eq!(recovery.counts().optional_requests_failed, 10);
+        assert_eq!(recovery.counts().host_resets, 0);
+        assert!(!recovery.has_errors());
+    }
+}
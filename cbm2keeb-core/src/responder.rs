@@ -0,0 +1,241 @@
+//! Handing the matrix state from the USB side to the responder without the CBM ever seeing half of
+//! an update, optionally only at the start of a KERNAL scan.
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{fence, AtomicU32, Ordering},
+};
+
+use crate::matrix::{self, ColumnBits};
+
+/// Sequence-locked words. There must only be a single writer at a time, readers always get the
+/// words from a single [`SeqCell::store`].
+pub struct SeqCell<const N: usize> {
+    seq: AtomicU32,
+    words: [AtomicU32; N],
+}
+
+/// The matrix state, handed from the USB side to the responder.
+pub type MatrixCell = SeqCell<4>;
+
+impl<const N: usize> SeqCell<N> {
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            words: [const { AtomicU32::new(0) }; N],
+        }
+    }
+
+    pub fn store(&self, words: [u32; N]) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        // odd while the words are being written
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        for (word, new) in self.words.iter().zip(words) {
+            word.store(new, Ordering::Relaxed);
+        }
+
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Changes with every [`SeqCell::store`].
+    #[inline(always)]
+    pub fn version(&self) -> u32 {
+        self.seq.load(Ordering::Relaxed)
+    }
+
+    /// Returns the words and their version, retrying while a store is in progress.
+    pub fn load(&self) -> (u32, [u32; N]) {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 0 {
+                let words = self
+                    .words
+                    .each_ref()
+                    .map(|word| word.load(Ordering::Relaxed));
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    return (seq, words);
+                }
+            }
+            spin_loop();
+        }
+    }
+}
+
+impl<const N: usize> Default for SeqCell<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Detects the start of a KERNAL scan from the column strobes.
+///
+/// A scan starts with the any-key check strobing all columns at once, or, for scans without it,
+/// when the strobed column index wraps around (this assumes the columns are scanned in ascending
+/// order). If the CBM stops scanning, a boundary is reported every `max_idle` observations so
+/// updates still get through.
+#[derive(Clone, Debug)]
+pub struct ScanSync {
+    max_idle: u32,
+    idle: u32,
+    last_col: Option<u32>,
+    in_any_key: bool,
+    any_key_seen: bool,
+}
+
+impl ScanSync {
+    pub const fn new(max_idle: u32) -> Self {
+        Self {
+            max_idle,
+            idle: 0,
+            last_col: None,
+            in_any_key: false,
+            any_key_seen: false,
+        }
+    }
+
+    /// Feeds the current column strobes (active high), returns whether a new scan starts.
+    #[inline(always)]
+    pub fn observe(&mut self, cols_in: u16) -> bool {
+        let boundary = if cols_in == 0xFFFF {
+            let entered = !self.in_any_key;
+            self.in_any_key = true;
+            self.any_key_seen |= entered;
+            entered
+        } else {
+            self.in_any_key = false;
+            if cols_in.is_power_of_two() {
+                let col = cols_in.trailing_zeros();
+                let wrapped = self.last_col.is_some_and(|last| col < last);
+                self.last_col = Some(col);
+                // the scan was already started by its any-key check
+                wrapped && !core::mem::take(&mut self.any_key_seen)
+            } else {
+                false
+            }
+        };
+
+        self.idle = self.idle.saturating_add(1);
+        if boundary || self.idle >= self.max_idle {
+            self.idle = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The responder's view of the matrix: picks up new states from the [`MatrixCell`], either
+/// right away or at the next scan boundary, and answers strobes from its own copy.
+#[derive(Clone, Debug)]
+pub struct Responder {
+    active: ColumnBits,
+    version: u32,
+    scan_sync: Option<ScanSync>,
+}
+
+impl Responder {
+    pub const fn new(scan_sync: Option<ScanSync>) -> Self {
+        Self {
+            active: [0; 4],
+            version: 0,
+            scan_sync,
+        }
+    }
+
+    /// Computes the row outputs for the strobed columns, see [`matrix::row_response`].
+    #[inline(always)]
+    pub fn respond(&mut self, cell: &MatrixCell, cols_in: u16) -> u8 {
+        let may_update = match &mut self.scan_sync {
+            Some(scan_sync) => scan_sync.observe(cols_in),
+            None => true,
+        };
+        if may_update && cell.version() != self.version {
+            (self.version, self.active) = cell.load();
+        }
+
+        matrix::row_response(cols_in, self.active)
+    }
+}
+
+impl Default for Responder {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::atomic::AtomicBool, thread};
+
+    #[test]
+    fn concurrent_loads_never_tear() {
+        let cell = MatrixCell::new();
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..200_000u32 {
+                    cell.store([i; 4]);
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+
+            while !done.load(Ordering::Relaxed) {
+                let (_, [a, b, c, d]) = cell.load();
+                assert!(a == b && b == c && c == d, "torn: {a} {b} {c} {d}");
+            }
+        });
+    }
+
+    fn scan(sync: &mut ScanSync, any_key: bool) -> Vec<bool> {
+        let strobes = any_key.then_some(0xFFFF).into_iter();
+        strobes
+            .chain((0..16).flat_map(|col| [1 << col, 0]))
+            .map(|cols_in| sync.observe(cols_in))
+            .collect()
+    }
+
+    #[test]
+    fn boundary_at_any_key_check() {
+        let mut sync = ScanSync::new(u32::MAX);
+        for _ in 0..3 {
+            let boundaries = scan(&mut sync, true);
+            assert!(boundaries[0]);
+            assert!(!boundaries[1..].iter().any(|&b| b));
+        }
+    }
+
+    #[test]
+    fn boundary_at_column_wrap_without_any_key_check() {
+        let mut sync = ScanSync::new(u32::MAX);
+        scan(&mut sync, false);
+        for _ in 0..3 {
+            let boundaries = scan(&mut sync, false);
+            assert!(boundaries[0]);
+            assert!(!boundaries[1..].iter().any(|&b| b));
+        }
+    }
+
+    #[test]
+    fn boundary_when_not_scanning() {
+        let mut sync = ScanSync::new(10);
+        let boundaries: Vec<_> = (0..30).map(|_| sync.observe(0)).collect();
+        assert_eq!(boundaries.iter().filter(|&&b| b).count(), 3);
+    }
+
+    #[test]
+    fn responder_waits_for_scan_start() {
+        let cell = MatrixCell::new();
+        let mut responder = Responder::new(Some(ScanSync::new(u32::MAX)));
+        responder.respond(&cell, 0xFFFF);
+        responder.respond(&cell, 1);
+
+        cell.store([0xFF; 4]);
+        assert_eq!(responder.respond(&cell, 1 << 1), 0xFF);
+        assert_eq!(responder.respond(&cell, 0xFFFF), 0x00);
+    }
+}
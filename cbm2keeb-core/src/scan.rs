@@ -0,0 +1,288 @@
+//! Derives the CBM's scan behaviour from the column strobes: scan period, column dwell time,
+//! strobe order and whether scans start with an any-key check.
+//!
+//! Scans are delimited like in [`ScanSync`](crate::responder::ScanSync): by the any-key check
+//! strobing all columns, or, without it, by the strobed column index wrapping around.
+
+use crate::{
+    pipeline::{Duration, Instant},
+    responder::SeqCell,
+};
+
+/// Timing of the last complete scan, plus when the current one started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScanTiming {
+    /// Start of the most recent scan.
+    pub start: Instant,
+    /// From the start of the last complete scan to the start of the next one.
+    pub period: Duration,
+    /// From the start of the last complete scan to the end of its last strobe.
+    pub active: Duration,
+    /// Longest single column strobe of the last complete scan.
+    pub dwell: Duration,
+    pub any_key_check: bool,
+    /// Number of single column strobes in the last complete scan.
+    pub columns: u8,
+    /// The strobed GPIO columns in order, 4 bits each, starting at the low bits. Only the first
+    /// 16 strobes are recorded.
+    pub order: u64,
+}
+
+/// [`ScanTiming`] handed from the responder to the rest of the firmware.
+pub type ScanCell = SeqCell<9>;
+
+impl ScanTiming {
+    /// The GPIO column of each strobe, in order.
+    pub fn order(&self) -> impl Iterator<Item = u8> {
+        let order = self.order;
+        (0..self.columns.min(16)).map(move |i| (order >> (i * 4)) as u8 & 0xF)
+    }
+
+    /// Whether the CBM is still scanning, a scan started within the last two periods.
+    pub fn is_current(&self, now: Instant) -> bool {
+        now <= self.start + self.period * 2
+    }
+
+    /// The first scan start at or after `now`, assuming the period stays the same.
+    pub fn next_scan_start(&self, now: Instant) -> Instant {
+        let period = self.period.ticks().max(1);
+        let since = now.ticks().saturating_sub(self.start.ticks());
+        self.start + Duration::from_ticks(since.div_ceil(period) * period)
+    }
+
+    /// When the first scan starting at or after `now` has strobed all of its columns.
+    pub fn next_full_scan_end(&self, now: Instant) -> Instant {
+        self.next_scan_start(now) + self.active
+    }
+
+    pub fn store(&self, cell: &ScanCell) {
+        let start = self.start.ticks();
+        cell.store([
+            1,
+            start as u32,
+            (start >> 32) as u32,
+            self.period.ticks() as u32,
+            self.active.ticks() as u32,
+            self.dwell.ticks() as u32,
+            (self.any_key_check as u32) << 8 | self.columns as u32,
+            self.order as u32,
+            (self.order >> 32) as u32,
+        ]);
+    }
+
+    /// `None` until a scan was stored.
+    pub fn load(cell: &ScanCell) -> Option<Self> {
+        let (_, words) = cell.load();
+        let [valid, start_lo, start_hi, period, active, dwell, flags, order_lo, order_hi] = words;
+        (valid != 0).then(|| Self {
+            start: Instant::from_ticks((start_hi as u64) << 32 | start_lo as u64),
+            period: Duration::from_ticks(period as u64),
+            active: Duration::from_ticks(active as u64),
+            dwell: Duration::from_ticks(dwell as u64),
+            any_key_check: flags & 0x100 != 0,
+            columns: flags as u8,
+            order: (order_hi as u64) << 32 | order_lo as u64,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Scan {
+    start: Instant,
+    end: Instant,
+    dwell: Duration,
+    any_key_check: bool,
+    columns: u8,
+    order: u64,
+}
+
+/// Feed it every change of the column strobes.
+#[derive(Clone, Debug)]
+pub struct ScanAnalyzer {
+    cols_in: u16,
+    strobe_start: Instant,
+    last_col: Option<u32>,
+    scan: Option<Scan>,
+    timing: Option<ScanTiming>,
+}
+
+impl ScanAnalyzer {
+    pub const fn new() -> Self {
+        Self {
+            cols_in: 0,
+            strobe_start: Instant::from_ticks(0),
+            last_col: None,
+            scan: None,
+            timing: None,
+        }
+    }
+
+    /// Feeds the column strobes (active high) from `time` on. Returns the new timing when a scan
+    /// completed, which is when the next one starts.
+    pub fn observe(&mut self, time: Instant, cols_in: u16) -> Option<ScanTiming> {
+        if cols_in == self.cols_in {
+            return None;
+        }
+
+        let ended = core::mem::replace(&mut self.cols_in, cols_in);
+        if let Some(scan) = &mut self.scan {
+            if ended.is_power_of_two() {
+                scan.dwell = scan.dwell.max(time - self.strobe_start);
+            }
+            if ended != 0 {
+                scan.end = time;
+            }
+        }
+        self.strobe_start = time;
+
+        let col = cols_in.is_power_of_two().then(|| cols_in.trailing_zeros());
+        let boundary = if cols_in == 0xFFFF {
+            true
+        } else if let Some(col) = col {
+            let wrapped = self.last_col.is_some_and(|last| col < last);
+            self.last_col = Some(col);
+            wrapped && !self.scan.is_some_and(|scan| scan.any_key_check)
+        } else {
+            false
+        };
+
+        let mut completed = None;
+        if boundary {
+            if let Some(scan) = self.scan {
+                let timing = ScanTiming {
+                    start: time,
+                    period: time - scan.start,
+                    active: scan.end - scan.start,
+                    dwell: scan.dwell,
+                    any_key_check: scan.any_key_check,
+                    columns: scan.columns,
+                    order: scan.order,
+                };
+                self.timing = Some(timing);
+                completed = Some(timing);
+            }
+            self.scan = Some(Scan {
+                start: time,
+                end: time,
+                dwell: Duration::from_ticks(0),
+                any_key_check: cols_in == 0xFFFF,
+                columns: 0,
+                order: 0,
+            });
+        }
+
+        if let (Some(scan), Some(col)) = (&mut self.scan, col) {
+            if scan.columns < 16 {
+                scan.order |= (col as u64) << (scan.columns * 4);
+            }
+            scan.columns = scan.columns.saturating_add(1);
+        }
+
+        completed
+    }
+
+    /// Timing of the last complete scan, with `start` of the current one.
+    pub fn timing(&self) -> Option<&ScanTiming> {
+        self.timing.as_ref()
+    }
+}
+
+impl Default for ScanAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `scans` scans of 10 us strobes with 2 us gaps, every 20 ms.
+    fn run(analyzer: &mut ScanAnalyzer, any_key: bool, order: &[u32], scans: u64) {
+        for scan in 0..scans {
+            let mut time = scan * 20_000;
+            let strobes = any_key.then_some(0xFFFF).into_iter();
+            for cols_in in strobes.chain(order.iter().map(|col| 1 << col)) {
+                analyzer.observe(Instant::from_ticks(time), cols_in);
+                analyzer.observe(Instant::from_ticks(time + 10), 0);
+                time += 12;
+            }
+        }
+    }
+
+    #[test]
+    fn measures_scans_with_any_key_check() {
+        let mut analyzer = ScanAnalyzer::new();
+        let order: Vec<u32> = (0..16).collect();
+        run(&mut analyzer, true, &order, 3);
+
+        let timing = analyzer.timing().unwrap();
+        assert_eq!(timing.start, Instant::from_ticks(40_000));
+        assert_eq!(timing.period, Duration::from_ticks(20_000));
+        assert_eq!(timing.active, Duration::from_ticks(17 * 12 - 2));
+        assert_eq!(timing.dwell, Duration::from_ticks(10));
+        assert!(timing.any_key_check);
+        assert_eq!(
+            timing.order().collect::<Vec<_>>(),
+            (0..16).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn measures_scans_without_any_key_check() {
+        let mut analyzer = ScanAnalyzer::new();
+        let order = [8, 9, 10, 11, 12, 13, 14, 15, 0, 1, 2, 3, 4, 5, 6, 7];
+        run(&mut analyzer, false, &order, 3);
+
+        // scans are delimited where the index wraps to PA0, in the middle of the KERNAL's scan
+        let timing = analyzer.timing().unwrap();
+        assert_eq!(timing.period, Duration::from_ticks(20_000));
+        assert!(!timing.any_key_check);
+        assert_eq!(timing.columns, 16);
+    }
+
+    #[test]
+    fn nothing_without_strobes() {
+        let mut analyzer = ScanAnalyzer::new();
+        analyzer.observe(Instant::from_ticks(0), 0);
+        assert_eq!(analyzer.timing(), None);
+    }
+
+    #[test]
+    fn next_full_scan() {
+        let timing = ScanTiming {
+            start: Instant::from_ticks(40_000),
+            period: Duration::from_ticks(20_000),
+            active: Duration::from_ticks(200),
+            dwell: Duration::from_ticks(10),
+            any_key_check: true,
+            columns: 16,
+            order: 0,
+        };
+
+        let at = |t| timing.next_full_scan_end(Instant::from_ticks(t)).ticks();
+        assert_eq!(at(40_000), 40_200);
+        assert_eq!(at(40_001), 60_200);
+        assert_eq!(at(60_000), 60_200);
+        assert!(timing.is_current(Instant::from_ticks(80_000)));
+        assert!(!timing.is_current(Instant::from_ticks(80_001)));
+    }
+
+    #[test]
+    fn store_and_load() {
+        let cell = ScanCell::new();
+        assert_eq!(ScanTiming::load(&cell), None);
+
+        let mut analyzer = ScanAnalyzer::new();
+        run(
+            &mut analyzer,
+            true,
+            &[3, 2, 1, 0, 15, 14, 13, 12, 11, 10],
+            2,
+        );
+        let timing = *analyzer.timing().unwrap();
+        timing.store(&cell);
+        assert_eq!(ScanTiming::load(&cell), Some(timing));
+    }
+}
@@ -0,0 +1,140 @@
+//! Latches the CBM's shift like its mechanical SHIFT LOCK key: the first press of the lock key
+//! holds shift down, the next one lets go of it. USB keyboards only have a non-locking Caps Lock
+//! key, the latch can take its place.
+
+use crate::{
+    key_state::KeyEvent,
+    keys::KEY_LEFTSHIFT,
+    pipeline::{Events, Processor, TimedEvent, SHIFT_LOCK_KEY},
+};
+
+#[derive(Clone, Debug)]
+pub struct ShiftLock {
+    key: Option<u8>,
+    latched: bool,
+    /// The keyboard's left shift is held, independently of the latch.
+    shift_held: bool,
+}
+
+impl ShiftLock {
+    /// Latches on `key`, `None` turns the stage off.
+    pub const fn new(key: Option<u8>) -> Self {
+        Self {
+            key,
+            latched: false,
+            shift_held: false,
+        }
+    }
+
+    pub fn is_latched(&self) -> bool {
+        self.latched
+    }
+}
+
+impl Default for ShiftLock {
+    /// Latches on [`SHIFT_LOCK_KEY`](crate::pipeline::SHIFT_LOCK_KEY).
+    fn default() -> Self {
+        Self::new(SHIFT_LOCK_KEY)
+    }
+}
+
+impl Processor for ShiftLock {
+    fn process(&mut self, event: TimedEvent, out: &mut Events) {
+        let key = event.event.key();
+        if self.key == Some(key) {
+            if let KeyEvent::Press(_) = event.event {
+                self.latched = !self.latched;
+                if !self.shift_held {
+                    out.emit(TimedEvent {
+                        event: match self.latched {
+                            true => KeyEvent::Press(KEY_LEFTSHIFT),
+                            false => KeyEvent::Release(KEY_LEFTSHIFT),
+                        },
+                        ..event
+                    });
+                }
+            }
+            return;
+        }
+
+        if key == KEY_LEFTSHIFT {
+            self.shift_held = matches!(event.event, KeyEvent::Press(_));
+            // shift is already down while latched
+            if self.latched {
+                return;
+            }
+        }
+        out.emit(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keys::{KEY_A, KEY_CAPSLOCK},
+        pipeline::{process, Instant, MatrixOutput},
+    };
+
+    fn event(event: KeyEvent) -> TimedEvent {
+        TimedEvent {
+            time: Instant::from_ticks(0),
+            event,
+        }
+    }
+
+    fn run(lock: &mut ShiftLock, output: &mut MatrixOutput, events: &[KeyEvent]) -> Vec<u8> {
+        process(&mut [lock], events.iter().copied().map(event), output);
+        output.keys().iter().collect()
+    }
+
+    #[test]
+    fn latches_and_unlatches_shift() {
+        let mut lock = ShiftLock::new(Some(KEY_CAPSLOCK));
+        let mut output = MatrixOutput::new();
+
+        let press = [
+            KeyEvent::Press(KEY_CAPSLOCK),
+            KeyEvent::Release(KEY_CAPSLOCK),
+        ];
+        assert_eq!(run(&mut lock, &mut output, &press), [KEY_LEFTSHIFT]);
+        assert!(lock.is_latched());
+        let typed = run(&mut lock, &mut output, &[KeyEvent::Press(KEY_A)]);
+        assert_eq!(typed, [KEY_A, KEY_LEFTSHIFT]);
+
+        assert_eq!(run(&mut lock, &mut output, &press), [KEY_A]);
+        assert!(!lock.is_latched());
+    }
+
+    #[test]
+    fn held_shift_outlasts_the_latch() {
+        let mut lock = ShiftLock::new(Some(KEY_CAPSLOCK));
+        let mut output = MatrixOutput::new();
+
+        let events = [
+            KeyEvent::Press(KEY_CAPSLOCK),
+            KeyEvent::Press(KEY_LEFTSHIFT),
+            KeyEvent::Release(KEY_LEFTSHIFT),
+        ];
+        // the release of the held shift doesn't end the latch
+        assert_eq!(run(&mut lock, &mut output, &events), [KEY_LEFTSHIFT]);
+
+        let events = [
+            KeyEvent::Press(KEY_LEFTSHIFT),
+            KeyEvent::Press(KEY_CAPSLOCK),
+        ];
+        // and unlatching doesn't release the held shift
+        assert_eq!(run(&mut lock, &mut output, &events), [KEY_LEFTSHIFT]);
+        let events = [KeyEvent::Release(KEY_LEFTSHIFT)];
+        assert!(run(&mut lock, &mut output, &events).is_empty());
+    }
+
+    #[test]
+    fn off_without_a_key() {
+        let mut lock = ShiftLock::new(None);
+        let mut output = MatrixOutput::new();
+        let events = [KeyEvent::Press(KEY_CAPSLOCK)];
+        assert_eq!(run(&mut lock, &mut output, &events), [KEY_CAPSLOCK]);
+        assert!(!lock.is_latched());
+    }
+}
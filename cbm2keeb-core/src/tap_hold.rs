@@ -0,0 +1,540 @@
+//! Dual function keys for compact keyboards, like QMK's mod-tap and one-shot keys. A tap-hold key
+//! is one key when tapped and another one when held, e.g. Caps Lock as ESC and CTRL. A one-shot
+//! key tapped on its own stays pressed for the next key, so a modifier doesn't have to be held.
+//!
+//! Events arriving while a tap-hold key is undecided are held back and passed on after the
+//! decision, in their order. Decisions only depend on the event times, the deadline of the
+//! tapping term is met by [`Processor::timeout`].
+
+use crate::{
+    key_state::KeyEvent,
+    keys::{KEY_LEFTCTRL, KEY_RIGHTMETA},
+    pipeline::{emit, Duration, Events, Instant, Processor, TimedEvent},
+};
+
+/// How long a tap-hold key can be held and still be a tap.
+pub const DEFAULT_TERM: Duration = Duration::millis(200);
+
+/// Events held back while a tap-hold key is undecided, further events decide it's held.
+const MAX_BUFFERED: usize = 16;
+/// Tap-hold keys held at the same time, further ones are tapped.
+const MAX_HELD: usize = 4;
+/// One-shot keys active at the same time.
+const MAX_ONE_SHOTS: usize = 4;
+
+/// When a tap-hold key is held rather than tapped, besides being held past its term.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Flavor {
+    /// Only the term decides.
+    TappingTerm,
+    /// Another key is pressed and released while the key is held.
+    PermissiveHold,
+    /// Another key is pressed while the key is held.
+    HoldOnOtherKeyPress,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Behavior {
+    /// `tap` when released within `term`, otherwise `hold` as long as the key is held.
+    TapHold {
+        tap: u8,
+        hold: u8,
+        term: Duration,
+        flavor: Flavor,
+    },
+    /// The key stays pressed after a tap until the next key is released. Tapping it again lets go
+    /// of it.
+    OneShot,
+}
+
+/// A tap-hold key that isn't decided yet.
+#[derive(Clone, Copy, Debug)]
+struct Pending {
+    key: u8,
+    tap: u8,
+    hold: u8,
+    until: Instant,
+    flavor: Flavor,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OneShotState {
+    /// Held like a normal key, `used` once another key was pressed.
+    Held { used: bool },
+    /// Tapped, waiting for the next key.
+    Latched,
+    /// Let go of when the key is released.
+    Applied(u8),
+    /// Let go of by a second press, which is still held.
+    Cancelled,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct OneShot {
+    key: u8,
+    state: OneShotState,
+}
+
+#[derive(Clone, Debug)]
+pub struct TapHold {
+    behaviors: &'static [(u8, Behavior)],
+    pending: Option<Pending>,
+    buffer: [Option<TimedEvent>; MAX_BUFFERED],
+    /// `(key, hold)` of the tap-hold keys decided to be held.
+    held: [Option<(u8, u8)>; MAX_HELD],
+    one_shots: [Option<OneShot>; MAX_ONE_SHOTS],
+}
+
+impl TapHold {
+    /// `(key, behavior)`, the other keys pass through.
+    pub const fn new(behaviors: &'static [(u8, Behavior)]) -> Self {
+        Self {
+            behaviors,
+            pending: None,
+            buffer: [None; MAX_BUFFERED],
+            held: [None; MAX_HELD],
+            one_shots: [None; MAX_ONE_SHOTS],
+        }
+    }
+
+    /// Replaces the behaviors, e.g. for a new keyboard. Keys already held keep their behavior.
+    pub fn set_behaviors(&mut self, behaviors: &'static [(u8, Behavior)]) {
+        self.behaviors = behaviors;
+    }
+
+    fn behavior(&self, key: u8) -> Option<Behavior> {
+        self.behaviors
+            .iter()
+            .find(|&&(behavior_key, _)| behavior_key == key)
+            .map(|&(_, behavior)| behavior)
+    }
+
+    /// Holds back `event` until the pending key is decided, `false` if there's no room left.
+    fn hold_back(&mut self, event: TimedEvent) -> bool {
+        let Some(slot) = self.buffer.iter_mut().find(|slot| slot.is_none()) else {
+            return false;
+        };
+        *slot = Some(event);
+        true
+    }
+
+    /// Decides the pending key at `time`, then passes on the events held back.
+    fn decide(&mut self, hold: bool, time: Instant, out: &mut Events) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        // a hold that can't be tracked couldn't be released
+        let slot = self.held.iter_mut().find(|slot| slot.is_none());
+        if let Some(slot) = slot.filter(|_| hold) {
+            *slot = Some((pending.key, pending.hold));
+            self.emit(time, KeyEvent::Press(pending.hold), out);
+        } else {
+            self.emit(time, KeyEvent::Press(pending.tap), out);
+            self.emit(time, KeyEvent::Release(pending.tap), out);
+        }
+
+        // later events may make another key pending
+        let buffer = core::mem::replace(&mut self.buffer, [None; MAX_BUFFERED]);
+        for event in buffer.into_iter().flatten() {
+            let time = time.max(event.time);
+            self.process(TimedEvent { time, ..event }, out);
+        }
+    }
+
+    /// Handles `event` while no key is pending.
+    fn handle(&mut self, event: TimedEvent, out: &mut Events) {
+        let key = event.event.key();
+        let pressed = matches!(event.event, KeyEvent::Press(_));
+        match self.behavior(key) {
+            Some(Behavior::TapHold {
+                tap,
+                hold,
+                term,
+                flavor,
+            }) if pressed => {
+                self.pending = Some(Pending {
+                    key,
+                    tap,
+                    hold,
+                    until: event.time + term,
+                    flavor,
+                });
+                return;
+            }
+            Some(Behavior::OneShot) => {
+                self.one_shot(key, pressed, event.time, out);
+                return;
+            }
+            _ => {}
+        }
+
+        let held = self
+            .held
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|(held, _)| held == key));
+        match held {
+            Some(slot) if !pressed => {
+                let (_, hold) = slot.take().unwrap();
+                self.emit(event.time, KeyEvent::Release(hold), out);
+            }
+            _ => self.emit(event.time, event.event, out),
+        }
+    }
+
+    fn one_shot(&mut self, key: u8, pressed: bool, time: Instant, out: &mut Events) {
+        let slot = self
+            .one_shots
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|one_shot| one_shot.key == key));
+        match (slot, pressed) {
+            (Some(Some(one_shot)), true) => {
+                one_shot.state = OneShotState::Cancelled;
+                self.emit(time, KeyEvent::Release(key), out);
+            }
+            (Some(slot), false) => {
+                let state = slot.map(|one_shot| one_shot.state);
+                match state {
+                    Some(OneShotState::Held { used: false }) => {
+                        *slot = Some(OneShot {
+                            key,
+                            state: OneShotState::Latched,
+                        });
+                    }
+                    Some(OneShotState::Held { used: true }) => {
+                        *slot = None;
+                        self.emit(time, KeyEvent::Release(key), out);
+                    }
+                    Some(OneShotState::Cancelled) => *slot = None,
+                    _ => {}
+                }
+            }
+            (None, true) => {
+                let free = self.one_shots.iter_mut().find(|slot| slot.is_none());
+                if let Some(slot) = free {
+                    *slot = Some(OneShot {
+                        key,
+                        state: OneShotState::Held { used: false },
+                    });
+                }
+                self.emit(time, KeyEvent::Press(key), out);
+            }
+            _ => self.emit(time, KeyEvent::Release(key), out),
+        }
+    }
+
+    /// Passes `event` on, and lets go of the one-shot keys it used up.
+    fn emit(&mut self, time: Instant, event: KeyEvent, out: &mut Events) {
+        out.emit(TimedEvent { time, event });
+        match event {
+            // a modifier is for the next key as well
+            KeyEvent::Press(KEY_LEFTCTRL..=KEY_RIGHTMETA) => {}
+            KeyEvent::Press(key) => {
+                for one_shot in self.one_shots.iter_mut().flatten() {
+                    match one_shot.state {
+                        OneShotState::Held { .. } => {
+                            one_shot.state = OneShotState::Held { used: true };
+                        }
+                        OneShotState::Latched => one_shot.state = OneShotState::Applied(key),
+                        _ => {}
+                    }
+                }
+            }
+            KeyEvent::Release(key) => {
+                for slot in self.one_shots.iter_mut() {
+                    if let Some(one_shot) =
+                        slot.filter(|one_shot| one_shot.state == OneShotState::Applied(key))
+                    {
+                        *slot = None;
+                        emit(out, time, KeyEvent::Release(one_shot.key));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for TapHold {
+    fn default() -> Self {
+        Self::new(&[])
+    }
+}
+
+impl Processor for TapHold {
+    fn process(&mut self, event: TimedEvent, out: &mut Events) {
+        let Some(pending) = self.pending else {
+            self.handle(event, out);
+            return;
+        };
+        // held past the term, before this event
+        if event.time >= pending.until {
+            self.decide(true, pending.until, out);
+            self.process(event, out);
+            return;
+        }
+
+        let hold = match event.event {
+            KeyEvent::Release(key) if key == pending.key => {
+                self.decide(false, event.time, out);
+                return;
+            }
+            KeyEvent::Press(_) => pending.flavor == Flavor::HoldOnOtherKeyPress,
+            // tapped while the key is held
+            KeyEvent::Release(key) => {
+                pending.flavor == Flavor::PermissiveHold
+                    && self
+                        .buffer
+                        .iter()
+                        .flatten()
+                        .any(|buffered| buffered.event == KeyEvent::Press(key))
+            }
+        };
+        if !self.hold_back(event) {
+            self.decide(true, event.time, out);
+            self.process(event, out);
+        } else if hold {
+            self.decide(true, event.time, out);
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|pending| pending.until)
+    }
+
+    fn timeout(&mut self, _now: Instant, out: &mut Events) {
+        if let Some(pending) = self.pending {
+            self.decide(true, pending.until, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keys::*,
+        pipeline::fixtures::{press, release, run},
+    };
+
+    const fn tap_hold(flavor: Flavor) -> Behavior {
+        Behavior::TapHold {
+            tap: KEY_ESC,
+            hold: KEY_LEFTCTRL,
+            term: DEFAULT_TERM,
+            flavor,
+        }
+    }
+
+    static TAPPING_TERM: [(u8, Behavior); 2] = [
+        (KEY_CAPSLOCK, tap_hold(Flavor::TappingTerm)),
+        (KEY_RIGHTSHIFT, Behavior::OneShot),
+    ];
+    static PERMISSIVE_HOLD: [(u8, Behavior); 1] =
+        [(KEY_CAPSLOCK, tap_hold(Flavor::PermissiveHold))];
+    static HOLD_ON_OTHER_KEY_PRESS: [(u8, Behavior); 1] =
+        [(KEY_CAPSLOCK, tap_hold(Flavor::HoldOnOtherKeyPress))];
+
+    #[test]
+    fn tapped_or_held_past_the_term() {
+        let mut stage = TapHold::new(&TAPPING_TERM);
+        let input = [
+            press(0, KEY_CAPSLOCK),
+            release(150, KEY_CAPSLOCK),
+            press(300, KEY_CAPSLOCK),
+            release(600, KEY_CAPSLOCK),
+        ];
+        assert_eq!(
+            run(&mut stage, &input),
+            [
+                press(150, KEY_ESC),
+                release(150, KEY_ESC),
+                press(500, KEY_LEFTCTRL),
+                release(600, KEY_LEFTCTRL),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_keys_wait_for_the_decision() {
+        let input = [
+            press(0, KEY_CAPSLOCK),
+            press(50, KEY_C),
+            release(100, KEY_C),
+            release(150, KEY_CAPSLOCK),
+        ];
+
+        // a tap, the C typed while Caps Lock is held follows the ESC
+        let mut stage = TapHold::new(&TAPPING_TERM);
+        assert_eq!(
+            run(&mut stage, &input),
+            [
+                press(150, KEY_ESC),
+                release(150, KEY_ESC),
+                press(150, KEY_C),
+                release(150, KEY_C),
+            ]
+        );
+
+        // CTRL+C once C is let go
+        let mut stage = TapHold::new(&PERMISSIVE_HOLD);
+        assert_eq!(
+            run(&mut stage, &input),
+            [
+                press(100, KEY_LEFTCTRL),
+                press(100, KEY_C),
+                release(100, KEY_C),
+                release(150, KEY_LEFTCTRL),
+            ]
+        );
+
+        // CTRL+C as soon as C is pressed
+        let mut stage = TapHold::new(&HOLD_ON_OTHER_KEY_PRESS);
+        assert_eq!(
+            run(&mut stage, &input),
+            [
+                press(50, KEY_LEFTCTRL),
+                press(50, KEY_C),
+                release(100, KEY_C),
+                release(150, KEY_LEFTCTRL),
+            ]
+        );
+    }
+
+    #[test]
+    fn rolling_over_is_a_tap() {
+        // C is still held when Caps Lock is let go, even permissive hold makes that a tap
+        let mut stage = TapHold::new(&PERMISSIVE_HOLD);
+        let input = [
+            press(0, KEY_CAPSLOCK),
+            press(50, KEY_C),
+            release(100, KEY_CAPSLOCK),
+            release(150, KEY_C),
+        ];
+        assert_eq!(
+            run(&mut stage, &input),
+            [
+                press(100, KEY_ESC),
+                release(100, KEY_ESC),
+                press(100, KEY_C),
+                release(150, KEY_C),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_beyond_the_held_ones_are_tapped() {
+        const fn hold(key: u8, hold: u8) -> (u8, Behavior) {
+            let behavior = Behavior::TapHold {
+                tap: key,
+                hold,
+                term: DEFAULT_TERM,
+                flavor: Flavor::TappingTerm,
+            };
+            (key, behavior)
+        }
+        static MODIFIERS: [(u8, Behavior); MAX_HELD + 1] = [
+            hold(KEY_A, KEY_LEFTCTRL),
+            hold(KEY_S, KEY_LEFTSHIFT),
+            hold(KEY_D, KEY_LEFTALT),
+            hold(KEY_F, KEY_LEFTMETA),
+            hold(KEY_G, KEY_RIGHTCTRL),
+        ];
+
+        let mut stage = TapHold::new(&MODIFIERS);
+        let mut input: Vec<_> = (0..)
+            .zip(MODIFIERS)
+            .map(|(i, (key, _))| press(300 * i, key))
+            .collect();
+        input.extend(MODIFIERS.map(|(key, _)| release(2000, key)));
+        assert_eq!(
+            run(&mut stage, &input),
+            [
+                press(200, KEY_LEFTCTRL),
+                press(500, KEY_LEFTSHIFT),
+                press(800, KEY_LEFTALT),
+                press(1100, KEY_LEFTMETA),
+                press(1400, KEY_G),
+                release(1400, KEY_G),
+                release(2000, KEY_LEFTCTRL),
+                release(2000, KEY_LEFTSHIFT),
+                release(2000, KEY_LEFTALT),
+                release(2000, KEY_LEFTMETA),
+                // nothing to let go of
+                release(2000, KEY_G),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_shot_keys_last_for_the_next_key() {
+        let mut stage = TapHold::new(&TAPPING_TERM);
+        let input = [
+            press(0, KEY_RIGHTSHIFT),
+            release(10, KEY_RIGHTSHIFT),
+            press(50, KEY_A),
+            release(60, KEY_A),
+            press(70, KEY_B),
+            release(80, KEY_B),
+            // held like a normal shift
+            press(100, KEY_RIGHTSHIFT),
+            press(110, KEY_C),
+            release(120, KEY_C),
+            release(130, KEY_RIGHTSHIFT),
+            // tapped twice
+            press(200, KEY_RIGHTSHIFT),
+            release(210, KEY_RIGHTSHIFT),
+            press(220, KEY_RIGHTSHIFT),
+            release(230, KEY_RIGHTSHIFT),
+        ];
+        assert_eq!(
+            run(&mut stage, &input),
+            [
+                press(0, KEY_RIGHTSHIFT),
+                press(50, KEY_A),
+                release(60, KEY_A),
+                release(60, KEY_RIGHTSHIFT),
+                press(70, KEY_B),
+                release(80, KEY_B),
+                press(100, KEY_RIGHTSHIFT),
+                press(110, KEY_C),
+                release(120, KEY_C),
+                release(130, KEY_RIGHTSHIFT),
+                press(200, KEY_RIGHTSHIFT),
+                release(220, KEY_RIGHTSHIFT),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_shot_keys_outlast_a_tap_hold_key() {
+        // the held CTRL doesn't use up the shift, the tap does
+        let mut stage = TapHold::new(&TAPPING_TERM);
+        let input = [
+            press(0, KEY_RIGHTSHIFT),
+            release(10, KEY_RIGHTSHIFT),
+            press(20, KEY_CAPSLOCK),
+            release(400, KEY_CAPSLOCK),
+            press(500, KEY_CAPSLOCK),
+            release(550, KEY_CAPSLOCK),
+        ];
+        assert_eq!(
+            run(&mut stage, &input),
+            [
+                press(0, KEY_RIGHTSHIFT),
+                press(220, KEY_LEFTCTRL),
+                release(400, KEY_LEFTCTRL),
+                press(550, KEY_ESC),
+                release(550, KEY_ESC),
+                release(550, KEY_RIGHTSHIFT),
+            ]
+        );
+    }
+
+    #[test]
+    fn everything_else_passes_through() {
+        let mut stage = TapHold::default();
+        let input = [press(0, KEY_CAPSLOCK), release(500, KEY_CAPSLOCK)];
+        assert_eq!(run(&mut stage, &input), input);
+    }
+}
@@ -0,0 +1,759 @@
+//! Translates the host keys to CBM keys, by position or by character.
+//!
+//! Positional mode, the default, presses the CBM key at the place of the host key, after moving
+//! the letters of the [`HostLayout`] to the keys labelled with them. The modifiers go with the
+//! host's, so shift+2 types whatever the CBM prints on its shifted 2.
+//!
+//! Symbolic mode types the character the host key produces instead: shift+2 on a US keyboard
+//! presses the CBM's `@` key, without shift. The CBM's shift is then pressed or released as each
+//! typed character needs. In both modes, the [`CHORDS`] type a CBM key with the modifiers they
+//! give. A key that needs other modifiers than the host's is only pressed once the CBM has seen
+//! them in a complete scan, so no scan sees the key with the old ones.
+//! Keys that don't type a character and keys pressed while CTRL, C= or Alt are held stay
+//! positional. Right Alt is AltGr on layouts that have it, dead keys put their accent on the next
+//! character. Characters the CBM doesn't have are replaced by [similar](petscii::similar) ones,
+//! keys typing neither type nothing.
+
+use crate::{
+    key_state::{KeyEvent, KeyState},
+    keys::*,
+    layer::{Action, Layers, LAYERS},
+    layout::{self, HostLayout, Level, DEFAULT_LAYOUT},
+    petscii,
+    pipeline::{emit, Duration, Events, Instant, Processor, TimedEvent},
+    scan::ScanTiming,
+};
+
+/// How long a shift change is given to be seen by the CBM without measured scan timing: a scan at
+/// 50 Hz.
+pub const DEFAULT_SETTLE: Duration = Duration::millis(20);
+
+/// Host keys typing characters, chords or layer keys held at the same time, further ones aren't
+/// pressed as their release couldn't be found. Positional keys don't count.
+const MAX_ACTIVE: usize = 8;
+/// Events waiting for a shift change, further ones are passed on right away.
+const MAX_QUEUED: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    Positional,
+    Symbolic,
+}
+
+/// The CBM's modifiers, `(bit, key)`. The bits are the ones of [`Chord::modifiers`].
+const CBM_MODIFIERS: [(u8, u8); 3] = [
+    (MOD_LEFTSHIFT, KEY_LEFTSHIFT),
+    (MOD_LEFTCTRL, KEY_LEFTCTRL),
+    (MOD_RIGHTMETA, KEY_RIGHTMETA),
+];
+
+#[derive(Clone, Copy, Debug)]
+struct Active {
+    host: u8,
+    cbm: u8,
+    /// The CBM modifiers the key needs, `None` if it goes with the host's.
+    modifiers: Option<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Translator {
+    mode: Mode,
+    layout: HostLayout,
+    /// Switches between the modes.
+    toggle_key: Option<u8>,
+    layers: Layers,
+    settle: Duration,
+    timing: Option<ScanTiming>,
+    /// The modifiers held on the host, as in boot reports.
+    host_modifiers: u8,
+    /// The keys held on the host, in the order they were pressed.
+    active: [Option<Active>; MAX_ACTIVE],
+    /// The keys held on the host that press their key in the keymap, their release maps back
+    /// through it.
+    positional: KeyState,
+    /// The CBM's modifiers, see [`CBM_MODIFIERS`].
+    modifiers: u8,
+    /// The accent of the last dead key, for the next character.
+    dead: Option<char>,
+    /// Events to pass on at their time, in order.
+    queue: [Option<TimedEvent>; MAX_QUEUED],
+}
+
+impl Translator {
+    /// Switches modes on `toggle_key`, `None` keeps `mode`. Shift changes get `settle` to be seen
+    /// while there's no current scan timing. The `layers` go over both modes.
+    pub const fn new(mode: Mode, toggle_key: Option<u8>, layers: Layers, settle: Duration) -> Self {
+        Self {
+            mode,
+            layout: DEFAULT_LAYOUT,
+            toggle_key,
+            layers,
+            settle,
+            timing: None,
+            host_modifiers: 0,
+            active: [None; MAX_ACTIVE],
+            positional: KeyState::new(),
+            modifiers: 0,
+            dead: None,
+            queue: [None; MAX_QUEUED],
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Switches the mode, releasing all keys pressed in the old one. Keys still held on the host
+    /// are pressed again only once they're pressed again.
+    pub fn set_mode(&mut self, mode: Mode, time: Instant, out: &mut Events) {
+        if mode == self.mode {
+            return;
+        }
+
+        self.flush(out);
+        for active in self.active.iter_mut().filter_map(Option::take) {
+            emit(out, time, KeyEvent::Release(active.cbm));
+        }
+        for key in self.positional.iter() {
+            emit(out, time, KeyEvent::Release(self.layout.keymap().key(key)));
+        }
+        self.positional = KeyState::new();
+        self.update_modifiers(time, out);
+        if self.host_modifiers & MOD_RIGHTALT != 0 && self.layout.has_altgr() {
+            let event = match mode {
+                Mode::Positional => KeyEvent::Press(KEY_RIGHTALT),
+                Mode::Symbolic => KeyEvent::Release(KEY_RIGHTALT),
+            };
+            emit(out, time, event);
+        }
+        self.dead = None;
+        self.mode = mode;
+    }
+
+    /// The topmost active layer, see [`Layers::top`].
+    pub fn layer(&self) -> u8 {
+        self.layers.top()
+    }
+
+    /// The layout of the keyboard typing, see [`Profile::layout`](crate::profile::Profile::layout).
+    pub fn set_layout(&mut self, layout: HostLayout) {
+        self.layout = layout;
+    }
+
+    /// Updates the measured scan timing, see [`ScanAnalyzer`](crate::scan::ScanAnalyzer).
+    pub fn set_scan_timing(&mut self, timing: Option<ScanTiming>) {
+        self.timing = timing;
+    }
+
+    /// When the CBM has seen a shift change at `time` in a complete scan.
+    fn settled(&self, time: Instant) -> Instant {
+        match self.timing.filter(|timing| timing.is_current(time)) {
+            Some(timing) => timing.next_full_scan_end(time),
+            None => time + self.settle,
+        }
+    }
+
+    /// Whether Right Alt is AltGr, instead of the CBM's π key.
+    fn altgr(&self) -> bool {
+        self.mode == Mode::Symbolic && self.layout.has_altgr()
+    }
+
+    /// The characters of the host keys typed now.
+    fn level(&self) -> Level {
+        if self.altgr() && self.host_modifiers & MOD_RIGHTALT != 0 {
+            Level::AltGr
+        } else if self.host_modifiers & (MOD_LEFTSHIFT | MOD_RIGHTSHIFT) != 0 {
+            Level::Shifted
+        } else {
+            Level::Normal
+        }
+    }
+
+    /// The character `key` types in symbolic mode, `None` if it stays positional.
+    fn char(&self, key: u8) -> Option<char> {
+        let altgr = match self.altgr() {
+            true => MOD_RIGHTALT,
+            false => 0,
+        };
+        let shift = MOD_LEFTSHIFT | MOD_RIGHTSHIFT;
+        if self.mode != Mode::Symbolic || self.host_modifiers & !(shift | altgr) != 0 {
+            return None;
+        }
+
+        self.layout.char(key, self.level())
+    }
+
+    /// Passes `event` on at `at` or later, but after the events already waiting. Returns when it's
+    /// passed on.
+    fn emit_at(&mut self, event: KeyEvent, now: Instant, at: Instant, out: &mut Events) -> Instant {
+        let last = self.queue.iter().flatten().map(|queued| queued.time).max();
+        let time = last.map_or(at, |last| last.max(at));
+        if last.is_none() && time <= now {
+            emit(out, now, event);
+            return time;
+        }
+
+        // a full queue is passed on early rather than letting `event` overtake it
+        if self.queue.iter().all(Option::is_some) {
+            self.flush(out);
+        }
+        if let Some(slot) = self.queue.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(TimedEvent { time, event });
+        }
+        time
+    }
+
+    /// Brings the CBM's modifiers to what the most recent key needing some wants, or to the
+    /// host's. Returns when the CBM has seen them.
+    fn update_modifiers(&mut self, time: Instant, out: &mut Events) -> Instant {
+        let host = CBM_MODIFIERS
+            .iter()
+            .filter(|&&(_, key)| self.host_modifiers & host_bits(key) != 0)
+            .fold(0, |modifiers, (bit, _)| modifiers | bit);
+        let wanted = self
+            .active
+            .iter()
+            .flatten()
+            .rev()
+            .find_map(|active| active.modifiers)
+            .unwrap_or(host);
+        if wanted == self.modifiers {
+            return time;
+        }
+
+        let mut changed = time;
+        for (bit, key) in CBM_MODIFIERS {
+            let event = match (wanted & bit != 0, self.modifiers & bit != 0) {
+                (true, false) => KeyEvent::Press(key),
+                (false, true) => KeyEvent::Release(key),
+                _ => continue,
+            };
+            changed = self.emit_at(event, time, time, out);
+        }
+        self.modifiers = wanted;
+        self.settled(changed)
+    }
+
+    fn press(&mut self, key: u8, time: Instant, out: &mut Events) {
+        let char = self.char(key);
+        let dead = self.dead.take();
+        if let Some(accent) = char.filter(|&char| layout::is_dead(char)) {
+            self.dead = Some(accent);
+            return;
+        }
+
+        let char = char.map(|char| {
+            dead.and_then(|accent| layout::compose(accent, char))
+                .unwrap_or(char)
+        });
+        let active = match (char, chord(key)) {
+            (Some(char), _) => {
+                let (cbm, shift) = symbol(char).unwrap_or((KEY_NONE, false));
+                Active {
+                    host: key,
+                    cbm,
+                    modifiers: Some(if shift { MOD_LEFTSHIFT } else { 0 }),
+                }
+            }
+            (None, Some(chord)) => Active {
+                host: key,
+                cbm: chord.key,
+                modifiers: Some(chord.modifiers),
+            },
+            (None, None) => {
+                let cbm = self.layout.keymap().key(key);
+                if cbm != KEY_NONE {
+                    self.positional.press(key);
+                    self.emit_at(KeyEvent::Press(cbm), time, time, out);
+                }
+                return;
+            }
+        };
+        self.activate(active, time, out);
+    }
+
+    /// Presses the CBM key of `active`, after its modifiers are seen.
+    fn activate(&mut self, active: Active, time: Instant, out: &mut Events) {
+        if active.cbm == KEY_NONE {
+            return;
+        }
+
+        let Some(slot) = self.active.iter_mut().find(|slot| slot.is_none()) else {
+            return;
+        };
+        *slot = Some(active);
+        let at = match active.modifiers {
+            Some(_) => self.update_modifiers(time, out),
+            None => time,
+        };
+        self.emit_at(KeyEvent::Press(active.cbm), time, at, out);
+    }
+
+    /// Whether the host key `key` pressed a CBM key.
+    fn is_active(&self, key: u8) -> bool {
+        self.active
+            .iter()
+            .flatten()
+            .any(|active| active.host == key)
+    }
+
+    /// Does what the layers have `key` do, returns whether that's anything.
+    fn press_layer(&mut self, key: u8, time: Instant, out: &mut Events) -> bool {
+        let (cbm, modifiers) = match self.layers.press(key) {
+            Action::Transparent => return false,
+            Action::None | Action::Momentary(_) | Action::Toggle(_) => return true,
+            Action::Key(cbm) => (cbm, None),
+            Action::Chord(chord) => (chord.key, Some(chord.modifiers)),
+        };
+        self.dead = None;
+        self.activate(
+            Active {
+                host: key,
+                cbm,
+                modifiers,
+            },
+            time,
+            out,
+        );
+        true
+    }
+
+    fn release(&mut self, key: u8, time: Instant, out: &mut Events) {
+        if self.positional.is_pressed(key) {
+            self.positional.release(key);
+            let cbm = self.layout.keymap().key(key);
+            self.emit_at(KeyEvent::Release(cbm), time, time, out);
+            return;
+        }
+
+        let Some(index) = self
+            .active
+            .iter()
+            .position(|active| active.is_some_and(|active| active.host == key))
+        else {
+            return;
+        };
+        let active = self.active[index].take();
+        self.active[index..].rotate_left(1);
+
+        if let Some(active) = active {
+            self.emit_at(KeyEvent::Release(active.cbm), time, time, out);
+            if active.modifiers.is_some() {
+                self.update_modifiers(time, out);
+            }
+        }
+    }
+
+    /// Passes on all waiting events.
+    fn flush(&mut self, out: &mut Events) {
+        for event in self.queue.iter_mut().filter_map(Option::take) {
+            out.emit(event);
+        }
+    }
+}
+
+impl Default for Translator {
+    fn default() -> Self {
+        Self::new(
+            Mode::Positional,
+            Some(KEY_SCROLLLOCK),
+            Layers::new(&LAYERS),
+            DEFAULT_SETTLE,
+        )
+    }
+}
+
+/// The host modifier bits of the CBM modifier `key`, the right shift and CTRL count as well.
+fn host_bits(key: u8) -> u8 {
+    match key {
+        KEY_LEFTSHIFT => MOD_LEFTSHIFT | MOD_RIGHTSHIFT,
+        KEY_LEFTCTRL => MOD_LEFTCTRL | MOD_RIGHTCTRL,
+        _ => 1 << (key - KEY_LEFTCTRL),
+    }
+}
+
+/// The CBM key and shift state typing `char`, or a character like it.
+fn symbol(char: char) -> Option<(u8, bool)> {
+    let code = petscii::from_char(char).or_else(|| petscii::from_char(petscii::similar(char)?))?;
+    let ((row, col), shift) = petscii::find(code)?;
+    let cbm = KEYMAP[row][col];
+    (cbm != KEY_NONE).then_some((cbm, shift))
+}
+
+impl Processor for Translator {
+    fn process(&mut self, event: TimedEvent, out: &mut Events) {
+        let key = event.event.key();
+        let pressed = matches!(event.event, KeyEvent::Press(_));
+
+        if Some(key) == self.toggle_key {
+            if pressed {
+                let mode = match self.mode {
+                    Mode::Positional => Mode::Symbolic,
+                    Mode::Symbolic => Mode::Positional,
+                };
+                self.set_mode(mode, event.time, out);
+            }
+            return;
+        }
+
+        // layers go first, even over the modifiers
+        let handled = match pressed {
+            true => self.press_layer(key, event.time, out),
+            false => self.layers.release(key) || self.is_active(key),
+        };
+        if handled {
+            if !pressed {
+                self.release(key, event.time, out);
+            }
+            return;
+        }
+
+        match key {
+            KEY_LEFTCTRL..=KEY_RIGHTMETA => {
+                let bit = 1 << (key - KEY_LEFTCTRL);
+                match pressed {
+                    true => self.host_modifiers |= bit,
+                    false => self.host_modifiers &= !bit,
+                }
+                // the CBM's modifiers also depend on the keys held, AltGr only picks the characters
+                if CBM_MODIFIERS
+                    .iter()
+                    .any(|&(_, cbm)| host_bits(cbm) & bit != 0)
+                {
+                    self.update_modifiers(event.time, out);
+                } else if key != KEY_RIGHTALT || !self.altgr() {
+                    self.emit_at(event.event, event.time, event.time, out);
+                }
+            }
+            _ if pressed => self.press(key, event.time, out),
+            _ => self.release(key, event.time, out),
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.queue.iter().flatten().map(|queued| queued.time).min()
+    }
+
+    fn timeout(&mut self, now: Instant, out: &mut Events) {
+        // the queue is in order, and nothing is queued after a free slot
+        for slot in self.queue.iter_mut() {
+            match slot {
+                Some(queued) if queued.time <= now => out.emit(slot.take().unwrap()),
+                _ => break,
+            }
+        }
+        let passed = self.queue.iter().take_while(|slot| slot.is_none()).count();
+        self.queue.rotate_left(passed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layer::KEYPAD_LAYER,
+        pipeline::fixtures::{at, press, release, run},
+    };
+
+    fn symbolic() -> Translator {
+        Translator::new(Mode::Symbolic, None, Layers::new(&LAYERS), DEFAULT_SETTLE)
+    }
+
+    #[test]
+    fn positional_by_default() {
+        let mut translator = Translator::default();
+        let input = [
+            press(0, KEY_LEFTSHIFT),
+            press(1, KEY_2),
+            release(2, KEY_2),
+            release(3, KEY_LEFTSHIFT),
+        ];
+        assert_eq!(run(&mut translator, &input), input);
+
+        // the letters follow the layout
+        translator.set_layout(HostLayout::German);
+        let input = [press(10, KEY_Y), release(11, KEY_Y)];
+        let passed = run(&mut translator, &input);
+        assert_eq!(passed, [press(10, KEY_Z), release(11, KEY_Z)]);
+    }
+
+    #[test]
+    fn suppresses_shift_for_unshifted_characters() {
+        let mut translator = symbolic();
+        // shift+2 is @, which the CBM has unshifted
+        let input = [
+            press(0, KEY_LEFTSHIFT),
+            press(100, KEY_2),
+            release(150, KEY_2),
+            release(200, KEY_LEFTSHIFT),
+        ];
+        assert_eq!(
+            run(&mut translator, &input),
+            [
+                press(0, KEY_LEFTSHIFT),
+                release(100, KEY_LEFTSHIFT),
+                press(120, KEY_BACKSLASH),
+                release(150, KEY_BACKSLASH),
+                press(150, KEY_LEFTSHIFT),
+                release(200, KEY_LEFTSHIFT),
+            ]
+        );
+    }
+
+    #[test]
+    fn injects_shift_for_shifted_characters() {
+        let mut translator = symbolic();
+        // ' is unshifted on both, " is the CBM's shifted 2
+        let input = [
+            press(0, KEY_APOSTROPHE),
+            release(10, KEY_APOSTROPHE),
+            press(100, KEY_RIGHTSHIFT),
+            press(110, KEY_APOSTROPHE),
+            release(200, KEY_APOSTROPHE),
+            release(210, KEY_RIGHTSHIFT),
+        ];
+        assert_eq!(
+            run(&mut translator, &input),
+            [
+                press(0, KEY_APOSTROPHE),
+                release(10, KEY_APOSTROPHE),
+                press(100, KEY_LEFTSHIFT),
+                press(110, KEY_2),
+                release(200, KEY_2),
+                release(210, KEY_LEFTSHIFT),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_wait_behind_shift_changes() {
+        let mut translator = symbolic();
+        // shift+= is +, on the keypad. The tap and the next key wait for the shift release.
+        let input = [
+            press(0, KEY_LEFTSHIFT),
+            press(100, KEY_EQUAL),
+            release(105, KEY_EQUAL),
+            release(106, KEY_LEFTSHIFT),
+            press(107, KEY_A),
+        ];
+        assert_eq!(
+            run(&mut translator, &input),
+            [
+                press(0, KEY_LEFTSHIFT),
+                release(100, KEY_LEFTSHIFT),
+                press(120, KEY_KPPLUS),
+                release(120, KEY_KPPLUS),
+                // the held shift is back for a moment, no scan sees it
+                press(120, KEY_LEFTSHIFT),
+                release(120, KEY_LEFTSHIFT),
+                press(120, KEY_A),
+            ]
+        );
+    }
+
+    #[test]
+    fn waits_for_a_complete_scan() {
+        let mut translator = symbolic();
+        translator.set_scan_timing(Some(ScanTiming {
+            start: at(0),
+            period: Duration::millis(10),
+            active: Duration::millis(1),
+            dwell: Duration::micros(10),
+            any_key_check: true,
+            columns: 16,
+            order: 0,
+        }));
+
+        // shift goes up during the scan starting at 10 ms, the one at 20 ms is the first to miss it
+        let input = [press(15, KEY_RIGHTSHIFT), press(16, KEY_2)];
+        assert_eq!(
+            run(&mut translator, &input),
+            [
+                press(15, KEY_LEFTSHIFT),
+                release(16, KEY_LEFTSHIFT),
+                press(21, KEY_BACKSLASH),
+            ]
+        );
+    }
+
+    #[test]
+    fn altgr_and_dead_keys() {
+        let mut translator = symbolic();
+        translator.set_layout(HostLayout::German);
+        let input = [
+            press(0, KEY_RIGHTALT),
+            press(1, KEY_Q),
+            release(2, KEY_Q),
+            release(3, KEY_RIGHTALT),
+            // ´ and E
+            press(4, KEY_EQUAL),
+            release(5, KEY_EQUAL),
+            press(6, KEY_E),
+        ];
+        assert_eq!(
+            run(&mut translator, &input),
+            [
+                press(1, KEY_BACKSLASH),
+                release(2, KEY_BACKSLASH),
+                press(6, KEY_E),
+            ]
+        );
+
+        // Right Alt is π again in positional mode
+        translator.set_mode(Mode::Positional, at(10), &mut Events::new());
+        let input = [press(11, KEY_RIGHTALT)];
+        assert_eq!(run(&mut translator, &input), input);
+    }
+
+    #[test]
+    fn control_keys_and_modifiers_stay_positional() {
+        let mut translator = symbolic();
+        let input = [
+            press(0, KEY_LEFTCTRL),
+            press(1, KEY_LEFTSHIFT),
+            press(2, KEY_2),
+            press(3, KEY_ENTER),
+        ];
+        assert_eq!(run(&mut translator, &input), input);
+    }
+
+    #[test]
+    fn chords_override_the_modifiers() {
+        let mut translator = Translator::default();
+        let input = [
+            press(0, KEY_DELETE),
+            release(50, KEY_DELETE),
+            press(100, KEY_RIGHTSHIFT),
+            press(101, KEY_LEFTCTRL),
+            press(150, KEY_PAGEUP),
+            release(200, KEY_PAGEUP),
+        ];
+        assert_eq!(
+            run(&mut translator, &input),
+            [
+                // shift+INST/DEL
+                press(0, KEY_LEFTSHIFT),
+                press(20, KEY_BACKSPACE),
+                release(50, KEY_BACKSPACE),
+                release(50, KEY_LEFTSHIFT),
+                // HOME without the held shift and CTRL
+                press(100, KEY_LEFTSHIFT),
+                press(101, KEY_LEFTCTRL),
+                release(150, KEY_LEFTSHIFT),
+                release(150, KEY_LEFTCTRL),
+                press(170, KEY_HOME),
+                release(200, KEY_HOME),
+                press(200, KEY_LEFTSHIFT),
+                press(200, KEY_LEFTCTRL),
+            ]
+        );
+    }
+
+    #[test]
+    fn layers_come_first() {
+        let mut translator = symbolic();
+        let input = [
+            press(0, KEY_LEFTMETA),
+            press(10, KEY_1),
+            // Fn let go first, F1 stays until 1 is
+            release(20, KEY_LEFTMETA),
+            release(30, KEY_1),
+            press(40, KEY_LEFTMETA),
+            press(50, KEY_BACKSPACE),
+            release(60, KEY_BACKSPACE),
+            press(70, KEY_N),
+            release(80, KEY_N),
+            release(90, KEY_LEFTMETA),
+            // the keypad stays until toggled off
+            press(100, KEY_I),
+            release(110, KEY_I),
+        ];
+        assert_eq!(
+            run(&mut translator, &input),
+            [
+                press(10, KEY_F1),
+                release(30, KEY_F1),
+                // INST
+                press(50, KEY_LEFTSHIFT),
+                press(70, KEY_BACKSPACE),
+                release(70, KEY_BACKSPACE),
+                release(70, KEY_LEFTSHIFT),
+                press(100, KEY_KP5),
+                release(110, KEY_KP5),
+            ]
+        );
+        assert_eq!(translator.layer(), KEYPAD_LAYER);
+    }
+
+    #[test]
+    fn keys_beyond_the_tracked_ones_are_ignored() {
+        let mut translator = symbolic();
+        // the letters type characters, the ninth one is ignored. The function keys stay
+        // positional, any number of them can be held.
+        let letters = KEY_A..=KEY_A + MAX_ACTIVE as u8;
+        let positional = KEY_F1..=KEY_F10;
+        let keys = letters.chain(positional.clone());
+        let input: Vec<_> = keys
+            .clone()
+            .enumerate()
+            .map(|(i, key)| press(i as u64, key))
+            .chain(keys.map(|key| release(20, key)))
+            .collect();
+        let output = run(&mut translator, &input);
+
+        assert_eq!(output.len(), 2 * (MAX_ACTIVE + positional.len()));
+        let mut held = KeyState::new();
+        for event in &output {
+            held.apply(event.event);
+        }
+        assert!(held.is_empty());
+    }
+
+    #[test]
+    fn waiting_keys_stay_in_order() {
+        let mut translator = symbolic();
+        // + waits for the shift release, and more keys wait behind it than fit into the queue
+        let letters = KEY_A..KEY_A + MAX_QUEUED as u8;
+        let mut input = vec![press(0, KEY_LEFTSHIFT), press(100, KEY_EQUAL)];
+        input.push(release(101, KEY_LEFTSHIFT));
+        for key in letters.clone() {
+            input.extend([press(102, key), release(102, key)]);
+        }
+        let output = run(&mut translator, &input);
+
+        let keys: Vec<_> = output.iter().map(|event| event.event).collect();
+        let mut expected = vec![
+            KeyEvent::Press(KEY_LEFTSHIFT),
+            KeyEvent::Release(KEY_LEFTSHIFT),
+            KeyEvent::Press(KEY_KPPLUS),
+        ];
+        expected.extend(letters.flat_map(|key| [KeyEvent::Press(key), KeyEvent::Release(key)]));
+        assert_eq!(keys, expected);
+        assert!(output.windows(2).all(|pair| pair[0].time <= pair[1].time));
+    }
+
+    #[test]
+    fn toggling_releases_everything() {
+        let mut translator = Translator::default();
+        let input = [
+            press(0, KEY_LEFTSHIFT),
+            press(1, KEY_A),
+            press(2, KEY_SCROLLLOCK),
+            release(3, KEY_SCROLLLOCK),
+        ];
+        assert_eq!(
+            run(&mut translator, &input),
+            [
+                press(0, KEY_LEFTSHIFT),
+                press(1, KEY_A),
+                // shift stays down, it's still held
+                release(2, KEY_A),
+            ]
+        );
+        assert_eq!(translator.mode(), Mode::Symbolic);
+
+        // the held A isn't pressed again, and its release goes nowhere
+        assert_eq!(run(&mut translator, &[release(4, KEY_A)]), []);
+    }
+}
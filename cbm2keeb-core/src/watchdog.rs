@@ -0,0 +1,172 @@
+//! Releases keys that are held for longer than a limit, in case a keyboard (or the USB side) never
+//! reports their release. The key's actual release later on is swallowed.
+
+use crate::{
+    key_state::KeyEvent,
+    pipeline::{Duration, Events, Instant, Processor, TimedEvent},
+};
+
+/// Long enough for holding a modifier or letting a key repeat.
+pub const DEFAULT_LIMIT: Duration = Duration::secs(60);
+
+/// Keys watched at the same time, a boot report can't hold more than 6 keys and 8 modifiers.
+const SLOTS: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+struct Held {
+    key: u8,
+    since: Instant,
+    /// Released by the watchdog, waiting for the keyboard to release it too.
+    released: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct StuckKeyWatchdog {
+    limit: Option<Duration>,
+    slots: [Option<Held>; SLOTS],
+    auto_released: u32,
+}
+
+impl StuckKeyWatchdog {
+    /// `None` turns the watchdog off.
+    pub const fn new(limit: Option<Duration>) -> Self {
+        Self {
+            limit,
+            slots: [None; SLOTS],
+            auto_released: 0,
+        }
+    }
+
+    /// Number of keys released by the watchdog since the last call.
+    pub fn take_auto_released(&mut self) -> u32 {
+        core::mem::take(&mut self.auto_released)
+    }
+}
+
+impl Default for StuckKeyWatchdog {
+    fn default() -> Self {
+        Self::new(Some(DEFAULT_LIMIT))
+    }
+}
+
+impl Processor for StuckKeyWatchdog {
+    fn process(&mut self, event: TimedEvent, out: &mut Events) {
+        if self.limit.is_none() {
+            out.emit(event);
+            return;
+        }
+
+        let key = event.event.key();
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|held| held.key == key));
+
+        match (event.event, slot) {
+            (KeyEvent::Press(_), Some(slot)) => {
+                *slot = Some(Held {
+                    key,
+                    since: event.time,
+                    released: false,
+                });
+                out.emit(event);
+            }
+            (KeyEvent::Press(_), None) => {
+                if let Some(free) = self.slots.iter_mut().find(|slot| slot.is_none()) {
+                    *free = Some(Held {
+                        key,
+                        since: event.time,
+                        released: false,
+                    });
+                }
+                out.emit(event);
+            }
+            (KeyEvent::Release(_), Some(slot)) => {
+                if !slot.take().is_some_and(|held| held.released) {
+                    out.emit(event);
+                }
+            }
+            (KeyEvent::Release(_), None) => out.emit(event),
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        let limit = self.limit?;
+        self.slots
+            .iter()
+            .flatten()
+            .filter(|held| !held.released)
+            .map(|held| held.since + limit)
+            .min()
+    }
+
+    fn timeout(&mut self, now: Instant, out: &mut Events) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+
+        for held in self.slots.iter_mut().flatten() {
+            if !held.released && held.since + limit <= now {
+                held.released = true;
+                self.auto_released += 1;
+                out.emit(TimedEvent {
+                    time: now,
+                    event: KeyEvent::Release(held.key),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::*;
+
+    fn event(secs: u64, event: KeyEvent) -> TimedEvent {
+        TimedEvent {
+            time: Instant::from_ticks(secs * 1_000_000),
+            event,
+        }
+    }
+
+    fn process(watchdog: &mut StuckKeyWatchdog, input: TimedEvent) -> Vec<TimedEvent> {
+        let mut out = Events::new();
+        watchdog.process(input, &mut out);
+        out.iter().copied().collect()
+    }
+
+    #[test]
+    fn releases_stuck_keys_once() {
+        let mut watchdog = StuckKeyWatchdog::new(Some(Duration::secs(10)));
+        let press = event(0, KeyEvent::Press(KEY_A));
+        assert_eq!(process(&mut watchdog, press), [press]);
+        assert_eq!(watchdog.deadline(), Some(Instant::from_ticks(10_000_000)));
+
+        let mut out = Events::new();
+        watchdog.timeout(Instant::from_ticks(10_000_000), &mut out);
+        assert_eq!(
+            out.iter().copied().collect::<Vec<_>>(),
+            [event(10, KeyEvent::Release(KEY_A))]
+        );
+        assert_eq!(watchdog.take_auto_released(), 1);
+        assert_eq!(watchdog.deadline(), None);
+
+        // the keyboard's own release doesn't get through a second time, a new press does
+        assert_eq!(
+            process(&mut watchdog, event(12, KeyEvent::Release(KEY_A))),
+            []
+        );
+        let press = event(13, KeyEvent::Press(KEY_A));
+        assert_eq!(process(&mut watchdog, press), [press]);
+    }
+
+    #[test]
+    fn released_keys_are_not_watched() {
+        let mut watchdog = StuckKeyWatchdog::default();
+        process(&mut watchdog, event(0, KeyEvent::Press(KEY_A)));
+        let release = event(1, KeyEvent::Release(KEY_A));
+        assert_eq!(process(&mut watchdog, release), [release]);
+        assert_eq!(watchdog.deadline(), None);
+    }
+}
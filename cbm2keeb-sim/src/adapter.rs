@@ -0,0 +1,120 @@
+//! Model of the firmware: takes reports like `usbctrl_irq`, runs them through the same pipeline,
+//! and answers strobes like the `idle` responder.
+
+use cbm2keeb_core::{
+    device::{Config, Devices},
+    hold::HoldStretcher,
+    layout::HostLayout,
+    pipeline::{Duration, Events, Instant, Pipeline, TimedEvent},
+    responder::{MatrixCell, Responder, ScanSync},
+    scan::{ScanAnalyzer, ScanTiming},
+    shift_lock::ShiftLock,
+    tap_hold::Behavior,
+    translate::Mode,
+};
+
+use crate::script::BootReport;
+
+/// PC0 ... PC5, the bits above are not connected to the CBM
+pub const ROWS_MASK: u8 = 0b11_1111;
+
+/// Address of the simulated keyboard.
+const KEYBOARD_ADDR: u8 = 1;
+
+#[derive(Default)]
+pub struct Adapter {
+    devices: Devices,
+    pipeline: Pipeline,
+    col_enabled_pins: MatrixCell,
+    responder: Responder,
+    scan_analyzer: ScanAnalyzer,
+}
+
+impl Adapter {
+    /// An adapter that only applies updates at scan boundaries, like the firmware's `scan-sync`
+    /// feature.
+    pub fn with_scan_sync() -> Self {
+        Self {
+            responder: Responder::new(Some(ScanSync::new(u32::MAX))),
+            ..Self::default()
+        }
+    }
+
+    /// Replaces the pipeline's minimum hold time, see [`HoldStretcher`]. Zero scans turn it off.
+    pub fn set_min_hold(&mut self, scans: u32, fallback_us: u64) {
+        self.pipeline.hold = HoldStretcher::new(scans, Duration::from_ticks(fallback_us));
+    }
+
+    /// Replaces the pipeline's shift lock key, see [`ShiftLock`]. `None` turns it off.
+    pub fn set_shift_lock(&mut self, key: Option<u8>) {
+        self.pipeline.shift_lock = ShiftLock::new(key);
+    }
+
+    /// Sets the translation mode and the layout of the simulated keyboard, see
+    /// [`Translator`](cbm2keeb_core::translate::Translator). Meant to be called before the first
+    /// report, the keys released by a mode change are dropped.
+    pub fn set_translation(&mut self, mode: Mode, layout: HostLayout) {
+        let config = Config {
+            layout,
+            ..self.devices.config(KEYBOARD_ADDR)
+        };
+        let _ = self.devices.added(KEYBOARD_ADDR, config);
+        let translator = &mut self.pipeline.translator;
+        translator.set_mode(mode, Instant::from_ticks(0), &mut Events::new());
+    }
+
+    /// Sets the tap-hold and one-shot keys of the simulated keyboard, see
+    /// [`TapHold`](cbm2keeb_core::tap_hold::TapHold).
+    pub fn set_behaviors(&mut self, behaviors: &'static [(u8, Behavior)]) {
+        let config = Config {
+            behaviors,
+            ..self.devices.config(KEYBOARD_ADDR)
+        };
+        let _ = self.devices.added(KEYBOARD_ADDR, config);
+    }
+
+    pub fn apply_report(&mut self, time_us: u64, report: &BootReport) {
+        let time = Instant::from_ticks(time_us);
+        // phantom reports keep the last state, like in the firmware
+        let Some(events) = self
+            .devices
+            .report(KEYBOARD_ADDR, report.modifiers, report.keys)
+        else {
+            return;
+        };
+        let events = events.map(|event| TimedEvent { time, event });
+
+        self.pipeline.configure(&self.devices.config(KEYBOARD_ADDR));
+        self.pipeline
+            .set_scan_timing(self.scan_analyzer.timing().copied());
+        self.pipeline.process(events);
+        self.pipeline.output.commit(&self.col_enabled_pins);
+    }
+
+    /// When the pipeline wants [`Adapter::timeout`] to be called next.
+    pub fn deadline_us(&self) -> Option<u64> {
+        self.pipeline.deadline().map(|deadline| deadline.ticks())
+    }
+
+    pub fn timeout(&mut self, time_us: u64) {
+        self.pipeline
+            .set_scan_timing(self.scan_analyzer.timing().copied());
+        self.pipeline.timeout(Instant::from_ticks(time_us));
+        self.pipeline.output.commit(&self.col_enabled_pins);
+    }
+
+    /// Feeds a change of the column strobes to the scan analyzer, like the responder does.
+    pub fn observe_strobes(&mut self, time_us: u64, cols_in: u16) {
+        self.scan_analyzer
+            .observe(Instant::from_ticks(time_us), cols_in);
+    }
+
+    pub fn scan_timing(&self) -> Option<&ScanTiming> {
+        self.scan_analyzer.timing()
+    }
+
+    /// Row outputs for the strobed columns, active low.
+    pub fn rows(&mut self, cols_in: u16) -> u8 {
+        self.responder.respond(&self.col_enabled_pins, cols_in) & ROWS_MASK
+    }
+}
@@ -0,0 +1,82 @@
+//! Model of the CBM-II KERNAL keyboard scan: strobes the columns through TPI2 PA/PB, reads the rows
+//! on PC0 ... PC5 and decodes the pressed key into the keyboard buffer.
+//!
+//! Like the KERNAL, a key produces a single code when it goes down and nothing while held, key
+//! repeat isn't modelled. If several keys are down, the first one found in scan order wins.
+
+use std::collections::VecDeque;
+
+use cbm2keeb_core::{
+    keys::swap_col,
+    petscii::{
+        KeyPos, COMMODORE, COMMODORE_KEY, CTRL, CTRL_KEY, DOUBLE_ZERO_KEY, NORMAL, NO_KEY, SHIFTED,
+        SHIFT_KEY,
+    },
+};
+
+use crate::adapter::ROWS_MASK;
+
+#[derive(Clone, Debug, Default)]
+pub struct Kernal {
+    last_key: Option<KeyPos>,
+    buffer: VecDeque<u8>,
+}
+
+impl Kernal {
+    /// Runs one scan, as done from the KERNAL's interrupt handler. `rows` gets the strobed columns
+    /// (active high, indexed like the adapter's GPIOs) and returns the active low row inputs.
+    pub fn scan(&mut self, mut rows: impl FnMut(u16) -> u8) {
+        if rows(0xFFFF) & ROWS_MASK == ROWS_MASK {
+            self.last_key = None;
+            return;
+        }
+
+        let (mut shift, mut ctrl, mut commodore) = (false, false, false);
+        let mut key = None;
+
+        for gpio_col in 0..16 {
+            let pressed = !rows(1 << gpio_col) & ROWS_MASK;
+            let col = swap_col(gpio_col);
+
+            for row in (0..6).filter(|row| pressed & (1 << row) != 0) {
+                match (row, col) {
+                    SHIFT_KEY => shift = true,
+                    CTRL_KEY => ctrl = true,
+                    COMMODORE_KEY => commodore = true,
+                    pos if NORMAL[row][col] != NO_KEY => {
+                        key = key.or(Some(pos));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if key == self.last_key {
+            return;
+        }
+        self.last_key = key;
+
+        let Some((row, col)) = key else {
+            return;
+        };
+        let table = if ctrl {
+            &CTRL
+        } else if commodore {
+            &COMMODORE
+        } else if shift {
+            &SHIFTED
+        } else {
+            &NORMAL
+        };
+
+        self.buffer.push_back(table[row][col]);
+        if (row, col) == DOUBLE_ZERO_KEY {
+            self.buffer.push_back(table[row][col]);
+        }
+    }
+
+    /// Takes everything from the keyboard buffer.
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        self.buffer.drain(..).collect()
+    }
+}
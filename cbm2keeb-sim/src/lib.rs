@@ -0,0 +1,9 @@
+//! Host-side simulation of the cbm2keeb adapter as seen from the CBM-II: scripted USB reports go
+//! in, column strobes are driven like the KERNAL scan does, and the row outputs are recorded.
+
+pub mod adapter;
+pub mod kernal;
+pub mod script;
+pub mod strobe;
+pub mod trace;
+pub mod vcd;
@@ -0,0 +1,151 @@
+use std::{fs, io, process::ExitCode};
+
+use cbm2keeb_core::{coverage, hold};
+use cbm2keeb_sim::{adapter::Adapter, script, strobe::StrobePattern, trace};
+
+const USAGE: &str = "\
+Usage: cbm2keeb-sim <script> [options]
+       cbm2keeb-sim --coverage
+
+Plays a script of timestamped boot protocol reports into the adapter model, strobes the columns
+like the KERNAL scan and prints what each scan read back. With --coverage, prints which host keys
+reach every CBM-II key instead.
+
+Options:
+  --vcd <file>          also write a VCD waveform of all column and row pins
+  --duration-us <n>     simulated time, defaults to two scan periods after the last report
+  --period-us <n>       time between two scans [default: 20000]
+  --dwell-us <n>        time each column is held low [default: 10]
+  --gap-us <n>          time between two strobes [default: 2]
+  --order <cols>        comma separated strobe order, 0-7 = PA0-PA7, 8-15 = PB0-PB7
+  --no-any-key-check    don't strobe all columns at the start of a scan
+  --scan-sync           only apply updates at the start of a scan
+  --min-hold-scans <n>  complete scans every key state stays visible for, 0 turns it off
+                        [default: 2]";
+
+struct Args {
+    script: String,
+    vcd: Option<String>,
+    duration_us: Option<u64>,
+    pattern: StrobePattern,
+    scan_sync: bool,
+    min_hold_scans: u32,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut script = None;
+    let mut vcd = None;
+    let mut duration_us = None;
+    let mut pattern = StrobePattern::default();
+    let mut scan_sync = false;
+    let mut min_hold_scans = hold::DEFAULT_SCANS;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{name} needs a value"));
+        let number = |name: &str, value: String| {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{name}: invalid number `{value}`"))
+        };
+
+        match arg.as_str() {
+            "--vcd" => vcd = Some(value("--vcd")?),
+            "--duration-us" => duration_us = Some(number(&arg, value(&arg)?)?),
+            "--period-us" => pattern.period_us = number(&arg, value(&arg)?)?,
+            "--dwell-us" => pattern.dwell_us = number(&arg, value(&arg)?)?,
+            "--gap-us" => pattern.gap_us = number(&arg, value(&arg)?)?,
+            "--order" => {
+                pattern.order = value(&arg)?
+                    .split(',')
+                    .map(|col| {
+                        col.trim()
+                            .parse()
+                            .map_err(|_| format!("invalid column `{col}`"))
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "--no-any-key-check" => pattern.any_key_check = false,
+            "--scan-sync" => scan_sync = true,
+            "--min-hold-scans" => {
+                min_hold_scans = number(&arg, value(&arg)?)?
+                    .try_into()
+                    .map_err(|_| "--min-hold-scans: too large".to_string())?;
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+            _ if script.is_none() => script = Some(arg),
+            _ => return Err(format!("unexpected argument `{arg}`")),
+        }
+    }
+
+    pattern.validate()?;
+
+    Ok(Args {
+        script: script.ok_or_else(|| USAGE.to_string())?,
+        vcd,
+        duration_us,
+        pattern,
+        scan_sync,
+        min_hold_scans,
+    })
+}
+
+fn main() -> ExitCode {
+    if std::env::args().skip(1).eq(["--coverage"]) {
+        print!("{}", coverage::Report);
+        return ExitCode::SUCCESS;
+    }
+
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{message}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let script = fs::read_to_string(&args.script)
+        .map_err(|error| format!("reading {}: {error}", args.script))?;
+    let reports = script::parse(&script).map_err(|error| format!("{}: {error}", args.script))?;
+
+    let duration_us = args.duration_us.unwrap_or_else(|| {
+        reports.last().map_or(0, |last| last.time_us) + 2 * args.pattern.period_us
+    });
+    let mut adapter = if args.scan_sync {
+        Adapter::with_scan_sync()
+    } else {
+        Adapter::default()
+    };
+    adapter.set_min_hold(
+        args.min_hold_scans,
+        args.min_hold_scans as u64 * args.pattern.period_us,
+    );
+    let trace = trace::run(&reports, &args.pattern, adapter, duration_us);
+
+    let mut stdout = io::stdout().lock();
+    trace
+        .write_table(&mut stdout)
+        .and_then(|()| trace.write_scan_timing(&mut stdout))
+        .map_err(|error| error.to_string())?;
+
+    if let Some(path) = &args.vcd {
+        let mut file = io::BufWriter::new(
+            fs::File::create(path).map_err(|error| format!("creating {path}: {error}"))?,
+        );
+        trace
+            .write_vcd(&mut file)
+            .map_err(|error| format!("writing {path}: {error}"))?;
+    }
+
+    Ok(())
+}
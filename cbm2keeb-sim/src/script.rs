@@ -0,0 +1,117 @@
+//! Report scripts, one boot protocol report per line:
+//!
+//! ```text
+//! # time_us  modifiers  keys...
+//! 0          00
+//! 20000      02         1f   # shift + 2
+//! 60000      00
+//! ```
+//!
+//! The timestamp is decimal microseconds, modifiers and keys are hex HID usages. Up to six keys
+//! can be listed, `#` starts a comment. Timestamps must not go backwards.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BootReport {
+    pub modifiers: u8,
+    pub keys: [u8; 6],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedReport {
+    pub time_us: u64,
+    pub report: BootReport,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse(script: &str) -> Result<Vec<TimedReport>, ParseError> {
+    let mut reports: Vec<TimedReport> = Vec::new();
+
+    for (idx, line) in script.lines().enumerate() {
+        let error = |message| ParseError {
+            line: idx + 1,
+            message,
+        };
+
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(time) = fields.next() else {
+            continue;
+        };
+
+        let time_us = time.parse().map_err(|_| error("invalid timestamp"))?;
+        if reports.last().is_some_and(|last| last.time_us > time_us) {
+            return Err(error("timestamp goes backwards"));
+        }
+
+        let modifiers = fields.next().ok_or_else(|| error("missing modifiers"))?;
+        let modifiers =
+            u8::from_str_radix(modifiers, 16).map_err(|_| error("invalid modifiers"))?;
+
+        let mut keys = [0; 6];
+        for (slot, key) in fields.enumerate() {
+            let key = u8::from_str_radix(key, 16).map_err(|_| error("invalid key"))?;
+            *keys
+                .get_mut(slot)
+                .ok_or_else(|| error("more than six keys"))? = key;
+        }
+
+        reports.push(TimedReport {
+            time_us,
+            report: BootReport { modifiers, keys },
+        });
+    }
+
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_reports_and_comments() {
+        let reports = parse("# header\n0 00\n\n20000 02 1f 04 # shift + 2 + a\n").unwrap();
+        assert_eq!(
+            reports,
+            [
+                TimedReport {
+                    time_us: 0,
+                    report: BootReport::default(),
+                },
+                TimedReport {
+                    time_us: 20000,
+                    report: BootReport {
+                        modifiers: 0x02,
+                        keys: [0x1f, 0x04, 0, 0, 0, 0],
+                    },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(parse("10 00\n5 00").unwrap_err().line, 2);
+        assert_eq!(parse("10").unwrap_err().message, "missing modifiers");
+        assert_eq!(
+            parse("0 00 04 05 06 07 08 09 0a").unwrap_err().message,
+            "more than six keys"
+        );
+        assert_eq!(parse("0 00 zz").unwrap_err().message, "invalid key");
+    }
+}
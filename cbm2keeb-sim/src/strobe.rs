@@ -0,0 +1,125 @@
+//! Column strobe patterns, modelled on the KERNAL scan: every scan optionally pulls all columns
+//! low at once to check whether any key is down, then pulls TPI2 PA0 ... PA7, PB0 ... PB7 low one
+//! at a time while reading the rows on PC0 ... PC5.
+
+/// Columns are GPIO indices, GPIO0 ... GPIO7 are PA0 ... PA7, GPIO8 ... GPIO15 are PB0 ... PB7.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StrobePattern {
+    /// Time between the start of two scans, the KERNAL scans from its 50/60 Hz interrupt
+    pub period_us: u64,
+    /// How long each strobe holds its columns low
+    pub dwell_us: u64,
+    /// Time with all columns released between two strobes
+    pub gap_us: u64,
+    /// Whether each scan starts by strobing all columns at once
+    pub any_key_check: bool,
+    pub order: Vec<u8>,
+}
+
+impl Default for StrobePattern {
+    fn default() -> Self {
+        Self {
+            period_us: 20_000,
+            dwell_us: 10,
+            gap_us: 2,
+            any_key_check: true,
+            order: (0..16).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StrobeKind {
+    AnyKey,
+    Column(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Strobe {
+    pub scan: u64,
+    pub start_us: u64,
+    pub end_us: u64,
+    pub kind: StrobeKind,
+}
+
+impl Strobe {
+    /// The strobed columns, active high like the firmware sees them after inverting the inputs.
+    pub fn cols(&self) -> u16 {
+        match self.kind {
+            StrobeKind::AnyKey => 0xFFFF,
+            StrobeKind::Column(col) => 1 << col,
+        }
+    }
+
+    /// The point in time at which the scan reads the rows.
+    pub fn sample_us(&self) -> u64 {
+        self.start_us + (self.end_us - self.start_us) / 2
+    }
+}
+
+impl StrobePattern {
+    pub fn strobes_per_scan(&self) -> usize {
+        self.order.len() + usize::from(self.any_key_check)
+    }
+
+    pub fn scan_duration_us(&self) -> u64 {
+        self.strobes_per_scan() as u64 * (self.dwell_us + self.gap_us)
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.dwell_us == 0 {
+            return Err("dwell time must not be zero");
+        }
+        if self.order.iter().any(|&col| col >= 16) {
+            return Err("column index out of range");
+        }
+        if self.scan_duration_us() > self.period_us {
+            return Err("scan does not fit into the scan period");
+        }
+        Ok(())
+    }
+
+    /// All strobes starting before `until_us`, in order.
+    pub fn strobes(&self, until_us: u64) -> impl Iterator<Item = Strobe> + '_ {
+        let any_key = self.any_key_check.then_some(StrobeKind::AnyKey);
+        let kinds = any_key
+            .into_iter()
+            .chain(self.order.iter().map(|&col| StrobeKind::Column(col)));
+        let step = self.dwell_us + self.gap_us;
+
+        (0..)
+            .flat_map(move |scan| {
+                kinds.clone().enumerate().map(move |(idx, kind)| {
+                    let start_us = scan * self.period_us + idx as u64 * step;
+                    Strobe {
+                        scan,
+                        start_us,
+                        end_us: start_us + self.dwell_us,
+                        kind,
+                    }
+                })
+            })
+            .take_while(move |strobe| strobe.start_us < until_us)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_pattern_scans_all_columns() {
+        let pattern = StrobePattern::default();
+        pattern.validate().unwrap();
+
+        let strobes: Vec<_> = pattern.strobes(pattern.period_us).collect();
+        assert_eq!(strobes.len(), 17);
+        assert_eq!(strobes[0].kind, StrobeKind::AnyKey);
+        assert_eq!(strobes[0].cols(), 0xFFFF);
+        assert_eq!(strobes[16].kind, StrobeKind::Column(15));
+        assert_eq!(strobes[16].start_us, 16 * 12);
+
+        let next = pattern.strobes(2 * pattern.period_us).nth(17).unwrap();
+        assert_eq!((next.scan, next.start_us), (1, 20_000));
+    }
+}
@@ -0,0 +1,259 @@
+//! Runs a report script against a strobe pattern and records what the CBM would see.
+
+use std::io::{self, Write};
+
+use cbm2keeb_core::scan::ScanTiming;
+
+use crate::{
+    adapter::{Adapter, ROWS_MASK},
+    script::TimedReport,
+    strobe::{StrobeKind, StrobePattern},
+};
+
+/// Pin levels from a point in time on, both active low as on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub time_us: u64,
+    pub cols: u16,
+    pub rows: u8,
+}
+
+/// What a single scan read back, `None` for strobes that didn't happen in the simulated time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Scan {
+    pub start_us: u64,
+    pub any_key: Option<u8>,
+    pub columns: [Option<u8>; 16],
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub changes: Vec<Change>,
+    pub scans: Vec<Scan>,
+    /// What the adapter's scan analyzer made of the strobes.
+    pub scan_timing: Option<ScanTiming>,
+}
+
+struct Run<'a> {
+    reports: &'a [TimedReport],
+    adapter: Adapter,
+    cols_in: u16,
+    trace: Trace,
+}
+
+impl Run<'_> {
+    fn record(&mut self, time_us: u64) {
+        let change = Change {
+            time_us,
+            cols: !self.cols_in,
+            rows: self.adapter.rows(self.cols_in),
+        };
+
+        match self.trace.changes.last_mut() {
+            Some(last) if last.time_us == time_us => *last = change,
+            Some(last) if (last.cols, last.rows) == (change.cols, change.rows) => {}
+            _ => self.trace.changes.push(change),
+        }
+    }
+
+    /// Applies all reports and pipeline timeouts up to and including `time_us`.
+    fn apply_reports(&mut self, time_us: u64) {
+        loop {
+            let deadline = self
+                .adapter
+                .deadline_us()
+                .filter(|&deadline| deadline <= time_us);
+            let report = self.reports.first().filter(|report| {
+                report.time_us <= time_us && deadline.is_none_or(|d| report.time_us <= d)
+            });
+
+            if let Some(report) = report {
+                self.reports = &self.reports[1..];
+                self.adapter.apply_report(report.time_us, &report.report);
+                self.record(report.time_us);
+            } else if let Some(deadline) = deadline {
+                self.adapter.timeout(deadline);
+                self.record(deadline);
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn set_cols(&mut self, time_us: u64, cols_in: u16) {
+        self.apply_reports(time_us);
+        self.cols_in = cols_in;
+        self.adapter.observe_strobes(time_us, cols_in);
+        self.record(time_us);
+    }
+}
+
+pub fn run(
+    reports: &[TimedReport],
+    pattern: &StrobePattern,
+    adapter: Adapter,
+    duration_us: u64,
+) -> Trace {
+    let mut run = Run {
+        reports,
+        adapter,
+        cols_in: 0,
+        trace: Trace::default(),
+    };
+    run.record(0);
+
+    for strobe in pattern.strobes(duration_us) {
+        if run.trace.scans.len() as u64 == strobe.scan {
+            run.trace.scans.push(Scan {
+                start_us: strobe.start_us,
+                ..Scan::default()
+            });
+        }
+
+        run.set_cols(strobe.start_us, strobe.cols());
+
+        run.apply_reports(strobe.sample_us());
+        let rows = run.adapter.rows(run.cols_in);
+        let scan = run.trace.scans.last_mut().unwrap();
+        match strobe.kind {
+            StrobeKind::AnyKey => scan.any_key = Some(rows),
+            StrobeKind::Column(col) => scan.columns[col as usize] = Some(rows),
+        }
+
+        run.set_cols(strobe.end_us, 0);
+    }
+    run.apply_reports(duration_us);
+
+    run.trace.scan_timing = run.adapter.scan_timing().copied();
+    run.trace
+}
+
+pub const COLUMN_NAMES: [&str; 16] = [
+    "PA0", "PA1", "PA2", "PA3", "PA4", "PA5", "PA6", "PA7", "PB0", "PB1", "PB2", "PB3", "PB4",
+    "PB5", "PB6", "PB7",
+];
+
+impl Trace {
+    /// Writes one line per scan with the rows read for each strobe, `--` if no row was low.
+    pub fn write_table(&self, w: &mut impl Write) -> io::Result<()> {
+        write!(w, "{:>6} {:>10} any", "scan", "time_us")?;
+        for name in COLUMN_NAMES {
+            write!(w, " {name}")?;
+        }
+        writeln!(w)?;
+
+        let fmt_rows = |rows: Option<u8>| match rows {
+            None => String::new(),
+            Some(ROWS_MASK) => "--".to_string(),
+            Some(rows) => format!("{rows:02x}"),
+        };
+
+        for (idx, scan) in self.scans.iter().enumerate() {
+            write!(w, "{idx:>6} {:>10}", scan.start_us)?;
+            write!(w, " {:>3}", fmt_rows(scan.any_key))?;
+            for rows in scan.columns {
+                write!(w, " {:>3}", fmt_rows(rows))?;
+            }
+            writeln!(w)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the scan timing as measured by the adapter.
+    pub fn write_scan_timing(&self, w: &mut impl Write) -> io::Result<()> {
+        let Some(timing) = &self.scan_timing else {
+            return writeln!(w, "scan timing: less than two scans");
+        };
+
+        writeln!(
+            w,
+            "scan timing: every {} us, {} us active, {} columns of up to {} us, any-key check: {}",
+            timing.period.ticks(),
+            timing.active.ticks(),
+            timing.columns,
+            timing.dwell.ticks(),
+            if timing.any_key_check { "yes" } else { "no" },
+        )?;
+        write!(w, "strobe order:")?;
+        for col in timing.order() {
+            write!(w, " {}", COLUMN_NAMES[col as usize])?;
+        }
+        writeln!(w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::BootReport;
+    use cbm2keeb_core::keys::{INVERSE_KEYMAP, KEY_A};
+
+    #[test]
+    fn scans_see_pressed_key() {
+        let reports = [
+            TimedReport {
+                time_us: 30_000,
+                report: BootReport {
+                    modifiers: 0,
+                    keys: [KEY_A, 0, 0, 0, 0, 0],
+                },
+            },
+            TimedReport {
+                time_us: 50_000,
+                report: BootReport::default(),
+            },
+        ];
+        let trace = run(
+            &reports,
+            &StrobePattern::default(),
+            Adapter::default(),
+            100_000,
+        );
+        let (col, row_bits) = INVERSE_KEYMAP[KEY_A as usize];
+
+        // released after one scan, but held for two complete scans
+        assert_eq!(trace.scans.len(), 5);
+        for (idx, scan) in trace.scans.iter().enumerate() {
+            let expected = if idx == 2 || idx == 3 {
+                !row_bits & ROWS_MASK
+            } else {
+                ROWS_MASK
+            };
+            assert_eq!(scan.any_key, Some(expected), "scan {idx}");
+            assert_eq!(scan.columns[col as usize], Some(expected), "scan {idx}");
+        }
+
+        let timing = trace.scan_timing.unwrap();
+        assert_eq!(timing.period.ticks(), 20_000);
+        assert_eq!(timing.dwell.ticks(), 10);
+        assert!(timing.any_key_check);
+
+        assert!(trace
+            .changes
+            .windows(2)
+            .all(|pair| pair[0].time_us < pair[1].time_us));
+    }
+
+    #[test]
+    fn measures_scan_timing() {
+        let pattern = StrobePattern {
+            period_us: 16_667,
+            dwell_us: 7,
+            gap_us: 3,
+            any_key_check: false,
+            order: vec![3, 4, 5, 6, 7, 8, 9, 10],
+        };
+        let trace = run(&[], &pattern, Adapter::default(), 100_000);
+
+        let timing = trace.scan_timing.unwrap();
+        assert_eq!(timing.period.ticks(), 16_667);
+        assert_eq!(
+            timing.active.ticks(),
+            pattern.scan_duration_us() - pattern.gap_us
+        );
+        assert_eq!(timing.dwell.ticks(), 7);
+        assert!(!timing.any_key_check);
+        assert!(timing.order().eq(3..=10));
+    }
+}
@@ -0,0 +1,93 @@
+//! Minimal VCD writer for [`Trace`]s, good enough for GTKWave.
+
+use std::io::{self, Write};
+
+use crate::trace::{Trace, COLUMN_NAMES};
+
+const ROW_NAMES: [&str; 6] = ["PC0", "PC1", "PC2", "PC3", "PC4", "PC5"];
+
+/// Identifier codes, columns first, rows after
+fn id(signal: usize) -> char {
+    (b'A' + signal as u8) as char
+}
+
+impl Trace {
+    pub fn write_vcd(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "$version cbm2keeb-sim $end")?;
+        writeln!(w, "$timescale 1us $end")?;
+        writeln!(w, "$scope module cbm2keeb $end")?;
+        writeln!(w, "$scope module columns $end")?;
+        for (col, name) in COLUMN_NAMES.iter().enumerate() {
+            writeln!(w, "$var wire 1 {} {name}_gpio{col} $end", id(col))?;
+        }
+        writeln!(w, "$upscope $end")?;
+        writeln!(w, "$scope module rows $end")?;
+        for (row, name) in ROW_NAMES.iter().enumerate() {
+            let gpio = row + usize::from(cbm2keeb_core::PINS_OUT_SHIFT);
+            writeln!(w, "$var wire 1 {} {name}_gpio{gpio} $end", id(16 + row))?;
+        }
+        writeln!(w, "$upscope $end")?;
+        writeln!(w, "$upscope $end")?;
+        writeln!(w, "$enddefinitions $end")?;
+
+        let mut last: Option<(u16, u8)> = None;
+        for change in &self.changes {
+            writeln!(w, "#{}", change.time_us)?;
+            if last.is_none() {
+                writeln!(w, "$dumpvars")?;
+            }
+
+            for col in 0..16 {
+                let level = (change.cols >> col) & 1;
+                if last.is_none_or(|(cols, _)| (cols >> col) & 1 != level) {
+                    writeln!(w, "{level}{}", id(col))?;
+                }
+            }
+            for row in 0..ROW_NAMES.len() {
+                let level = (change.rows >> row) & 1;
+                if last.is_none_or(|(_, rows)| (rows >> row) & 1 != level) {
+                    writeln!(w, "{level}{}", id(16 + row))?;
+                }
+            }
+
+            if last.is_none() {
+                writeln!(w, "$end")?;
+            }
+            last = Some((change.cols, change.rows));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trace::{Change, Trace};
+
+    #[test]
+    fn writes_header_and_only_changed_signals() {
+        let trace = Trace {
+            changes: vec![
+                Change {
+                    time_us: 0,
+                    cols: 0xFFFF,
+                    rows: 0x3F,
+                },
+                Change {
+                    time_us: 10,
+                    cols: 0xFFFE,
+                    rows: 0x3D,
+                },
+            ],
+            ..Trace::default()
+        };
+
+        let mut out = Vec::new();
+        trace.write_vcd(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("$var wire 1 A PA0_gpio0 $end"));
+        assert!(out.contains("$var wire 1 Q PC0_gpio16 $end"));
+        assert!(out.ends_with("#10\n0A\n0R\n"));
+    }
+}
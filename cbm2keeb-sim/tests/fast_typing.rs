@@ -0,0 +1,86 @@
+//! Symbolic translation of fast typing end to end: shifted and unshifted symbols typed back to back
+//! go through the adapter model into the KERNAL scan model, at every phase of the KERNAL scan.
+//! The injected shift changes have to stay in order with the keys around them.
+
+use cbm2keeb_core::{keys::*, layout::HostLayout, translate::Mode};
+use cbm2keeb_sim::{adapter::Adapter, kernal::Kernal, script::BootReport};
+
+const SCAN_US: u64 = 20_000;
+
+/// `(modifiers, key)` of `"`, `2`, `@` and `'` on a US keyboard.
+const QUOTE: (u8, u8) = (MOD_LEFTSHIFT, KEY_APOSTROPHE);
+const TWO: (u8, u8) = (0, KEY_2);
+const AT: (u8, u8) = (MOD_LEFTSHIFT, KEY_2);
+const APOSTROPHE: (u8, u8) = (0, KEY_APOSTROPHE);
+
+/// Taps `taps` for `tap_us` each with `gap_us` in between, the modifiers in the same report as
+/// the key. The KERNAL scans every 20 ms from `phase_us` on, the pipeline's timeouts are called
+/// when they're due.
+fn type_taps(taps: &[(u8, u8)], tap_us: u64, gap_us: u64, phase_us: u64) -> Vec<u8> {
+    let mut adapter = Adapter::default();
+    adapter.set_translation(Mode::Symbolic, HostLayout::Us);
+    let mut kernal = Kernal::default();
+
+    let mut reports = Vec::new();
+    for (i, &(modifiers, key)) in (0..).zip(taps) {
+        let start = i * (tap_us + gap_us);
+        let mut report = BootReport {
+            modifiers,
+            ..BootReport::default()
+        };
+        report.keys[0] = key;
+        reports.push((start, report));
+        reports.push((start + tap_us, BootReport::default()));
+    }
+
+    let end = reports.last().map_or(0, |&(time, _)| time) + 1_000_000;
+    let mut reports = reports.into_iter().peekable();
+    for scan in (phase_us..end).step_by(SCAN_US as usize) {
+        // everything up to the scan, in order
+        loop {
+            let report = reports.peek().map(|&(time, _)| time);
+            let deadline = adapter.deadline_us();
+            match (report, deadline) {
+                (Some(time), _) if time <= scan && deadline.is_none_or(|d| time < d) => {
+                    let (time, report) = reports.next().unwrap();
+                    adapter.apply_report(time, &report);
+                }
+                (_, Some(deadline)) if deadline <= scan => adapter.timeout(deadline),
+                _ => break,
+            }
+        }
+        kernal.scan(|cols| adapter.rows(cols));
+    }
+
+    kernal.take_buffer()
+}
+
+/// Types `taps` at every scan phase and checks it comes out as `codes`.
+fn assert_types(taps: &[(u8, u8)], tap_us: u64, gap_us: u64, codes: &[u8]) {
+    for phase_us in (0..SCAN_US).step_by(1_000) {
+        let typed = type_taps(taps, tap_us, gap_us, phase_us);
+        assert_eq!(
+            typed, codes,
+            "{tap_us} µs taps, {gap_us} µs gaps, phase {phase_us} µs"
+        );
+    }
+}
+
+#[test]
+fn shifted_then_unshifted() {
+    assert_types(&[QUOTE, TWO], 10_000, 5_000, &[0x22, 0x32]);
+    assert_types(&[QUOTE, TWO], 20_000, 0, &[0x22, 0x32]);
+}
+
+#[test]
+fn unshifted_then_shifted() {
+    assert_types(&[TWO, QUOTE], 10_000, 5_000, &[0x32, 0x22]);
+    assert_types(&[TWO, QUOTE], 20_000, 0, &[0x32, 0x22]);
+}
+
+#[test]
+fn suppressed_shift_between_symbols() {
+    // @ is unshifted on the CBM, the host's shift is let go of for it
+    let taps = [QUOTE, AT, QUOTE, APOSTROPHE, AT];
+    assert_types(&taps, 10_000, 5_000, &[0x22, 0x40, 0x22, 0x27, 0x40]);
+}
@@ -0,0 +1,167 @@
+//! Symbolic translation end to end: every character of the host layout tables goes through the
+//! adapter model into the KERNAL scan model and has to come out as its PETSCII code.
+
+use cbm2keeb_core::{
+    keys::*,
+    layout::{self, HostLayout, Level, LAYOUTS},
+    petscii,
+    translate::Mode,
+};
+use cbm2keeb_sim::{adapter::Adapter, kernal::Kernal, script::BootReport};
+
+/// Long enough for a shift change to settle.
+const STEP_US: u64 = 20_000;
+
+/// Holds each report for three scans and releases it for two, calling the pipeline's timeouts in
+/// between. The modifiers go down a scan before the keys, like when typing.
+fn type_reports(layout: HostLayout, reports: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut adapter = Adapter::default();
+    adapter.set_translation(Mode::Symbolic, layout);
+    let mut kernal = Kernal::default();
+
+    let mut time = 0;
+
+    for &(modifiers, keys) in reports {
+        let mut report = BootReport {
+            modifiers,
+            ..BootReport::default()
+        };
+        report.keys[..keys.len()].copy_from_slice(keys);
+
+        let modifiers_only = BootReport {
+            modifiers,
+            ..BootReport::default()
+        };
+        adapter.apply_report(time, &modifiers_only);
+        scan(&mut adapter, &mut kernal, &mut time);
+        adapter.apply_report(time, &report);
+        for _ in 0..3 {
+            scan(&mut adapter, &mut kernal, &mut time);
+        }
+        adapter.apply_report(time, &BootReport::default());
+        for _ in 0..2 {
+            scan(&mut adapter, &mut kernal, &mut time);
+        }
+    }
+
+    kernal.take_buffer()
+}
+
+/// Scans at `time` after the timeouts that are due, then advances it.
+fn scan(adapter: &mut Adapter, kernal: &mut Kernal, time: &mut u64) {
+    while let Some(deadline) = adapter.deadline_us().filter(|&deadline| deadline <= *time) {
+        adapter.timeout(deadline);
+    }
+    kernal.scan(|cols| adapter.rows(cols));
+    *time += STEP_US;
+}
+
+fn modifiers(level: Level) -> u8 {
+    match level {
+        Level::Normal => 0,
+        Level::Shifted => MOD_LEFTSHIFT,
+        Level::AltGr => MOD_RIGHTALT,
+    }
+}
+
+/// Against the PETSCII tables, see `characters_type_known_codes` for codes written down by hand.
+#[test]
+fn every_character_types_its_code() {
+    for layout in LAYOUTS {
+        for key in layout.keys() {
+            for level in [Level::Normal, Level::Shifted, Level::AltGr] {
+                let Some(char) = layout.char(key, level) else {
+                    continue;
+                };
+                if layout::is_dead(char) {
+                    continue;
+                }
+                let code = petscii::from_char(char)
+                    .or_else(|| petscii::from_char(petscii::similar(char)?));
+
+                let typed = type_reports(layout, &[(modifiers(level), &[key])]);
+                let expected = code.map(|code| vec![code]).unwrap_or_default();
+                assert_eq!(typed, expected, "{layout:?} {char:?}");
+            }
+        }
+    }
+}
+
+/// Modifiers and keys of a report.
+type Report = (u8, &'static [u8]);
+
+#[test]
+fn characters_type_known_codes() {
+    use HostLayout::*;
+    const SHIFT: u8 = MOD_LEFTSHIFT;
+    const ALTGR: u8 = MOD_RIGHTALT;
+
+    let cases: [(HostLayout, &[Report], &[u8]); 20] = [
+        // a, A, @ and { as [
+        (Us, &[(0, &[KEY_A])], &[0x41]),
+        (Us, &[(SHIFT, &[KEY_A])], &[0xC1]),
+        (Us, &[(SHIFT, &[KEY_2])], &[0x40]),
+        (Us, &[(SHIFT, &[KEY_LEFTBRACE])], &[0x5B]),
+        // ´ and e is é as e, ¨ and space is ", ä as a
+        (
+            UsInternational,
+            &[(0, &[KEY_APOSTROPHE]), (0, &[KEY_E])],
+            &[0x45],
+        ),
+        (
+            UsInternational,
+            &[(SHIFT, &[KEY_APOSTROPHE]), (0, &[KEY_SPACE])],
+            &[0x22],
+        ),
+        (UsInternational, &[(ALTGR, &[KEY_Q])], &[0x41]),
+        // z, ", @, ö as o, ^ and space is ^
+        (German, &[(0, &[KEY_Y])], &[0x5A]),
+        (German, &[(SHIFT, &[KEY_2])], &[0x22]),
+        (German, &[(ALTGR, &[KEY_Q])], &[0x40]),
+        (German, &[(0, &[KEY_SEMICOLON])], &[0x4F]),
+        (German, &[(0, &[KEY_GRAVE]), (0, &[KEY_SPACE])], &[0x5E]),
+        // " and @ swapped from US, # left of Enter
+        (Uk, &[(SHIFT, &[KEY_2])], &[0x22]),
+        (Uk, &[(SHIFT, &[KEY_APOSTROPHE])], &[0x40]),
+        (Uk, &[(0, &[KEY_HASHTILDE])], &[0x23]),
+        // a, & and 1 on the same key, ^ and e is ê as e
+        (French, &[(0, &[KEY_Q])], &[0x41]),
+        (French, &[(0, &[KEY_1]), (SHIFT, &[KEY_1])], &[0x26, 0x31]),
+        (French, &[(0, &[KEY_LEFTBRACE]), (0, &[KEY_E])], &[0x45]),
+        // ä as a, ´ and E is É as E
+        (Swedish, &[(0, &[KEY_APOSTROPHE])], &[0x41]),
+        (Swedish, &[(0, &[KEY_EQUAL]), (SHIFT, &[KEY_E])], &[0xC5]),
+    ];
+    for (layout, reports, codes) in cases {
+        let typed = type_reports(layout, reports);
+        assert_eq!(typed, codes, "{layout:?} {reports:?}");
+    }
+}
+
+#[test]
+fn dead_keys_accent_the_next_character() {
+    let german = HostLayout::German;
+    // ` then space is `, typed as ', ´ then E is É, typed as E
+    assert_eq!(
+        type_reports(german, &[(MOD_LEFTSHIFT, &[KEY_EQUAL]), (0, &[KEY_SPACE])]),
+        [0x27]
+    );
+    assert_eq!(
+        type_reports(german, &[(0, &[KEY_EQUAL]), (MOD_LEFTSHIFT, &[KEY_E])]),
+        [0xC5]
+    );
+
+    let international = HostLayout::UsInternational;
+    // the dead key alone types nothing, and a character without the accent stays as it is
+    assert_eq!(type_reports(international, &[(0, &[KEY_APOSTROPHE])]), []);
+    assert_eq!(
+        type_reports(international, &[(0, &[KEY_APOSTROPHE]), (0, &[KEY_T])]),
+        [0x54]
+    );
+}
+
+#[test]
+fn right_alt_is_pi_without_altgr() {
+    assert_eq!(type_reports(HostLayout::Us, &[(MOD_RIGHTALT, &[])]), [0xDE]);
+    assert_eq!(type_reports(HostLayout::German, &[(MOD_RIGHTALT, &[])]), []);
+}
@@ -0,0 +1,123 @@
+//! End to end checks: HID reports go through the adapter model into the KERNAL scan model, and the
+//! keyboard buffer has to contain what the CBM-II would print.
+
+use cbm2keeb_core::{
+    keys::*,
+    petscii::{self, COMMODORE_KEY, CTRL_KEY, DOUBLE_ZERO_KEY, SHIFT_KEY},
+};
+use cbm2keeb_sim::{adapter::Adapter, kernal::Kernal, script::BootReport};
+
+/// Holds the keys for two scans, then releases them for one. The scans are 20 ms apart.
+fn type_keys(modifiers: u8, keys: &[u8]) -> Vec<u8> {
+    let mut report = BootReport {
+        modifiers,
+        ..BootReport::default()
+    };
+    report.keys[..keys.len()].copy_from_slice(keys);
+
+    let mut adapter = Adapter::default();
+    let mut kernal = Kernal::default();
+
+    adapter.apply_report(0, &report);
+    kernal.scan(|cols| adapter.rows(cols));
+    timeouts(&mut adapter, 20_000);
+    kernal.scan(|cols| adapter.rows(cols));
+    adapter.apply_report(40_000, &BootReport::default());
+    timeouts(&mut adapter, 40_000);
+    kernal.scan(|cols| adapter.rows(cols));
+
+    kernal.take_buffer()
+}
+
+/// Calls the pipeline's timeouts up to `time_us`.
+fn timeouts(adapter: &mut Adapter, time_us: u64) {
+    while let Some(deadline) = adapter
+        .deadline_us()
+        .filter(|&deadline| deadline <= time_us)
+    {
+        adapter.timeout(deadline);
+    }
+}
+
+#[test]
+fn digits() {
+    assert_eq!(type_keys(0, &[KEY_2]), b"2");
+    assert_eq!(type_keys(MOD_LEFTSHIFT, &[KEY_2]), b"\"");
+    assert_eq!(type_keys(MOD_RIGHTSHIFT, &[KEY_8]), b"(");
+    assert_eq!(type_keys(0, &[KEY_6]), b"6");
+}
+
+#[test]
+fn letters_and_control_codes() {
+    assert_eq!(type_keys(0, &[KEY_A]), [0x41]);
+    assert_eq!(type_keys(MOD_LEFTSHIFT, &[KEY_A]), [0xC1]);
+    assert_eq!(type_keys(MOD_LEFTCTRL, &[KEY_A]), [0x01]);
+    assert_eq!(type_keys(MOD_RIGHTCTRL | MOD_LEFTSHIFT, &[KEY_Z]), [0x1A]);
+    assert_eq!(type_keys(MOD_RIGHTMETA, &[KEY_A]), [0xA1]);
+}
+
+#[test]
+fn pi_from_right_alt() {
+    assert_eq!(type_keys(MOD_RIGHTALT, &[]), [0xDE]);
+}
+
+#[test]
+fn editing_keys() {
+    assert_eq!(type_keys(0, &[KEY_ENTER]), [0x0D]);
+    assert_eq!(type_keys(0, &[KEY_BACKSPACE]), [0x14]);
+    assert_eq!(type_keys(MOD_LEFTSHIFT, &[KEY_BACKSPACE]), [0x94]);
+    assert_eq!(type_keys(0, &[KEY_HOME]), [0x13]);
+    assert_eq!(type_keys(MOD_LEFTSHIFT, &[KEY_HOME]), [0x93]);
+    assert_eq!(type_keys(0, &[KEY_PAUSE]), [0x03]);
+    assert_eq!(type_keys(0, &[KEY_SPACE]), [0x20]);
+}
+
+#[test]
+fn chords() {
+    assert_eq!(type_keys(0, &[KEY_DELETE]), [0x94]);
+    assert_eq!(type_keys(0, &[KEY_END]), [0x93]);
+    assert_eq!(type_keys(MOD_LEFTSHIFT, &[KEY_PAGEUP]), [0x13]);
+    assert_eq!(type_keys(MOD_LEFTCTRL, &[KEY_PAGEDOWN]), [0x8D]);
+}
+
+#[test]
+fn holding_a_key_produces_one_code() {
+    assert_eq!(type_keys(0, &[KEY_Q]).len(), 1);
+}
+
+#[test]
+fn nothing_pressed_produces_nothing() {
+    assert_eq!(type_keys(0, &[]), []);
+    assert_eq!(type_keys(MOD_LEFTSHIFT, &[]), []);
+}
+
+#[test]
+fn whole_keymap_decodes_through_the_tables() {
+    for (row, keys) in KEYMAP.iter().enumerate() {
+        for (col, &key) in keys.iter().enumerate() {
+            let pos = (row, col);
+            if key == KEY_NONE || [SHIFT_KEY, CTRL_KEY, COMMODORE_KEY].contains(&pos) {
+                continue;
+            }
+
+            let repeat = if pos == DOUBLE_ZERO_KEY { 2 } else { 1 };
+            let expect = |code: u8| vec![code; repeat];
+
+            assert_eq!(
+                type_keys(0, &[key]),
+                expect(petscii::NORMAL[row][col]),
+                "{pos:?} unshifted"
+            );
+            assert_eq!(
+                type_keys(0, &[key, KEY_LEFTSHIFT]),
+                expect(petscii::SHIFTED[row][col]),
+                "{pos:?} shifted"
+            );
+            assert_eq!(
+                type_keys(MOD_LEFTCTRL, &[key]),
+                expect(petscii::CTRL[row][col]),
+                "{pos:?} with ctrl"
+            );
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Tap-hold keys end to end: the Caps Lock of the compact profile goes through the adapter model
+//! into the KERNAL scan model, as CTRL when held and as the shift lock key when tapped.
+
+use cbm2keeb_core::{keys::*, profile::PROFILES};
+use cbm2keeb_sim::{adapter::Adapter, kernal::Kernal, script::BootReport};
+
+const SCAN_US: u64 = 20_000;
+
+/// Applies `reports`, `(time, keys)`, scanning every 20 ms until a second after the last one.
+fn type_reports(reports: &[(u64, &[u8])]) -> Vec<u8> {
+    let profile = PROFILES
+        .iter()
+        .find(|profile| profile.name == "Compact 60%")
+        .unwrap();
+    let mut adapter = Adapter::default();
+    adapter.set_behaviors(profile.behaviors);
+    let mut kernal = Kernal::default();
+
+    let end = reports.last().map_or(0, |&(time, _)| time) + 1_000_000;
+    let mut reports = reports.iter().peekable();
+    for time in (0..end).step_by(SCAN_US as usize) {
+        while let Some((_, keys)) = reports.next_if(|&&(report_time, _)| report_time <= time) {
+            let mut report = BootReport::default();
+            report.keys[..keys.len()].copy_from_slice(keys);
+            adapter.apply_report(time, &report);
+        }
+        while let Some(deadline) = adapter.deadline_us().filter(|&deadline| deadline <= time) {
+            adapter.timeout(deadline);
+        }
+        kernal.scan(|cols| adapter.rows(cols));
+    }
+
+    kernal.take_buffer()
+}
+
+#[test]
+fn held_caps_lock_is_ctrl() {
+    let reports: [(u64, &[u8]); 4] = [
+        (0, &[KEY_CAPSLOCK]),
+        (40_000, &[KEY_CAPSLOCK, KEY_C]),
+        (80_000, &[KEY_CAPSLOCK]),
+        (120_000, &[]),
+    ];
+    assert_eq!(type_reports(&reports), [0x03]);
+}
+
+#[test]
+fn tapped_caps_lock_latches_shift() {
+    let reports: [(u64, &[u8]); 4] = [
+        (0, &[KEY_CAPSLOCK]),
+        (40_000, &[]),
+        (100_000, &[KEY_A]),
+        (160_000, &[]),
+    ];
+    assert_eq!(type_reports(&reports), [0xC1]);
+}
+
+#[test]
+fn rolling_over_types_both_keys() {
+    let reports: [(u64, &[u8]); 4] = [
+        (0, &[KEY_CAPSLOCK]),
+        (40_000, &[KEY_CAPSLOCK, KEY_A]),
+        (80_000, &[KEY_A]),
+        (120_000, &[]),
+    ];
+    // the latched shift shifts the A
+    assert_eq!(type_reports(&reports), [0xC1]);
+}
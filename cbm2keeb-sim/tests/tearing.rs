@@ -0,0 +1,94 @@
+//! A report arriving in the middle of a KERNAL scan must not be visible to only part of that scan
+//! when the adapter syncs its updates to the scan.
+
+use cbm2keeb_core::{keys::*, matrix};
+use cbm2keeb_sim::{
+    adapter::{Adapter, ROWS_MASK},
+    script::{BootReport, TimedReport},
+    strobe::StrobePattern,
+    trace::{self, Scan},
+};
+
+fn report(time_us: u64, modifiers: u8, keys: &[u8]) -> TimedReport {
+    let mut report = BootReport {
+        modifiers,
+        ..BootReport::default()
+    };
+    report.keys[..keys.len()].copy_from_slice(keys);
+    TimedReport { time_us, report }
+}
+
+/// What a whole scan reads with `keys` held
+fn expected_columns(keys: &[u8]) -> [Option<u8>; 16] {
+    let col_bits = matrix::col_bits_from_keys(keys.iter().copied());
+    core::array::from_fn(|col| Some(matrix::row_response(1 << col, col_bits) & ROWS_MASK))
+}
+
+/// Alternates between shift + `A` and `L` in the middle of every scan. `L` is on PA0, shift and
+/// `A` on PB0 and PB1, so the two halves of a scan see different states unless updates wait.
+fn mid_scan_reports(pattern: &StrobePattern) -> Vec<TimedReport> {
+    let mid_scan = pattern.scan_duration_us() / 2;
+    (0..8)
+        .map(|scan| {
+            let time_us = scan * pattern.period_us + mid_scan;
+            if scan % 2 == 0 {
+                report(time_us, MOD_LEFTSHIFT, &[KEY_A])
+            } else {
+                report(time_us, 0, &[KEY_L])
+            }
+        })
+        .collect()
+}
+
+fn is_consistent(scan: &Scan, states: &[&[u8]]) -> bool {
+    states
+        .iter()
+        .any(|keys| scan.columns == expected_columns(keys))
+}
+
+#[test]
+fn immediate_updates_tear_scans() {
+    let pattern = StrobePattern::default();
+    let reports = mid_scan_reports(&pattern);
+    // keys change every scan, which the minimum hold time would stretch
+    let mut adapter = Adapter::default();
+    adapter.set_min_hold(0, 0);
+    let trace = trace::run(&reports, &pattern, adapter, 8 * pattern.period_us);
+
+    let torn = trace
+        .scans
+        .iter()
+        .filter(|scan| !is_consistent(scan, &[&[], &[KEY_LEFTSHIFT, KEY_A], &[KEY_L]]))
+        .count();
+    assert!(torn > 0);
+}
+
+#[test]
+fn scan_synced_updates_never_tear() {
+    for any_key_check in [true, false] {
+        let pattern = StrobePattern {
+            any_key_check,
+            ..StrobePattern::default()
+        };
+        let reports = mid_scan_reports(&pattern);
+        // keys change every scan, which the minimum hold time would stretch
+        let mut adapter = Adapter::with_scan_sync();
+        adapter.set_min_hold(0, 0);
+        let trace = trace::run(&reports, &pattern, adapter, 8 * pattern.period_us);
+
+        assert_eq!(trace.scans.len(), 8);
+        for (idx, scan) in trace.scans.iter().enumerate() {
+            // each report shows up in full in the scan after the one it arrived in
+            let expected: &[u8] = match idx {
+                0 => &[],
+                _ if idx % 2 == 1 => &[KEY_LEFTSHIFT, KEY_A],
+                _ => &[KEY_L],
+            };
+            assert_eq!(
+                scan.columns,
+                expected_columns(expected),
+                "scan {idx}, any-key check: {any_key_check}"
+            );
+        }
+    }
+}
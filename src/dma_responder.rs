@@ -0,0 +1,278 @@
+//! Matrix responder that runs without the CPU.
+//!
+//! PIO0 SM0 samples GPIO0..15 and pushes `table base | index` as a single word. The index is
+//! either the raw columns into a 64K-entry table, or with the `pio-responder` feature, a column
+//! index decoded by SM0 into a table with an entry per column. The latter only needs 64 bytes of
+//! RAM and is updated in microseconds instead of milliseconds.
+//!
+//! DMA channel 0 copies that word into the read address trigger of channel 1, which copies the
+//! table entry into PIO0 SM1, which drives the rows on GPIO16..21. Channel 1 chains back to
+//! channel 0. The response latency is fixed by the sample rate and the DMA round trip, interrupts
+//! don't affect it.
+//!
+//! There are two tables: updates are written into the inactive one, then SM0 is switched over to
+//! it. The CPU only has to watch the [`MatrixCell`] (and, with scan sync, the column strobes).
+
+use cbm2keeb_core::{
+    responder::{MatrixCell, ScanSync},
+    PINS_OUT_SHIFT,
+};
+use portable_atomic::{AtomicBool, Ordering};
+use rp_pico::hal::{
+    pac,
+    pio::{
+        PIOBuilder, PIOExt, PinDir, PinState, Running, ShiftDirection, StateMachine, Tx, PIO0SM0,
+    },
+};
+
+use table::{ResponseTables, TABLE_LEN};
+
+const DREQ_PIO0_TX1: u8 = 1;
+const DREQ_PIO0_RX0: u8 = 4;
+
+/// 64K entries, indexed by the raw column inputs.
+#[cfg(not(feature = "pio-responder"))]
+mod table {
+    use cbm2keeb_core::matrix::{fill_response_table, ColumnBits};
+
+    pub const TABLE_LEN: usize = 0x10000;
+    pub const INDEX_BITS: u32 = 16;
+
+    /// SM0 runs a 5 instruction loop, slowed down so a sample takes longer than the DMA round
+    /// trip. Otherwise stale samples would queue up in the RX FIFO.
+    pub const SAMPLE_CLOCK_DIVISOR: u16 = 4;
+    /// Longest time between two samples, in system clocks.
+    pub const SAMPLE_CLOCKS: u32 = 5 * SAMPLE_CLOCK_DIVISOR as u32;
+
+    /// Aligned so a table's address is its upper 16 bits.
+    #[repr(C, align(65536))]
+    pub struct ResponseTables(pub [[u8; TABLE_LEN]; 2]);
+
+    pub fn fill(table: &mut [u8; TABLE_LEN], col_bits: ColumnBits) {
+        fill_response_table(table, col_bits);
+    }
+
+    /// Pushes `base << 16 | raw columns`. `pull noblock` keeps the old base in X unless a new one
+    /// was written.
+    pub fn sample_program() -> pio::Program<32> {
+        pio_proc::pio_asm!(
+            ".wrap_target",
+            "    pull noblock",
+            "    mov x, osr",
+            "    in x, 16",
+            "    in pins, 16",
+            "    push",
+            ".wrap",
+        )
+        .program
+    }
+}
+
+/// One entry per column, the strobes are decoded into a column index by SM0.
+#[cfg(feature = "pio-responder")]
+mod table {
+    use cbm2keeb_core::matrix::{fill_column_table, ColumnBits};
+
+    pub const TABLE_LEN: usize = 32;
+    pub const INDEX_BITS: u32 = 5;
+
+    /// The shortest loop (the any-key check) already takes longer than the DMA round trip.
+    pub const SAMPLE_CLOCK_DIVISOR: u16 = 1;
+    /// Longest time between two samples, in system clocks: all 16 columns tested.
+    pub const SAMPLE_CLOCKS: u32 = 52;
+
+    /// Aligned so a table's address is its upper 27 bits.
+    #[repr(C, align(32))]
+    pub struct ResponseTables(pub [[u8; TABLE_LEN]; 2]);
+
+    pub fn fill(table: &mut [u8; TABLE_LEN], col_bits: ColumnBits) {
+        fill_column_table(table, col_bits);
+    }
+
+    /// Pushes `base << 5 | column index`, with the index as used by [`fill_column_table`]. If
+    /// several (but not all) columns are strobed, the highest one is answered.
+    ///
+    /// The columns are reversed into the OSR and tested one by one with `out pc, 1`, which jumps
+    /// to address 0 for a strobed (low) column, and to address 1 otherwise. Y counts down the
+    /// column index, and wraps to 31 (`COLUMN_TABLE_NONE`) if no column is strobed.
+    pub fn sample_program() -> pio::Program<32> {
+        pio_proc::pio_asm!(
+            ".origin 0",
+            "    jmp found",
+            "    jmp y-- test",
+            "    jmp found",
+            ".wrap_target",
+            "    pull noblock",
+            "    mov x, osr",
+            "    in pins, 16",
+            "    mov y, isr",
+            "    jmp !y any_key",
+            "    mov osr, ::isr",
+            "    set y, 15",
+            "    out null, 16",
+            "test:",
+            "    out pc, 1",
+            "any_key:",
+            "    set y, 16",
+            "found:",
+            "    mov isr, null",
+            "    in x, 27",
+            "    in y, 5",
+            // keeps the RX FIFO from filling up with stale samples
+            "    push [7]",
+            ".wrap",
+        )
+        .program
+    }
+}
+
+// zeroed, so this ends up in .bss instead of being copied from flash
+static mut RESPONSE_TABLES: ResponseTables = ResponseTables([[0; TABLE_LEN]; 2]);
+static RESPONSE_TABLES_TAKEN: AtomicBool = AtomicBool::new(false);
+
+pub struct DmaResponder {
+    tables: &'static mut ResponseTables,
+    sample_tx: Tx<PIO0SM0>,
+    _sample_sm: StateMachine<PIO0SM0, Running>,
+    active: usize,
+    active_version: u32,
+    /// Version in the inactive table, waiting for the next scan boundary.
+    pending_version: Option<u32>,
+    scan_sync: Option<ScanSync>,
+}
+
+impl DmaResponder {
+    /// Sets up PIO0 and DMA channels 0 and 1 and starts responding. GPIO16..21 must be set to the
+    /// PIO0 function. Panics if called more than once.
+    pub fn new(
+        pio0: pac::PIO0,
+        dma: pac::DMA,
+        resets: &mut pac::RESETS,
+        scan_sync: Option<ScanSync>,
+    ) -> Self {
+        assert!(!RESPONSE_TABLES_TAKEN.swap(true, Ordering::Relaxed));
+        // SAFETY: checked above that this is the only reference
+        let tables = unsafe { &mut *core::ptr::addr_of_mut!(RESPONSE_TABLES) };
+        for table in &mut tables.0 {
+            table::fill(table, [0; 4]);
+        }
+
+        let (mut pio, sm0, sm1, _, _) = pio0.split(resets);
+
+        let (sample_sm, mut rx, mut sample_tx) =
+            PIOBuilder::from_installed_program(pio.install(&table::sample_program()).unwrap())
+                .in_pin_base(0)
+                .in_shift_direction(ShiftDirection::Left)
+                .out_shift_direction(ShiftDirection::Right)
+                .clock_divisor_fixed_point(table::SAMPLE_CLOCK_DIVISOR, 0)
+                .build(sm0);
+
+        let drive = pio_proc::pio_asm!(
+            ".wrap_target",
+            "    out pins, 6",
+            "    out null, 2",
+            ".wrap",
+        );
+        let (mut drive_sm, _, drive_tx) =
+            PIOBuilder::from_installed_program(pio.install(&drive.program).unwrap())
+                .out_pins(PINS_OUT_SHIFT, 6)
+                .out_shift_direction(ShiftDirection::Right)
+                .autopull(true)
+                .pull_threshold(8)
+                .build(sm1);
+        let rows = PINS_OUT_SHIFT..PINS_OUT_SHIFT + 6;
+        drive_sm.set_pins(rows.clone().map(|pin| (pin, PinState::High)));
+        drive_sm.set_pindirs(rows.map(|pin| (pin, PinDir::Output)));
+
+        drive_sm.start();
+        sample_tx.write(table_base(&tables.0[0]));
+        let sample_sm = sample_sm.start();
+        // the first sample can be from before the base was pulled
+        while rx.read().is_none() {}
+
+        resets.reset().modify(|_, w| w.dma().clear_bit());
+        while resets.reset_done().read().dma().bit_is_clear() {}
+
+        let (lookup, fetch) = (dma.ch(0), dma.ch(1));
+        // channel 1: table entry -> SM1, started by channel 0 writing its read address
+        fetch
+            .ch_write_addr()
+            .write(|w| unsafe { w.bits(drive_tx.fifo_address() as u32) });
+        fetch.ch_trans_count().write(|w| unsafe { w.bits(1) });
+        fetch.ch_al1_ctrl().write(|w| unsafe {
+            w.en().set_bit();
+            w.high_priority().set_bit();
+            w.data_size().size_byte();
+            w.incr_read().clear_bit();
+            w.incr_write().clear_bit();
+            w.treq_sel().bits(DREQ_PIO0_TX1);
+            w.chain_to().bits(0)
+        });
+        // channel 0: sample -> channel 1 read address
+        lookup
+            .ch_read_addr()
+            .write(|w| unsafe { w.bits(rx.fifo_address() as u32) });
+        lookup
+            .ch_write_addr()
+            .write(|w| unsafe { w.bits(fetch.ch_al3_read_addr_trig().as_ptr() as u32) });
+        lookup.ch_trans_count().write(|w| unsafe { w.bits(1) });
+        lookup.ch_ctrl_trig().write(|w| unsafe {
+            w.en().set_bit();
+            w.high_priority().set_bit();
+            w.data_size().size_word();
+            w.incr_read().clear_bit();
+            w.incr_write().clear_bit();
+            w.treq_sel().bits(DREQ_PIO0_RX0);
+            // chaining to itself disables chaining
+            w.chain_to().bits(0)
+        });
+
+        Self {
+            tables,
+            sample_tx,
+            _sample_sm: sample_sm,
+            active: 0,
+            active_version: 0,
+            pending_version: None,
+            scan_sync,
+        }
+    }
+
+    /// Picks up new states from the [`MatrixCell`], call this in a loop with the current column
+    /// strobes (active high). The DMA keeps answering from the active table while the other one
+    /// is filled.
+    #[inline(always)]
+    pub fn update(&mut self, cell: &MatrixCell, cols_in: u16) {
+        let may_update = match &mut self.scan_sync {
+            Some(scan_sync) => scan_sync.observe(cols_in),
+            None => true,
+        };
+        if let Some(version) = self.pending_version.filter(|_| may_update) {
+            self.activate(1 - self.active, version);
+        }
+
+        let version = cell.version();
+        if version != self.pending_version.unwrap_or(self.active_version) {
+            let (version, col_bits) = cell.load();
+            table::fill(&mut self.tables.0[1 - self.active], col_bits);
+            self.pending_version = Some(version);
+        }
+    }
+
+    fn activate(&mut self, index: usize, version: u32) {
+        self.sample_tx.write(table_base(&self.tables.0[index]));
+        // SM0 picks the base up at its next sample. Samples with the old base can still be in the
+        // RX FIFO (up to 4) or in flight, the old table must stay intact until they're answered.
+        while !self.sample_tx.is_empty() {}
+        cortex_m::asm::delay(5 * table::SAMPLE_CLOCKS);
+
+        self.active = index;
+        self.active_version = version;
+        self.pending_version = None;
+    }
+}
+
+/// The table address without the index bits, as shifted into the samples by SM0.
+fn table_base(table: &[u8; TABLE_LEN]) -> u32 {
+    table.as_ptr() as u32 >> table::INDEX_BITS
+}
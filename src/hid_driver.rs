@@ -0,0 +1,634 @@
+//! usbh driver for HID keyboards, in boot or report protocol.
+//!
+//! Every HID interface of a device is set up: its report descriptor is read and parsed, so NKRO
+//! bitmaps, report IDs and any report size work. Interfaces whose descriptor can't be parsed or
+//! has no keyboard usages fall back to the boot protocol if they support it. The keys of all
+//! interfaces of a device are merged, as keyboards often send modifiers and some keys on one
+//! interface and the rest on another. Within an interface, each report ID keeps its own keys.
+//!
+//! The LEDs set with [`HidDriver::set_leds`] are sent to every device with LEDs in an output report,
+//! on the first interface that has them.
+//!
+//! The vendor and product ID, the product string and the country code of the first HID interface
+//! that has one are passed on with [`HidEvent::DeviceAdded`], to pick a
+//! [`profile`](cbm2keeb_core::profile) and [`layout`](cbm2keeb_core::layout) for the device.
+
+use cbm2keeb_core::{
+    hid::{InputKeys, ReportDescriptor, MAX_OUTPUT_REPORT},
+    key_state::KeyState,
+    profile::DeviceInfo,
+};
+use usb_device::{
+    control::{Recipient, RequestType},
+    UsbDirection, UsbError,
+};
+use usbh::{
+    bus::HostBus,
+    driver::Driver,
+    types::{ConnectionSpeed, DeviceAddress, SetupPacket},
+    PipeId, UsbHost,
+};
+
+const MAX_DEVICES: usize = cbm2keeb_core::device::MAX_DEVICES;
+const MAX_INTERFACES: usize = 3;
+/// Room for a removal, an addition and a key state per device, plus removals of devices whose
+/// address was reused, see [`EventQueue`].
+const MAX_EVENTS: usize = 4 * MAX_DEVICES;
+
+const DESCRIPTOR_DEVICE: u8 = 0x01;
+const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_STRING: u8 = 0x03;
+const DESCRIPTOR_INTERFACE: u8 = 0x04;
+const DESCRIPTOR_ENDPOINT: u8 = 0x05;
+const DESCRIPTOR_HID: u8 = 0x21;
+const DESCRIPTOR_REPORT: u8 = 0x22;
+
+const CLASS_HID: u8 = 0x03;
+const SUBCLASS_BOOT: u8 = 0x01;
+
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+const REQUEST_SET_REPORT: u8 = 0x09;
+const REQUEST_SET_IDLE: u8 = 0x0A;
+const REQUEST_SET_PROTOCOL: u8 = 0x0B;
+/// US English, which any device with strings supports.
+const LANGUAGE_ID: u16 = 0x0409;
+/// Room for the product string characters that are kept.
+const PRODUCT_STRING_LEN: u16 = 2 + 2 * cbm2keeb_core::profile::MAX_PRODUCT as u16;
+const PROTOCOL_BOOT: u16 = 0;
+const PROTOCOL_REPORT: u16 = 1;
+const REPORT_OUTPUT: u16 = 0x02;
+
+#[derive(Clone, Copy, Debug)]
+pub enum HidEvent {
+    DeviceAdded(u8, DeviceInfo),
+    DeviceRemoved(u8),
+    /// The keys pressed on all interfaces of the device.
+    InputChanged(u8, KeyState),
+}
+
+impl HidEvent {
+    fn addr(&self) -> u8 {
+        match *self {
+            HidEvent::DeviceAdded(addr, _)
+            | HidEvent::DeviceRemoved(addr)
+            | HidEvent::InputChanged(addr, _) => addr,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    /// Waiting for the configuration.
+    Enumerating,
+    ProductString,
+    ReportDescriptor(usize),
+    Protocol(usize),
+    Idle(usize),
+    Running,
+}
+
+impl Step {
+    /// The step after this one: the product string, then the report descriptor, protocol and idle
+    /// rate of each interface in turn.
+    fn next(self) -> Self {
+        match self {
+            Step::Enumerating => Step::ProductString,
+            Step::ProductString => Step::ReportDescriptor(0),
+            Step::ReportDescriptor(i) => Step::Protocol(i),
+            Step::Protocol(i) => Step::Idle(i),
+            Step::Idle(i) if i + 1 < MAX_INTERFACES => Step::ReportDescriptor(i + 1),
+            Step::Idle(_) | Step::Running => Step::Running,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Endpoint {
+    number: u8,
+    size: u16,
+    interval: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Interface {
+    number: u8,
+    /// Supports the boot protocol.
+    boot: bool,
+    report_descriptor_len: u16,
+    endpoint: Option<Endpoint>,
+    /// `None` if the interface has no keys.
+    layout: Option<ReportDescriptor>,
+    /// Uses the boot protocol, as its report descriptor couldn't be used.
+    boot_protocol: bool,
+    pipe: Option<PipeId>,
+    keys: InputKeys,
+}
+
+impl Interface {
+    /// The report descriptor couldn't be read or has no keys.
+    fn fall_back_to_boot(&mut self) {
+        if self.boot {
+            self.layout = Some(ReportDescriptor::boot_keyboard());
+            self.boot_protocol = true;
+        }
+    }
+}
+
+struct HidDevice {
+    addr: DeviceAddress,
+    info: DeviceInfo,
+    /// Index of the product string, 0 if there's none.
+    product_string: u8,
+    config: Option<u8>,
+    control_pipe: Option<PipeId>,
+    interfaces: [Option<Interface>; MAX_INTERFACES],
+    step: Step,
+    /// A request of the current step was sent and hasn't completed yet.
+    in_flight: bool,
+    /// The LEDs the device shows, `None` until they were set once.
+    leds: Option<u8>,
+    /// LEDs sent in an output report that hasn't completed yet.
+    leds_in_flight: Option<u8>,
+}
+
+impl HidDevice {
+    fn keys(&self) -> KeyState {
+        let mut merged = KeyState::new();
+        for interface in self.interfaces.iter().flatten() {
+            for key in interface.keys.keys().iter() {
+                merged.press(key);
+            }
+        }
+        merged
+    }
+
+    /// Sends the request of the current step, the result arrives in `completed_control`.
+    /// `None` if the step doesn't apply to the interface.
+    fn send_request<B: HostBus>(&self, host: &mut UsbHost<B>) -> Option<Result<(), UsbError>> {
+        let pipe = self.control_pipe?;
+        if self.step == Step::ProductString {
+            return (self.product_string != 0).then(|| {
+                host.control_in(
+                    Some(self.addr),
+                    Some(pipe),
+                    SetupPacket::new(
+                        UsbDirection::In,
+                        RequestType::Standard,
+                        Recipient::Device,
+                        REQUEST_GET_DESCRIPTOR,
+                        (DESCRIPTOR_STRING as u16) << 8 | self.product_string as u16,
+                        LANGUAGE_ID,
+                        PRODUCT_STRING_LEN,
+                    ),
+                    PRODUCT_STRING_LEN,
+                )
+            });
+        }
+        let (Step::ReportDescriptor(i) | Step::Protocol(i) | Step::Idle(i)) = self.step else {
+            return None;
+        };
+        let interface = self.interfaces[i].filter(|interface| interface.endpoint.is_some())?;
+
+        match self.step {
+            Step::ReportDescriptor(_) if interface.report_descriptor_len > 0 => {
+                Some(host.control_in(
+                    Some(self.addr),
+                    Some(pipe),
+                    SetupPacket::new(
+                        UsbDirection::In,
+                        RequestType::Standard,
+                        Recipient::Interface,
+                        REQUEST_GET_DESCRIPTOR,
+                        (DESCRIPTOR_REPORT as u16) << 8,
+                        interface.number as u16,
+                        interface.report_descriptor_len,
+                    ),
+                    interface.report_descriptor_len,
+                ))
+            }
+            // only boot interfaces support SET_PROTOCOL
+            Step::Protocol(_) if interface.boot && interface.layout.is_some() => {
+                let protocol = match interface.boot_protocol {
+                    true => PROTOCOL_BOOT,
+                    false => PROTOCOL_REPORT,
+                };
+                Some(host.control_out(
+                    Some(self.addr),
+                    Some(pipe),
+                    class_request(REQUEST_SET_PROTOCOL, protocol, interface.number),
+                    &[],
+                ))
+            }
+            // reports only on changes, the key state doesn't need repeats
+            Step::Idle(_) if interface.layout.is_some() => Some(host.control_out(
+                Some(self.addr),
+                Some(pipe),
+                class_request(REQUEST_SET_IDLE, 0, interface.number),
+                &[],
+            )),
+            _ => None,
+        }
+    }
+
+    /// Sends an output report with `leds`, the result arrives in `completed_control`. `None` if
+    /// the device has no LEDs.
+    fn send_leds<B: HostBus>(
+        &self,
+        host: &mut UsbHost<B>,
+        leds: u8,
+    ) -> Option<Result<(), UsbError>> {
+        let pipe = self.control_pipe?;
+        let (interface, output) = self.interfaces.iter().flatten().find_map(|interface| {
+            let output = interface.layout?.leds()?;
+            Some((interface, output))
+        })?;
+
+        let mut buf = [0; MAX_OUTPUT_REPORT];
+        let report = output.report(leds, &mut buf);
+        Some(host.control_out(
+            Some(self.addr),
+            Some(pipe),
+            SetupPacket::new(
+                UsbDirection::Out,
+                RequestType::Class,
+                Recipient::Interface,
+                REQUEST_SET_REPORT,
+                REPORT_OUTPUT << 8 | output.report_id as u16,
+                interface.number as u16,
+                report.len() as u16,
+            ),
+            report,
+        ))
+    }
+
+    fn open_pipes<B: HostBus>(&mut self, host: &mut UsbHost<B>) {
+        for interface in self.interfaces.iter_mut().flatten() {
+            if let (Some(_), Some(endpoint)) = (interface.layout, interface.endpoint) {
+                interface.pipe = host.create_interrupt_pipe(
+                    self.addr,
+                    endpoint.number,
+                    UsbDirection::In,
+                    endpoint.size,
+                    endpoint.interval,
+                );
+            }
+        }
+    }
+}
+
+fn class_request(request: u8, value: u16, interface: u8) -> SetupPacket {
+    SetupPacket::new(
+        UsbDirection::Out,
+        RequestType::Class,
+        Recipient::Interface,
+        request,
+        value,
+        interface as u16,
+        0,
+    )
+}
+
+/// Picks the HID interfaces with an interrupt IN endpoint out of a configuration descriptor.
+fn parse_configuration(device: &mut HidDevice, data: &[u8]) {
+    device.config = data.get(5).copied();
+
+    let mut current = None;
+    let mut rest = data;
+    while let [len, kind, ..] = *rest {
+        let len = len as usize;
+        if len < 2 || len > rest.len() {
+            break;
+        }
+        let descriptor = &rest[..len];
+        rest = &rest[len..];
+
+        match (kind, descriptor) {
+            (DESCRIPTOR_INTERFACE, &[_, _, number, alternate, _, class, subclass, ..]) => {
+                current = None;
+                if class != CLASS_HID || alternate != 0 {
+                    continue;
+                }
+                if let Some(slot) = device.interfaces.iter().position(Option::is_none) {
+                    device.interfaces[slot] = Some(Interface {
+                        number,
+                        boot: subclass == SUBCLASS_BOOT,
+                        report_descriptor_len: 0,
+                        endpoint: None,
+                        layout: None,
+                        boot_protocol: false,
+                        pipe: None,
+                        keys: InputKeys::new(),
+                    });
+                    current = Some(slot);
+                }
+            }
+            (DESCRIPTOR_HID, &[_, _, _, _, country_code, _, DESCRIPTOR_REPORT, lo, hi, ..]) => {
+                if let Some(interface) = current.and_then(|i| device.interfaces[i].as_mut()) {
+                    interface.report_descriptor_len = u16::from_le_bytes([lo, hi]);
+                    if device.info.country_code == 0 {
+                        device.info.country_code = country_code;
+                    }
+                }
+            }
+            (DESCRIPTOR_ENDPOINT, &[_, _, address, attributes, lo, hi, interval, ..]) => {
+                let interrupt_in = address & 0x80 != 0 && attributes & 0b11 == 0b11;
+                if let Some(interface) = current.and_then(|i| device.interfaces[i].as_mut()) {
+                    if interrupt_in && interface.endpoint.is_none() {
+                        interface.endpoint = Some(Endpoint {
+                            number: address & 0x0F,
+                            size: u16::from_le_bytes([lo, hi]),
+                            interval,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The events not taken yet, in order. A device's key states are merged into the latest one, and
+/// a removal replaces all events of the device, so there's at most a removal, an addition and a
+/// key state per address.
+struct EventQueue {
+    events: [Option<HidEvent>; MAX_EVENTS],
+    overflows: u32,
+}
+
+impl EventQueue {
+    const fn new() -> Self {
+        Self {
+            events: [None; MAX_EVENTS],
+            overflows: 0,
+        }
+    }
+
+    fn push(&mut self, event: HidEvent) {
+        let addr = event.addr();
+        match event {
+            HidEvent::InputChanged(_, keys) => {
+                let latest = self.events.iter_mut().flatten().rev();
+                if let Some(HidEvent::InputChanged(_, queued)) =
+                    latest.find(|queued| queued.addr() == addr)
+                {
+                    *queued = keys;
+                    return;
+                }
+            }
+            // the removal releases the device's keys, whatever was queued for it
+            HidEvent::DeviceRemoved(_) => {
+                let mut kept = [None; MAX_EVENTS];
+                let events = self.events.iter().flatten();
+                for (slot, queued) in kept
+                    .iter_mut()
+                    .zip(events.filter(|queued| queued.addr() != addr))
+                {
+                    *slot = Some(*queued);
+                }
+                self.events = kept;
+            }
+            HidEvent::DeviceAdded(..) => {}
+        }
+
+        match self.events.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(event),
+            None => self.overflows += 1,
+        }
+    }
+
+    fn take(&mut self) -> Option<HidEvent> {
+        let event = self.events[0].take();
+        self.events.rotate_left(1);
+        event
+    }
+}
+
+pub struct HidDriver {
+    devices: [Option<HidDevice>; MAX_DEVICES],
+    events: EventQueue,
+    failed_requests: u32,
+    leds: u8,
+}
+
+impl HidDriver {
+    pub const fn new() -> Self {
+        Self {
+            devices: [const { None }; MAX_DEVICES],
+            events: EventQueue::new(),
+            failed_requests: 0,
+            leds: 0,
+        }
+    }
+
+    /// Takes the oldest event.
+    pub fn take_event(&mut self) -> Option<HidEvent> {
+        self.events.take()
+    }
+
+    /// Number of events that didn't fit into the queue since the last call, which can only happen
+    /// if events aren't taken for a long time.
+    pub fn take_overflows(&mut self) -> u32 {
+        core::mem::take(&mut self.events.overflows)
+    }
+
+    /// Sets the LEDs of all devices, see [`leds`](cbm2keeb_core::leds). They are sent by
+    /// [`HidDriver::send_requests`].
+    pub fn set_leds(&mut self, leds: u8) {
+        self.leds = leds;
+    }
+
+    /// Number of optional requests (reading the product string or report descriptor, SET_PROTOCOL,
+    /// SET_IDLE, setting the LEDs) that failed since the last call. The devices are used without
+    /// them.
+    pub fn take_failed_requests(&mut self) -> u32 {
+        core::mem::take(&mut self.failed_requests)
+    }
+
+    /// Sends the setup requests of new devices, one at a time per device, and the LEDs to devices
+    /// that show others. Call this after every `poll` of the host.
+    pub fn send_requests<B: HostBus>(&mut self, host: &mut UsbHost<B>) {
+        for device in self.devices.iter_mut().flatten() {
+            while !device.in_flight && !matches!(device.step, Step::Enumerating | Step::Running) {
+                match device.send_request(host) {
+                    Some(Ok(())) => device.in_flight = true,
+                    // the host is busy with another transfer, retried on the next call
+                    Some(Err(UsbError::WouldBlock)) => break,
+                    sent => {
+                        if sent.is_some() {
+                            self.failed_requests += 1;
+                        }
+                        // also without a report descriptor to read
+                        if let Step::ReportDescriptor(i) = device.step {
+                            if let Some(interface) = device.interfaces[i].as_mut() {
+                                interface.fall_back_to_boot();
+                            }
+                        }
+                        device.step = device.step.next();
+                    }
+                }
+
+                if device.step == Step::Running {
+                    device.open_pipes(host);
+                    self.events
+                        .push(HidEvent::DeviceAdded(device.addr.into(), device.info));
+                }
+            }
+
+            if device.step == Step::Running
+                && device.leds_in_flight.is_none()
+                && device.leds != Some(self.leds)
+            {
+                match device.send_leds(host, self.leds) {
+                    Some(Ok(())) => device.leds_in_flight = Some(self.leds),
+                    Some(Err(UsbError::WouldBlock)) => {}
+                    sent => {
+                        if sent.is_some() {
+                            self.failed_requests += 1;
+                        }
+                        // not retried until the LEDs change again
+                        device.leds = Some(self.leds);
+                    }
+                }
+            }
+        }
+    }
+
+    fn device(&mut self, addr: DeviceAddress) -> Option<&mut HidDevice> {
+        self.devices
+            .iter_mut()
+            .flatten()
+            .find(|device| device.addr == addr)
+    }
+}
+
+impl Default for HidDriver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: HostBus> Driver<B> for HidDriver {
+    fn attached(&mut self, addr: DeviceAddress, _speed: ConnectionSpeed) {
+        if let Some(slot) = self.devices.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(HidDevice {
+                addr,
+                info: DeviceInfo::new(0, 0),
+                product_string: 0,
+                config: None,
+                control_pipe: None,
+                interfaces: [None; MAX_INTERFACES],
+                step: Step::Enumerating,
+                in_flight: false,
+                leds: None,
+                leds_in_flight: None,
+            });
+        }
+    }
+
+    fn detached(&mut self, addr: DeviceAddress) {
+        let Some(slot) = self
+            .devices
+            .iter_mut()
+            .find(|slot| slot.as_ref().is_some_and(|device| device.addr == addr))
+        else {
+            return;
+        };
+        if slot
+            .take()
+            .is_some_and(|device| device.step == Step::Running)
+        {
+            self.events.push(HidEvent::DeviceRemoved(addr.into()));
+        }
+    }
+
+    fn descriptor(&mut self, addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        let Some(device) = self.device(addr) else {
+            return;
+        };
+        match descriptor_type {
+            DESCRIPTOR_DEVICE => {
+                if let Some(info) = DeviceInfo::from_device_descriptor(data) {
+                    device.info = info;
+                    device.product_string = data.get(15).copied().unwrap_or(0);
+                }
+            }
+            DESCRIPTOR_CONFIGURATION => parse_configuration(device, data),
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, addr: DeviceAddress) -> Option<u8> {
+        let device = self.device(addr)?;
+        let has_endpoint = device
+            .interfaces
+            .iter()
+            .flatten()
+            .any(|interface| interface.endpoint.is_some());
+        device.config.filter(|_| has_endpoint)
+    }
+
+    fn configured(&mut self, addr: DeviceAddress, _value: u8, host: &mut UsbHost<B>) {
+        if let Some(device) = self.device(addr) {
+            device.control_pipe = host.create_control_pipe(addr);
+            device.step = Step::ProductString;
+        }
+    }
+
+    fn completed_control(&mut self, addr: DeviceAddress, _pipe: PipeId, data: Option<&[u8]>) {
+        let Some(device) = self.device(addr) else {
+            return;
+        };
+        if device.step == Step::Running {
+            // the output report with the LEDs completed
+            device.leds = device.leds_in_flight.take();
+            return;
+        }
+        if device.step == Step::ProductString {
+            if let Some(data) = data {
+                device.info.set_product(data);
+            }
+        }
+        if let Step::ReportDescriptor(i) = device.step {
+            if let Some(interface) = device.interfaces[i].as_mut() {
+                let layout = data
+                    .and_then(|data| ReportDescriptor::parse(data).ok())
+                    .filter(ReportDescriptor::has_keys);
+                match layout {
+                    Some(layout) => interface.layout = Some(layout),
+                    None => interface.fall_back_to_boot(),
+                }
+            }
+        }
+        device.in_flight = false;
+        device.step = device.step.next();
+    }
+
+    fn completed_in(&mut self, addr: DeviceAddress, pipe: PipeId, data: &[u8]) {
+        let Some(device) = self.device(addr) else {
+            return;
+        };
+        let old = device.keys();
+        let Some(interface) = device
+            .interfaces
+            .iter_mut()
+            .flatten()
+            .find(|interface| interface.pipe == Some(pipe))
+        else {
+            return;
+        };
+        // reports without keys (e.g. from a mouse on the same interface) leave the keys alone
+        let Some(layout) = interface.layout else {
+            return;
+        };
+        if !interface.keys.update(&layout, data) {
+            return;
+        }
+
+        let keys = device.keys();
+        if keys != old {
+            self.events.push(HidEvent::InputChanged(addr.into(), keys));
+        }
+    }
+
+    fn completed_out(&mut self, _addr: DeviceAddress, _pipe: PipeId, _data: &mut [u8]) {}
+}
@@ -0,0 +1,50 @@
+//! Worst-case duration of a responder loop iteration, which bounds how long a column strobe can go
+//! unanswered. Measured with the SysTick of the core running the loop, in system clocks.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+
+const SYST_MAX: u32 = 0x00FF_FFFF;
+
+static MAX_LOOP_CLOCKS: AtomicU32 = AtomicU32::new(0);
+
+pub struct LoopTimer {
+    syst: SYST,
+    last: u32,
+}
+
+impl LoopTimer {
+    pub fn new(mut syst: SYST) -> Self {
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(SYST_MAX);
+        syst.clear_current();
+        syst.enable_counter();
+
+        Self {
+            last: SYST::get_current(),
+            syst,
+        }
+    }
+
+    /// Call once per loop iteration.
+    #[inline(always)]
+    pub fn lap(&mut self) {
+        let now = SYST::get_current();
+        // counts down, wraps after ~100 ms
+        let clocks = self.last.wrapping_sub(now) & SYST_MAX;
+        self.last = now;
+
+        // only this core writes a new maximum, a reset from the other core can get lost
+        if clocks > MAX_LOOP_CLOCKS.load(Ordering::Relaxed) {
+            MAX_LOOP_CLOCKS.store(clocks, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the longest iteration since the last call.
+pub fn take_max_loop_clocks() -> u32 {
+    let clocks = MAX_LOOP_CLOCKS.load(Ordering::Relaxed);
+    MAX_LOOP_CLOCKS.store(0, Ordering::Relaxed);
+    clocks
+}
@@ -1,48 +1,135 @@
 #![no_std]
 #![no_main]
 
-mod keys;
+#[cfg(feature = "dma-responder")]
+mod dma_responder;
+mod hid_driver;
+mod latency;
 mod oc;
 
+use cbm2keeb_core::{
+    device::Devices,
+    key_state::KeyEvent,
+    leds::AdapterState,
+    pipeline::{Duration, Instant, Pipeline, TimedEvent},
+    profile::{self, PROFILES},
+    recovery::{BusRecovery, RecoveryAction},
+    responder::{MatrixCell, ScanSync},
+    scan::{ScanAnalyzer, ScanCell, ScanTiming},
+};
 use defmt as _;
-use defmt::{error, info};
+use defmt::{debug, error, info, warn};
 use defmt_rtt as _;
 use panic_probe as _;
-use rtic_monotonics::rp2040_timer_monotonic;
+use rtic_monotonics::{rp2040_timer_monotonic, Monotonic};
 
 rp2040_timer_monotonic!(Mono);
 
-pub const PINS_IN_MASK: u32 = 0b1111_1111_1111_1111;
-pub const PINS_OUT_SHIFT: u8 = 16;
+/// With scan sync, updates are applied after roughly this many responder loop iterations (about
+/// 100 ms) if the CBM isn't scanning.
+const SCAN_SYNC_MAX_IDLE: u32 = 500_000;
+
+/// How often the keyboard LEDs are updated without USB activity, to show that the CBM stopped
+/// scanning.
+const LED_REFRESH: Duration = Duration::millis(100);
+
+#[cfg(not(feature = "dma-responder"))]
+type MatrixResponder = cbm2keeb_core::responder::Responder;
+#[cfg(feature = "dma-responder")]
+type MatrixResponder = dma_responder::DmaResponder;
+
+/// Written by the USB side, read by the responder (on core 1, unless `core0-responder` is set).
+static COL_ENABLED_PINS: MatrixCell = MatrixCell::new();
+/// Written by the responder after every scan.
+static SCAN_TIMING: ScanCell = ScanCell::new();
+
+#[cfg(not(feature = "core0-responder"))]
+static mut CORE1_STACK: rp_pico::hal::multicore::Stack<1024> =
+    rp_pico::hal::multicore::Stack::new();
+
+/// `Mono::now()` without its latched registers, which only work from a single core.
+fn timer_now(timer: &rp_pico::hal::pac::TIMER) -> Instant {
+    loop {
+        let high = timer.timerawh().read().bits();
+        let low = timer.timerawl().read().bits();
+        if timer.timerawh().read().bits() == high {
+            return Instant::from_ticks((high as u64) << 32 | low as u64);
+        }
+    }
+}
+
+/// Answers the column strobes forever, and analyzes them after answering. With the
+/// `latency-stats` feature, also measures the worst-case loop iteration, which `log_latency`
+/// reports.
+fn run_responder(responder: &mut MatrixResponder) -> ! {
+    // SAFETY: only the GPIO registers are used, which are fine to access from both cores
+    let sio = unsafe { rp_pico::hal::pac::SIO::steal() };
+    // SAFETY: only reads the raw timer registers
+    let timer = unsafe { rp_pico::hal::pac::TIMER::steal() };
+    let mut analyzer = ScanAnalyzer::new();
+    let mut last_cols_in = 0;
+    let mut loop_timer = cfg!(feature = "latency-stats")
+        .then(|| latency::LoopTimer::new(unsafe { cortex_m::Peripherals::steal() }.SYST));
+
+    loop {
+        // masking not needed, only checking the low bits, as the bit index matches the row
+        // index
+        let cols_in = !sio.gpio_in().read().bits() as u16; // & PINS_IN_MASK
+
+        #[cfg(not(feature = "dma-responder"))]
+        {
+            let out = responder.respond(&COL_ENABLED_PINS, cols_in);
+
+            sio.gpio_out()
+                .write(|w| unsafe { w.bits((out as u32) << cbm2keeb_core::PINS_OUT_SHIFT) });
+        }
+        // only watches for updates, and with scan sync for the scan boundaries
+        #[cfg(feature = "dma-responder")]
+        responder.update(&COL_ENABLED_PINS, cols_in);
+
+        if cols_in != last_cols_in {
+            last_cols_in = cols_in;
+            if let Some(timing) = analyzer.observe(timer_now(&timer), cols_in) {
+                timing.store(&SCAN_TIMING);
+            }
+        }
+
+        if let Some(loop_timer) = &mut loop_timer {
+            loop_timer.lap();
+        }
+    }
+}
 
 #[rtic::app(
     device = rp_pico::hal::pac, dispatchers = [TIMER_IRQ_1]
 )]
 mod app {
     use super::*;
-    use core::sync::atomic::{AtomicU32, Ordering};
-    use hal::gpio::PinState;
+    use crate::hid_driver::{HidDriver, HidEvent};
     use rp_pico::hal::gpio::PullNone;
     use rp_pico::hal::{self, watchdog::Watchdog};
     use rp_pico::XOSC_CRYSTAL_FREQ;
-    use usbh::{
-        driver::kbd::{KbdDriver, KbdEvent},
-        PollResult, UsbHost,
-    };
+    use usbh::{PollResult, UsbHost};
     use usbh_rp2040::UsbHostBus;
 
     // Shared resources go here
     #[shared]
     struct Shared {
-        col_enabled_pins: [AtomicU32; 4],
+        pipeline: Pipeline,
     }
 
     // Local resources go here
     #[local]
     struct Local {
         usb_host: UsbHost<UsbHostBus>,
-        kbd_driver: KbdDriver,
-        sio: rp_pico::hal::pac::SIO,
+        hid_driver: HidDriver,
+        /// Keyboards are told apart by address, their keys are merged.
+        devices: Devices,
+        recovery: BusRecovery,
+        /// Index in [`PROFILES`] of the keyboard added last, whose LED bindings are used.
+        profile: usize,
+        #[cfg(feature = "core0-responder")]
+        responder: MatrixResponder,
     }
 
     #[init]
@@ -93,24 +180,52 @@ mod app {
 
         // config outputs. outputs should pull low, or float
         // the CBM II has pull-up resistors on these already
-        pins.gpio16
-            .into_pull_type::<PullNone>()
-            .into_push_pull_output_in_state(PinState::High);
-        pins.gpio17
-            .into_pull_type::<PullNone>()
-            .into_push_pull_output_in_state(PinState::High);
-        pins.gpio18
-            .into_pull_type::<PullNone>()
-            .into_push_pull_output_in_state(PinState::High);
-        pins.gpio19
-            .into_pull_type::<PullNone>()
-            .into_push_pull_output_in_state(PinState::High);
-        pins.gpio20
-            .into_pull_type::<PullNone>()
-            .into_push_pull_output_in_state(PinState::High);
-        pins.gpio21
-            .into_pull_type::<PullNone>()
-            .into_push_pull_output_in_state(PinState::High);
+        #[cfg(not(feature = "dma-responder"))]
+        {
+            use hal::gpio::PinState;
+
+            pins.gpio16
+                .into_pull_type::<PullNone>()
+                .into_push_pull_output_in_state(PinState::High);
+            pins.gpio17
+                .into_pull_type::<PullNone>()
+                .into_push_pull_output_in_state(PinState::High);
+            pins.gpio18
+                .into_pull_type::<PullNone>()
+                .into_push_pull_output_in_state(PinState::High);
+            pins.gpio19
+                .into_pull_type::<PullNone>()
+                .into_push_pull_output_in_state(PinState::High);
+            pins.gpio20
+                .into_pull_type::<PullNone>()
+                .into_push_pull_output_in_state(PinState::High);
+            pins.gpio21
+                .into_pull_type::<PullNone>()
+                .into_push_pull_output_in_state(PinState::High);
+        }
+        #[cfg(feature = "dma-responder")]
+        {
+            use hal::gpio::FunctionPio0;
+
+            pins.gpio16
+                .into_pull_type::<PullNone>()
+                .into_function::<FunctionPio0>();
+            pins.gpio17
+                .into_pull_type::<PullNone>()
+                .into_function::<FunctionPio0>();
+            pins.gpio18
+                .into_pull_type::<PullNone>()
+                .into_function::<FunctionPio0>();
+            pins.gpio19
+                .into_pull_type::<PullNone>()
+                .into_function::<FunctionPio0>();
+            pins.gpio20
+                .into_pull_type::<PullNone>()
+                .into_function::<FunctionPio0>();
+            pins.gpio21
+                .into_pull_type::<PullNone>()
+                .into_function::<FunctionPio0>();
+        }
 
         // config unused gpios
         pins.gpio22.into_pull_down_disabled();
@@ -122,6 +237,40 @@ mod app {
         pins.gpio28.into_pull_down_disabled();
         pins.gpio29.into_pull_down_disabled();
 
+        let scan_sync = cfg!(feature = "scan-sync").then(|| ScanSync::new(SCAN_SYNC_MAX_IDLE));
+        #[cfg(not(feature = "dma-responder"))]
+        let responder = MatrixResponder::new(scan_sync);
+        #[cfg(feature = "dma-responder")]
+        let responder = MatrixResponder::new(
+            ctx.device.PIO0,
+            ctx.device.DMA,
+            &mut ctx.device.RESETS,
+            scan_sync,
+        );
+
+        #[cfg(not(feature = "core0-responder"))]
+        {
+            use hal::multicore::Multicore;
+
+            let mut fifo = sio.fifo;
+            let mut mc = Multicore::new(&mut ctx.device.PSM, &mut ctx.device.PPB, &mut fifo);
+            let cores = mc.cores();
+            // SAFETY: init runs once, nothing else uses the stack
+            let stack = unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK.mem) };
+            cores[1]
+                .spawn(stack, move || {
+                    let mut responder = responder;
+                    run_responder(&mut responder)
+                })
+                .unwrap();
+        }
+
+        log_scan_timing::spawn().ok();
+        refresh_leds::spawn().ok();
+        if cfg!(feature = "latency-stats") {
+            log_latency::spawn().ok();
+        }
+
         let usb_host = UsbHost::new(usbh_rp2040::UsbHostBus::new(
             ctx.device.USBCTRL_REGS,
             ctx.device.USBCTRL_DPRAM,
@@ -130,122 +279,281 @@ mod app {
         ));
         (
             Shared {
-                col_enabled_pins: [const { AtomicU32::new(0) }; 4],
+                pipeline: Pipeline::new(),
             },
             Local {
                 usb_host,
-                kbd_driver: KbdDriver::new(),
-                sio: unsafe { rp_pico::hal::pac::SIO::steal() },
+                hid_driver: HidDriver::new(),
+                devices: Devices::new(),
+                recovery: BusRecovery::default(),
+                profile: 0,
+                #[cfg(feature = "core0-responder")]
+                responder,
             },
         )
     }
 
-    #[idle(
-        local = [sio],
-        shared = [&col_enabled_pins]
-    )]
+    /// With `core0-responder`, answers the strobes here, otherwise core 1 does and this just
+    /// sleeps.
+    #[idle(local = [responder])]
     fn idle(ctx: idle::Context) -> ! {
-        // 4 bits to 0xFF in those byte positions
-        let lookup: [u32; 16] = [
-            0x00000000, 0x000000FF, 0x0000FF00, 0x0000FFFF, 0x00FF0000, 0x00FF00FF, 0x00FFFF00,
-            0x00FFFFFF, 0xFF000000, 0xFF0000FF, 0xFF00FF00, 0xFF00FFFF, 0xFFFF0000, 0xFFFF00FF,
-            0xFFFFFF00, 0xFFFFFFFF,
-        ];
+        #[cfg(feature = "core0-responder")]
+        run_responder(ctx.local.responder);
 
-        loop {
-            // masking not needed, only checking the low bits, as the bit index matches the row
-            // index
-            let cols_in = !ctx.local.sio.gpio_in().read().bits() as u16; // & PINS_IN_MASK
-
-            let mask = [
-                lookup[(cols_in & 0b1111) as usize],
-                lookup[((cols_in >> 4) & 0b1111) as usize],
-                lookup[((cols_in >> 8) & 0b1111) as usize],
-                lookup[((cols_in >> 12) & 0b1111) as usize],
-            ];
-
-            let out = (ctx.shared.col_enabled_pins[0].load(Ordering::Relaxed) & mask[0])
-                | (ctx.shared.col_enabled_pins[1].load(Ordering::Relaxed) & mask[1])
-                | (ctx.shared.col_enabled_pins[2].load(Ordering::Relaxed) & mask[2])
-                | (ctx.shared.col_enabled_pins[3].load(Ordering::Relaxed) & mask[3]);
-            let [out0, out1, out2, out3] = out.to_ne_bytes();
-            let out = !(out0 | out1 | out2 | out3);
-
-            ctx.local
-                .sio
-                .gpio_out()
-                .write(|w| unsafe { w.bits((out as u32) << PINS_OUT_SHIFT) });
+        #[cfg(not(feature = "core0-responder"))]
+        {
+            let _ = ctx;
+            loop {
+                cortex_m::asm::wfi();
+            }
         }
     }
 
     #[task(
         binds = USBCTRL_IRQ,
-        local = [usb_host, kbd_driver],
-        shared = [&col_enabled_pins]
+        local = [usb_host, hid_driver, devices, recovery, profile],
+        shared = [pipeline]
     )]
-    fn usbctrl_irq(ctx: usbctrl_irq::Context) {
-        match ctx
+    fn usbctrl_irq(mut ctx: usbctrl_irq::Context) {
+        let devices = ctx.local.devices;
+        let recovery = ctx.local.recovery;
+        let now = Mono::now();
+        // the one driver handles every keyboard the host enumerates, hubs are enumerated by the
+        // host itself
+        let (lost, action) = match ctx
             .local
             .usb_host
-            .poll(&mut [ctx.local.kbd_driver /* as &mut dyn Driver<_> */])
+            .poll(&mut [ctx.local.hid_driver /* as &mut dyn Driver<_> */])
         {
-            PollResult::NoDevice => {
-                return;
-            }
-            PollResult::Busy => {}
-            PollResult::Idle => {}
+            PollResult::NoDevice => (true, RecoveryAction::Continue),
+            PollResult::Busy => (false, RecoveryAction::Continue),
+            PollResult::Idle => (false, RecoveryAction::Continue),
             PollResult::BusError(error) => {
                 error!("Bus error: {}", error);
+                (true, recovery.bus_error(now))
             }
             PollResult::DiscoveryError(dev_addr) => {
                 error!("Discovery for device {} failed", dev_addr);
+                (false, recovery.discovery_error(now))
+            }
+            _ => (false, RecoveryAction::Continue),
+        };
+
+        if action == RecoveryAction::ResetHost {
+            warn!(
+                "Resetting the USB host, errors so far: {}",
+                recovery.counts()
+            );
+            run_pipeline(&mut ctx.shared.pipeline, devices.lost());
+            reset_usb_host(ctx.local.usb_host, ctx.local.hid_driver);
+            return;
+        }
+        if lost {
+            // keyboards may be gone without a removal event, nothing must stay pressed then
+            if !devices.is_empty() {
+                warn!("Keyboards lost, releasing all keys");
+                run_pipeline(&mut ctx.shared.pipeline, devices.lost());
             }
-            _ => {}
-        }
-
-        match ctx.local.kbd_driver.take_event() {
-            None => {}
-            Some(event) => match event {
-                KbdEvent::DeviceAdded(dev_addr) => {
-                    info!("Keyboard with address {} added", dev_addr);
-                    ctx.local
-                        .kbd_driver
-                        .set_idle(dev_addr, 0, ctx.local.usb_host)
-                        .ok()
-                        .unwrap();
+            return;
+        }
+
+        ctx.local.hid_driver.send_requests(ctx.local.usb_host);
+        for _ in 0..ctx.local.hid_driver.take_failed_requests() {
+            recovery.optional_request_failed();
+        }
+
+        let overflows = ctx.local.hid_driver.take_overflows();
+        if overflows > 0 {
+            warn!("HID driver dropped {} events", overflows);
+        }
+        while let Some(event) = ctx.local.hid_driver.take_event() {
+            match event {
+                HidEvent::DeviceAdded(dev_addr, device) => {
+                    let selected = profile::select(&PROFILES, &device);
+                    let profile = &PROFILES[selected];
+                    info!(
+                        "Keyboard with address {} added: {=u16:04x}:{=u16:04x} {}, country code {}, profile {}, layout {}",
+                        dev_addr,
+                        device.vid,
+                        device.pid,
+                        device.product().unwrap_or(""),
+                        device.country_code,
+                        profile.name,
+                        profile.layout(&device)
+                    );
+                    *ctx.local.profile = selected;
+                    recovery.success();
+                    run_pipeline(
+                        &mut ctx.shared.pipeline,
+                        devices.added(dev_addr, profile.config(&device)),
+                    );
                 }
-                KbdEvent::DeviceRemoved(dev_addr) => {
+                HidEvent::DeviceRemoved(dev_addr) => {
                     info!("Keyboard with address {} removed", dev_addr);
+                    run_pipeline(&mut ctx.shared.pipeline, devices.removed(dev_addr));
                 }
-                KbdEvent::InputChanged(_, report) => {
-                    let mut col_gpio_bits = [0u32; 4];
+                HidEvent::InputChanged(dev_addr, keys) => {
+                    recovery.success();
+                    // the keyboards share the pipeline, it follows the one that's typing
+                    let config = devices.config(dev_addr);
+                    ctx.shared
+                        .pipeline
+                        .lock(|pipeline| pipeline.configure(&config));
+                    match devices.report_keys(dev_addr, keys) {
+                        Some(events) => run_pipeline(&mut ctx.shared.pipeline, events),
+                        None => debug!("Ignored report from keyboard {}", dev_addr),
+                    }
+                }
+            }
+        }
+
+        let (shift_lock, layer) = ctx.shared.pipeline.lock(|pipeline| {
+            (
+                pipeline.shift_lock.is_latched(),
+                pipeline.translator.layer(),
+            )
+        });
+        let state = AdapterState {
+            shift_lock,
+            layer,
+            profile: *ctx.local.profile as u8,
+            error: recovery.has_errors(),
+            scanning: ScanTiming::load(&SCAN_TIMING).is_some_and(|timing| timing.is_current(now)),
+        };
+        let bindings = &PROFILES[*ctx.local.profile].leds;
+        ctx.local.hid_driver.set_leds(bindings.leds(&state));
+        ctx.local.hid_driver.send_requests(ctx.local.usb_host);
+    }
 
-                    let cast_to_bytes = bytemuck::cast_mut(&mut col_gpio_bits);
+    /// Resets the USB controller by setting up a new host on it, which re-enumerates all devices.
+    fn reset_usb_host(usb_host: &mut UsbHost<UsbHostBus>, hid_driver: &mut HidDriver) {
+        // SAFETY: the old host owned these, and is replaced below. The clocks manager only hands
+        // out the clock tokens, it doesn't touch the configuration done in init.
+        let (regs, dpram, mut resets, clocks) = unsafe {
+            (
+                hal::pac::USBCTRL_REGS::steal(),
+                hal::pac::USBCTRL_DPRAM::steal(),
+                hal::pac::RESETS::steal(),
+                hal::clocks::ClocksManager::new(hal::pac::CLOCKS::steal()),
+            )
+        };
+        *usb_host = UsbHost::new(UsbHostBus::new(regs, dpram, clocks.usb_clock, &mut resets));
+        *hid_driver = HidDriver::new();
+    }
 
-                    for key in report.pressed_keys() {
-                        key_set(key, cast_to_bytes);
-                    }
-                    let modifier_status = report.modifier_status;
-                    if modifier_status.left_shift() || modifier_status.right_shift() {
-                        key_set(keys::KEY_LEFTSHIFT, cast_to_bytes);
-                    }
-                    if modifier_status.left_ctrl() || modifier_status.right_ctrl() {
-                        key_set(keys::KEY_LEFTCTRL, cast_to_bytes);
-                    }
+    /// Runs key events through the pipeline and updates the matrix, then starts the timer for
+    /// any deadline.
+    fn run_pipeline(
+        pipeline: &mut impl rtic::Mutex<T = Pipeline>,
+        events: impl Iterator<Item = KeyEvent>,
+    ) {
+        let time = Mono::now();
+        let events = events.map(|event| {
+            debug!("{}", event);
+            TimedEvent { time, event }
+        });
 
-                    for (storage, new) in ctx.shared.col_enabled_pins.iter().zip(col_gpio_bits) {
-                        storage.store(new, Ordering::Relaxed);
-                    }
+        let deadline = pipeline.lock(|pipeline| {
+            pipeline.set_scan_timing(ScanTiming::load(&SCAN_TIMING));
+            let mode = pipeline.translator.mode();
+            let dropped = pipeline.process(events);
+            if dropped > 0 {
+                warn!("Pipeline dropped {} events", dropped);
+            }
+            if pipeline.translator.mode() != mode {
+                info!("Translation mode: {}", pipeline.translator.mode());
+            }
+            pipeline.output.commit(&COL_ENABLED_PINS);
+            pipeline.deadline()
+        });
+
+        if deadline.is_some() {
+            // fails if it's already running, it picks up the new deadline by itself
+            pipeline_timer::spawn().ok();
+        }
+    }
+
+    /// Calls the pipeline's timeouts as long as any processor has a deadline. Wakes up at least
+    /// every millisecond, as `usbctrl_irq` can move the deadline forward while this is waiting.
+    #[task(priority = 1, shared = [pipeline])]
+    async fn pipeline_timer(mut ctx: pipeline_timer::Context) {
+        while let Some(deadline) = ctx.shared.pipeline.lock(|pipeline| pipeline.deadline()) {
+            Mono::delay_until(deadline.min(Mono::now() + Duration::millis(1))).await;
+
+            let now = Mono::now();
+            ctx.shared.pipeline.lock(|pipeline| {
+                pipeline.set_scan_timing(ScanTiming::load(&SCAN_TIMING));
+                let dropped = pipeline.timeout(now);
+                if dropped > 0 {
+                    warn!("Pipeline dropped {} events", dropped);
                 }
-                _ => {}
-            },
+                let auto_released = pipeline.watchdog.take_auto_released();
+                if auto_released > 0 {
+                    warn!("Released {} keys held for too long", auto_released);
+                }
+                pipeline.output.commit(&COL_ENABLED_PINS);
+            });
         }
     }
-}
 
-fn key_set(key: u8, col_gpio_bits: &mut [u8; 16]) {
-    unsafe {
-        let (col, row_bits) = *keys::INVERSE_KEYMAP.get_unchecked(key as usize);
-        *col_gpio_bits.get_unchecked_mut(col as usize) |= row_bits;
-    };
+    /// Has `usbctrl_irq` update the keyboard LEDs regularly, as the CBM's scanning state changes
+    /// without any USB activity.
+    #[task(priority = 1)]
+    async fn refresh_leds(_: refresh_leds::Context) {
+        loop {
+            Mono::delay(LED_REFRESH).await;
+            rtic::pend(hal::pac::Interrupt::USBCTRL_IRQ);
+        }
+    }
+
+    /// Reports the worst-case responder loop iteration every second.
+    #[task(priority = 1)]
+    async fn log_latency(_: log_latency::Context) {
+        loop {
+            Mono::delay(Duration::secs(1)).await;
+            info!(
+                "Worst-case responder loop: {} clocks",
+                latency::take_max_loop_clocks()
+            );
+        }
+    }
+
+    /// Logs the scan timing whenever the CBM's scan behaviour changes, or scanning stops.
+    #[task(priority = 1)]
+    async fn log_scan_timing(_: log_scan_timing::Context) {
+        let mut logged: Option<ScanTiming> = None;
+        loop {
+            Mono::delay(Duration::secs(1)).await;
+
+            let timing =
+                ScanTiming::load(&SCAN_TIMING).filter(|timing| timing.is_current(Mono::now()));
+            let changed = match (&logged, &timing) {
+                (Some(logged), Some(timing)) => {
+                    logged.any_key_check != timing.any_key_check
+                        || logged.columns != timing.columns
+                        || logged.order != timing.order
+                        || logged.period.ticks().abs_diff(timing.period.ticks()) > 100
+                }
+                (None, None) => false,
+                _ => true,
+            };
+            if !changed {
+                continue;
+            }
+
+            match &timing {
+                Some(timing) => info!(
+                    "Scan: every {} us, {} us active, {} columns of up to {} us, any-key check: {}, order: {=u64:x}",
+                    timing.period.ticks(),
+                    timing.active.ticks(),
+                    timing.columns,
+                    timing.dwell.ticks(),
+                    timing.any_key_check,
+                    timing.order
+                ),
+                None => info!("Scan: CBM stopped scanning"),
+            }
+            logged = timing;
+        }
+    }
 }